@@ -0,0 +1,583 @@
+//! Commands of the command line tool
+
+use crate::{
+    debug::{Breakpoint, Debugger},
+    flatten::Flattened,
+    report::{
+        coverage::Coverage,
+        junit::JUnit,
+        profile::Profile,
+        sarif::Sarif,
+        stats::Collector,
+        time::CycleTime,
+        trace::{Recorder, TraceRecord},
+    },
+    search::BlockSearch,
+    CheckFormat, Format, Options, PartArgs, TimeArgs,
+};
+use millsim::{
+    compare::Comparison,
+    diagnostic::{Diagnostic, Rule, Severity},
+    errors::{LineError, SimpleError},
+    gcode::{words::Words, Edit, FormatOptions, GCodeFile},
+    machine::{Analysis, Executor, Machine, Program},
+    render::{self, heightmap::HeightMap, svg::Svg, Render},
+    types::Micrometer,
+};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Load program with subprogram libraries, searching for missing ones
+fn load(path: &Path, opts: &Options) -> Result<Program, LineError> {
+    Program::with_libraries(open(path)?, &opts.libraries, &opts.search_path)
+}
+
+/// Start the main program with the block budget
+fn start<'t>(program: &'t Program, opts: &Options) -> Result<Executor<'t>, LineError> {
+    program
+        .execute(None)
+        .map(|e| e.with_budget(opts.max_blocks))
+        .map_err(SimpleError::no_line)
+}
+
+/// Run program on the machine, calling `observe` after each executed block
+///
+/// `observe` gets file line, the raw block, the executor and the machine.
+/// Rule violations which don't stop the program go to standard error.
+fn simulate(
+    program: &Program,
+    opts: &Options,
+    machine: Machine,
+    observe: impl FnMut(u64, &Words, &Executor, &mut Machine) -> Result<(), LineError>,
+) -> Result<Machine, LineError> {
+    simulate_reporting(program, opts, machine, |d| eprintln!("{d}"), observe)
+}
+
+/// Run program like `simulate`, passing rule violations to `report`
+fn simulate_reporting(
+    program: &Program,
+    opts: &Options,
+    mut machine: Machine,
+    report: impl FnMut(Diagnostic),
+    observe: impl FnMut(u64, &Words, &Executor, &mut Machine) -> Result<(), LineError>,
+) -> Result<Machine, LineError> {
+    machine.run(start(program, opts)?, report, observe)?;
+    Ok(machine)
+}
+
+fn simulate_part(path: &Path, part: &PartArgs, opts: &Options) -> Result<HeightMap, LineError> {
+    let machine =
+        Machine::with_renders_and_config(vec![Box::new(part.height_map())], opts.machine()?);
+    let mut renders = load(path, opts)
+        .and_then(|p| simulate(&p, opts, machine, |_, _, _, _| Ok(())))
+        .map_err(|e| e.in_file(path))?
+        .finalize();
+    Ok(*render::take::<HeightMap>(&mut renders).expect("Bug: height map render lost"))
+}
+
+fn output_error(e: std::io::Error) -> LineError {
+    SimpleError(format!("Can't write output file: {e}")).no_line()
+}
+
+/// File name standing for standard input or output
+const STDIO: &str = "-";
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+/// Parse a G-code file, reading standard input for "-"
+fn open(path: &Path) -> Result<GCodeFile, LineError> {
+    if is_stdio(path) {
+        GCodeFile::from_reader("<stdin>", io::stdin().lock())
+    } else {
+        GCodeFile::load(path)
+    }
+}
+
+/// Buffered output file or standard output for "-"
+#[derive(Debug)]
+enum Output {
+    File(BufWriter<File>),
+    Stdout(io::Stdout),
+}
+
+impl Output {
+    fn create(path: &Path) -> Result<Self, LineError> {
+        if is_stdio(path) {
+            return Ok(Self::Stdout(io::stdout()));
+        }
+        File::create(path)
+            .map(|fd| Self::File(BufWriter::new(fd)))
+            .map_err(|e| output_error(e).in_file(path))
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::File(fd) => fd.write(buf),
+            Self::Stdout(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(fd) => fd.flush(),
+            Self::Stdout(out) => out.flush(),
+        }
+    }
+}
+
+pub fn run(
+    path: &Path,
+    out_path: Option<&Path>,
+    heightmap: Option<&Path>,
+    trace_path: Option<&Path>,
+    part: &PartArgs,
+    opts: &Options,
+) -> Result<(), LineError> {
+    // Blocks are echoed unless standard output takes a rendering
+    let to_stdout = [out_path, heightmap, trace_path]
+        .into_iter()
+        .flatten()
+        .filter(|p| is_stdio(p))
+        .count();
+    if to_stdout > 1 {
+        return Err(SimpleError("Only one output can go to standard output".into()).no_line());
+    }
+    let echo = to_stdout == 0;
+
+    let config = opts.machine()?;
+    let program = load(path, opts)?;
+    let mut renders: Vec<Box<dyn Render>> = Vec::new();
+    if out_path.is_some() {
+        // Written once the program ran, no file is left behind on errors
+        renders.push(Box::new(Svg::new(Vec::new(), part.stock())));
+    }
+    if heightmap.is_some() {
+        renders.push(Box::new(part.height_map()));
+    }
+    let mut trace = match trace_path {
+        Some(p) => {
+            renders.push(Box::<Recorder>::default());
+            Some(Output::create(p)?)
+        }
+        None => None,
+    };
+
+    let machine = Machine::with_renders_and_config(renders, config);
+    let machine = simulate(&program, opts, machine, |line, raw, exec, machine| {
+        if echo {
+            println!("{raw}");
+        }
+        if let Some(fd) = &mut trace {
+            let segments = machine
+                .render_mut::<Recorder>()
+                .expect("Bug: trace render lost")
+                .take();
+            TraceRecord::new(line, exec.block_stack(), raw, machine.state(), segments)
+                .write(fd)
+                .map_err(output_error)?;
+        }
+        Ok(())
+    })?;
+    if let Some(mut fd) = trace {
+        fd.flush().map_err(output_error)?;
+    }
+    let mut renders = machine.finalize();
+
+    if let Some(p) = out_path {
+        let svg = render::take::<Svg<Vec<u8>>>(&mut renders).expect("Bug: SVG render lost");
+        let mut out = Output::create(p)?;
+        svg.finish()
+            .and_then(|svg| out.write_all(&svg))
+            .and_then(|()| out.flush())
+            .map_err(output_error)?;
+    }
+    if let Some(p) = heightmap {
+        let map = render::take::<HeightMap>(&mut renders).expect("Bug: height map render lost");
+        let mut out = Output::create(p)?;
+        map.write(&mut out)
+            .and_then(|()| out.flush())
+            .map_err(output_error)?;
+    }
+    for render in renders {
+        render.finalize().map_err(output_error)?;
+    }
+
+    Ok(())
+}
+
+pub fn compare(
+    path: &Path,
+    reference: &Path,
+    tolerance: Micrometer,
+    diff: Option<&Path>,
+    part: &PartArgs,
+    opts: &Options,
+) -> Result<bool, LineError> {
+    let result = simulate_part(path, part, opts)?;
+    let expected = if reference.extension().is_some_and(|e| e == "hmap") {
+        HeightMap::load(reference).map_err(|e| e.in_file(reference))?
+    } else {
+        simulate_part(reference, part, opts)?
+    };
+
+    let cmp = Comparison::new(&result, &expected, tolerance)
+        .map_err(|e| e.no_line().in_file(reference))?;
+    // The summary gives way to the heat map on standard output
+    if !diff.is_some_and(is_stdio) {
+        print!("{cmp}");
+    }
+
+    if let Some(p) = diff {
+        let mut out = Output::create(p)?;
+        cmp.write_svg(&mut out)
+            .and_then(|()| out.flush())
+            .map_err(output_error)?;
+    }
+
+    Ok(cmp.passed())
+}
+
+pub fn time(path: &Path, time: &TimeArgs, opts: &Options) -> Result<(), LineError> {
+    let machine = Machine::with_renders_and_config(Vec::new(), time.config(opts)?);
+    let mut cycle = CycleTime::default();
+    simulate(&load(path, opts)?, opts, machine, |line, raw, exec, m| {
+        cycle.record(line, raw, exec.depth(), m.elapsed());
+        Ok(())
+    })?;
+    print!("{cycle}");
+    Ok(())
+}
+
+pub fn stats(
+    path: &Path,
+    format: Format,
+    time: &TimeArgs,
+    opts: &Options,
+) -> Result<(), LineError> {
+    let program = load(path, opts)?;
+    let machine =
+        Machine::with_renders_and_config(vec![Box::<Collector>::default()], time.config(opts)?);
+    let mut executed = 0;
+    let mut renders = simulate(&program, opts, machine, |_, _, _, _| {
+        executed += 1;
+        Ok(())
+    })?
+    .finalize();
+
+    let collector = render::take::<Collector>(&mut renders).expect("Bug: statistics render lost");
+    let stats = collector.into_statistics(executed, program.block_count());
+    match format {
+        Format::Text => print!("{stats}"),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).expect("Bug: unserializable statistics")
+        ),
+    }
+    Ok(())
+}
+
+pub fn coverage(path: &Path, html: Option<&Path>, opts: &Options) -> Result<(), LineError> {
+    let program = load(path, opts)?;
+    let mut coverage = Coverage::default();
+    let machine = Machine::with_config(opts.machine()?);
+    simulate(&program, opts, machine, |line, _, exec, _| {
+        coverage.record(exec.file(), line);
+        Ok(())
+    })?;
+
+    let report = coverage.report(&program);
+    if !html.is_some_and(is_stdio) {
+        print!("{report}");
+    }
+    if let Some(p) = html {
+        let mut out = Output::create(p)?;
+        report
+            .write_html(&mut out)
+            .and_then(|()| out.flush())
+            .map_err(output_error)?;
+    }
+    Ok(())
+}
+
+pub fn profile(
+    path: &Path,
+    top: Option<usize>,
+    time: &TimeArgs,
+    opts: &Options,
+) -> Result<(), LineError> {
+    let machine = Machine::with_renders_and_config(Vec::new(), time.config(opts)?);
+    let mut profile = Profile::new(top);
+    simulate(&load(path, opts)?, opts, machine, |line, raw, exec, m| {
+        profile.record(exec.file(), line, raw, &exec.call_stack(), m.elapsed());
+        Ok(())
+    })?;
+    print!("{profile}");
+    Ok(())
+}
+
+pub fn flatten(
+    path: &Path,
+    out_path: Option<&Path>,
+    evaluate: bool,
+    opts: &Options,
+) -> Result<(), LineError> {
+    let mut flat = Flattened::new(evaluate);
+    let machine = Machine::with_config(opts.machine()?);
+    simulate(&load(path, opts)?, opts, machine, |line, raw, exec, _| {
+        flat.record(line, raw, exec);
+        Ok(())
+    })?;
+
+    let mut out = Output::create(out_path.unwrap_or(Path::new(STDIO)))?;
+    write!(out, "{flat}")
+        .and_then(|()| out.flush())
+        .map_err(output_error)
+}
+
+pub fn format_files(
+    files: &[PathBuf],
+    check: bool,
+    opts: &FormatOptions,
+) -> Result<bool, LineError> {
+    let mut formatted = true;
+    for path in files {
+        let file = open(path)?;
+        let text = file.format(opts).map_err(|e| e.in_file(path))?;
+        if is_stdio(path) && !check {
+            print!("{text}");
+            continue;
+        }
+        if text == file.text() {
+            continue;
+        }
+        if check {
+            println!("'{}' is not formatted", file.path().display());
+            formatted = false;
+        } else {
+            std::fs::write(path, text).map_err(|e| output_error(e).in_file(path))?;
+        }
+    }
+    Ok(formatted)
+}
+
+/// Apply suggested fixes to `path` until the program runs or fails with no fix
+///
+/// `simulate` loads and runs the program. Fixes of other files, like
+/// subprogram libraries, are reported but not applied. Returns the number of
+/// applied fixes.
+fn apply_fixes(
+    path: &Path,
+    mut simulate: impl FnMut() -> Result<(), LineError>,
+) -> Result<usize, LineError> {
+    if is_stdio(path) {
+        return Err(SimpleError("Can't apply fixes to standard input".into()).no_line());
+    }
+    // Failing source lines with their errors, a fix that doesn't help ends it
+    let mut seen = HashSet::new();
+    loop {
+        let Err(e) = simulate() else {
+            return Ok(seen.len());
+        };
+        let (Some(fix), Some(file), Some(line)) = (e.fix(), e.file(), e.line()) else {
+            return Ok(seen.len());
+        };
+        if file != path {
+            eprintln!(
+                "Not fixing line {line} of '{}', only '{}' is fixed: {fix}",
+                file.display(),
+                path.display()
+            );
+            return Ok(seen.len());
+        }
+        let source = GCodeFile::load(path)?;
+        let Some(failing) = source.source_line(line) else {
+            return Ok(seen.len());
+        };
+        if !seen.insert((failing.text.clone(), e.message().to_owned())) {
+            return Ok(seen.len());
+        }
+
+        let indent = &failing.text[..failing.text.len() - failing.text.trim_start().len()];
+        let end = if failing.end.is_empty() {
+            "\n"
+        } else {
+            &failing.end
+        };
+        let insert = Edit::new(
+            failing.start..failing.start,
+            format!("{indent}{}{end}", fix.insert),
+        );
+        let text = source.edit(vec![insert]).map_err(SimpleError::no_line)?;
+        std::fs::write(path, text).map_err(|e| output_error(e).in_file(path))?;
+        eprintln!("Fixed line {line} of '{}': {fix}", path.display());
+    }
+}
+
+/// Diagnostics of the check command with the analysis summary
+#[derive(Serialize)]
+struct CheckReport {
+    diagnostics: Vec<Diagnostic>,
+    /// `None` if the program doesn't load
+    #[serde(flatten)]
+    analysis: Option<Analysis>,
+}
+
+pub fn check(
+    path: &Path,
+    depth_limit: usize,
+    format: CheckFormat,
+    fix: bool,
+    opts: &Options,
+) -> Result<bool, LineError> {
+    let config = opts.machine()?;
+    if fix {
+        apply_fixes(path, || {
+            let program = load(path, opts)?;
+            let machine = Machine::with_config(opts.machine()?);
+            simulate_reporting(&program, opts, machine, |_| (), |_, _, _, _| Ok(())).map(drop)
+        })?;
+    }
+
+    let mut diagnostics = Vec::new();
+    let mut files = vec![path.to_owned()];
+    let analysis = match load(path, opts) {
+        Ok(program) => {
+            files = program.files().to_vec();
+            let analysis = Analysis::new(&program, depth_limit, config.rules());
+            diagnostics.extend(analysis.findings.iter().map(|f| f.diagnostic()));
+            let machine = Machine::with_config(config);
+            let report = |d| diagnostics.push(d);
+            let simulated =
+                simulate_reporting(&program, opts, machine, report, |_, _, _, _| Ok(()));
+            if let Err(e) = simulated {
+                diagnostics.push(Diagnostic::from_error(&e.in_file(path), Rule::RuntimeError));
+            }
+            Some(analysis)
+        }
+        Err(e) => {
+            diagnostics.push(Diagnostic::from_error(&e.in_file(path), Rule::ProgramError));
+            None
+        }
+    };
+
+    let passed = diagnostics.iter().all(|d| d.severity != Severity::Error);
+    let report = CheckReport {
+        diagnostics,
+        analysis,
+    };
+    match format {
+        CheckFormat::Text => {
+            for d in &report.diagnostics {
+                println!("{d}");
+            }
+            if let Some(analysis) = &report.analysis {
+                print!("{analysis}");
+            }
+        }
+        CheckFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Bug: unserializable check report")
+        ),
+        CheckFormat::Sarif => print!("{}", Sarif::new(&report.diagnostics)),
+        CheckFormat::Junit => print!("{}", JUnit::new(&files, &report.diagnostics)),
+    }
+    Ok(passed)
+}
+
+pub fn debug(path: &Path, opts: &Options) -> Result<(), LineError> {
+    let program = load(path, opts)?;
+    let exec = start(&program, opts)?;
+    let stdin = std::io::stdin().lock();
+    Debugger::new(exec, Machine::with_config(opts.machine()?))
+        .repl(stdin, std::io::stdout())
+        .map_err(|e| SimpleError(format!("I/O error {e}")).no_line())
+}
+
+pub fn search(
+    path: &Path,
+    target: Breakpoint,
+    occurrence: usize,
+    opts: &Options,
+) -> Result<bool, LineError> {
+    let program = load(path, opts)?;
+    let exec = start(&program, opts)?;
+    let machine = Machine::with_config(opts.machine()?);
+    let found = BlockSearch::run(Debugger::new(exec, machine), target, occurrence)?;
+    print!("{found}");
+    Ok(found.safe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_fixes;
+    use millsim::{Fix, GCodeFile, LineError, Machine, Program, SimpleError};
+    use std::{fs, path::Path};
+
+    fn simulate(path: &Path, libraries: &[&Path]) -> Result<(), LineError> {
+        let libraries: Vec<_> = libraries.iter().map(|p| p.to_path_buf()).collect();
+        let program = Program::with_libraries(GCodeFile::load(path)?, &libraries, &[])?;
+        let exec = program.execute(None).map_err(|e| e.no_line())?;
+        Machine::default().run(exec, |_| (), |_, _, _, _| Ok(()))
+    }
+
+    #[test]
+    fn fix_loop() {
+        let dir = std::env::temp_dir().join(format!("millsim-{}-fix", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.mpf");
+        fs::write(
+            &main,
+            "%MPF1\nG0 Z150\nG0 X0 Y0\n  M3 S1000 D1\r\nG1 Z-1 F100\nG0 Z150\nM5\nM2",
+        )
+        .unwrap();
+        assert_eq!(apply_fixes(&main, || simulate(&main, &[])).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(&main).unwrap(),
+            "%MPF1\nG0 Z150\nG0 X0 Y0\n  M8\r\n  M3 S1000 D1\r\nG1 Z-1 F100\nG0 Z150\nM5\nM9\nM2",
+        );
+        // Nothing left to fix
+        assert_eq!(apply_fixes(&main, || simulate(&main, &[])).unwrap(), 0);
+
+        // Fixes of a shared library are left to the user
+        let lib = dir.join("lib.spf");
+        let lib_text = "%SPF10\nM3 S1000 D1\nM5\nM17\n";
+        fs::write(&lib, lib_text).unwrap();
+        let calling = "%MPF1\nG0 Z150\nG0 X0 Y0\nL10 P0\nM2\n";
+        fs::write(&main, calling).unwrap();
+        assert_eq!(apply_fixes(&main, || simulate(&main, &[&lib])).unwrap(), 0);
+        assert_eq!(fs::read_to_string(&lib).unwrap(), lib_text);
+        assert_eq!(fs::read_to_string(&main).unwrap(), calling);
+
+        // A fix that doesn't help ends the loop
+        let mut runs = 0;
+        let stuck = || {
+            runs += 1;
+            let text = fs::read_to_string(&main).unwrap();
+            let line = (1..)
+                .zip(text.lines())
+                .find(|(_, l)| *l == "L10 P0")
+                .unwrap()
+                .0;
+            Err(SimpleError("Stuck".into())
+                .at_line(line)
+                .in_file(&main)
+                .with_fix(Fix::insert("M8")))
+        };
+        assert_eq!(apply_fixes(&main, stuck).unwrap(), 1);
+        assert_eq!(runs, 2);
+        assert_eq!(
+            fs::read_to_string(&main).unwrap(),
+            "%MPF1\nG0 Z150\nG0 X0 Y0\nM8\nL10 P0\nM2\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! Comparison of machined parts
+
+use crate::{errors::SimpleError, render::heightmap::HeightMap, types::Micrometer};
+use std::{
+    fmt,
+    io::{Error, Write},
+};
+
+/// Kind of deviation from the reference part
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deviation {
+    /// Part is lower than the reference, too much material removed
+    OverCut,
+    /// Part is higher than the reference, material left
+    UnderCut,
+}
+
+impl fmt::Display for Deviation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Deviation::OverCut => "over-cut",
+            Deviation::UnderCut => "under-cut",
+        }
+        .fmt(f)
+    }
+}
+
+/// Connected area of cells deviating beyond tolerance
+#[derive(Debug)]
+pub struct Region {
+    /// Material left or removed beyond tolerance
+    pub kind: Deviation,
+    /// Number of grid cells in the region
+    pub cells: usize,
+    /// Bounding box lower left corner in millimeters
+    pub min: (f64, f64),
+    /// Bounding box upper right corner in millimeters
+    pub max: (f64, f64),
+    /// Largest absolute deviation in the region
+    pub worst: Micrometer,
+}
+
+/// Result of comparing a part against the reference part
+#[derive(Debug)]
+pub struct Comparison {
+    grid: HeightMap,
+    /// Part height minus reference height for each cell
+    diff: Vec<Micrometer>,
+    tolerance: Micrometer,
+    max: Micrometer,
+    mean: f64,
+    regions: Vec<Region>,
+}
+
+impl Comparison {
+    /// Compare `part` against `reference` machined on the same stock
+    pub fn new(
+        part: &HeightMap,
+        reference: &HeightMap,
+        tolerance: Micrometer,
+    ) -> Result<Self, SimpleError> {
+        if !part.same_grid(reference) {
+            return Err(SimpleError(
+                "Height maps have different stock or resolution".into(),
+            ));
+        }
+
+        let (cols, rows) = part.size();
+        let diff: Vec<_> = (0..rows)
+            .flat_map(|row| (0..cols).map(move |col| (col, row)))
+            .map(|(col, row)| part.height(col, row) - reference.height(col, row))
+            .collect();
+
+        let max = diff.iter().map(|d| Micrometer(d.0.abs())).max();
+        let sum: i64 = diff.iter().map(|d| d.0.abs()).sum();
+        let mean = if diff.is_empty() {
+            0.0
+        } else {
+            sum as f64 / diff.len() as f64 / 1000.0
+        };
+
+        let mut cmp = Self {
+            grid: part.clone(),
+            diff,
+            tolerance,
+            max: max.unwrap_or(Micrometer(0)),
+            mean,
+            regions: Vec::new(),
+        };
+        cmp.regions = cmp.find_regions();
+        Ok(cmp)
+    }
+
+    /// Check if the part is within tolerance everywhere
+    pub fn passed(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    fn classify(&self, d: Micrometer) -> Option<Deviation> {
+        if d.0 < -self.tolerance.0 {
+            Some(Deviation::OverCut)
+        } else if d.0 > self.tolerance.0 {
+            Some(Deviation::UnderCut)
+        } else {
+            None
+        }
+    }
+
+    fn find_regions(&self) -> Vec<Region> {
+        let (cols, rows) = self.grid.size();
+        let mut seen = vec![false; self.diff.len()];
+        let mut regions = Vec::new();
+
+        for start in 0..self.diff.len() {
+            let Some(kind) = self.classify(self.diff[start]) else {
+                continue;
+            };
+            if seen[start] {
+                continue;
+            }
+
+            let mut region = Region {
+                kind,
+                cells: 0,
+                min: (f64::INFINITY, f64::INFINITY),
+                max: (f64::NEG_INFINITY, f64::NEG_INFINITY),
+                worst: Micrometer(0),
+            };
+            let half = self.grid.step().to_mm() / 2.0;
+            let mut queue = vec![start];
+            seen[start] = true;
+            while let Some(idx) = queue.pop() {
+                let (col, row) = (idx % cols, idx / cols);
+                let (x, y) = self.grid.cell_center(col, row);
+                region.cells += 1;
+                region.min = (region.min.0.min(x - half), region.min.1.min(y - half));
+                region.max = (region.max.0.max(x + half), region.max.1.max(y + half));
+                region.worst = region.worst.max(Micrometer(self.diff[idx].0.abs()));
+
+                let neighbours = [
+                    (col > 0).then(|| idx - 1),
+                    (col + 1 < cols).then(|| idx + 1),
+                    (row > 0).then(|| idx - cols),
+                    (row + 1 < rows).then(|| idx + cols),
+                ];
+                for n in neighbours.into_iter().flatten() {
+                    if !seen[n] && self.classify(self.diff[n]) == Some(kind) {
+                        seen[n] = true;
+                        queue.push(n);
+                    }
+                }
+            }
+            regions.push(region);
+        }
+
+        regions
+    }
+
+    /// Write the difference map as SVG heat map
+    pub fn write_svg(&self, mut fd: impl Write) -> Result<(), Error> {
+        let (cols, rows) = self.grid.size();
+        let step = self.grid.step().to_mm();
+        let (left, bottom) = (self.grid.origin().0.to_mm(), self.grid.origin().1.to_mm());
+        let (width, height) = (cols as f64 * step, rows as f64 * step);
+        let top = -(bottom + height);
+        writeln!(fd, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}mm\" height=\"{height}mm\" viewBox=\"{left} {top} {width} {height}\">")?;
+
+        for row in 0..rows {
+            // Merge runs of equally colored cells into one rectangle
+            let mut col = 0;
+            while col < cols {
+                let color = self.color(self.diff[row * cols + col]);
+                let run = (col..cols)
+                    .take_while(|&c| self.color(self.diff[row * cols + c]) == color)
+                    .count();
+                let x = left + col as f64 * step;
+                let y = -(bottom + (row + 1) as f64 * step);
+                let w = run as f64 * step;
+                writeln!(fd, "<rect x=\"{x}\" y=\"{y}\" width=\"{w}\" height=\"{step}\" stroke=\"none\" fill=\"{color}\" />")?;
+                col += run;
+            }
+        }
+
+        writeln!(fd, "</svg>")?;
+        fd.flush()
+    }
+
+    /// Heat map color: grey within tolerance, red over-cut, blue under-cut
+    fn color(&self, d: Micrometer) -> String {
+        const LEVELS: i64 = 8;
+        let Some(kind) = self.classify(d) else {
+            return "#d0d0d0".into();
+        };
+        let level = d.0.abs() * LEVELS / self.max.0.max(1);
+        let shade = 0xc0 - (level.min(LEVELS) * 0xc0 / LEVELS) as u8;
+        match kind {
+            Deviation::OverCut => format!("#ff{shade:02x}{shade:02x}"),
+            Deviation::UnderCut => format!("#{shade:02x}{shade:02x}ff"),
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Maximal deviation: {} mm", self.max)?;
+        writeln!(f, "Mean deviation: {:.3} mm", self.mean)?;
+        writeln!(f, "Tolerance: {} mm", self.tolerance)?;
+        for r in &self.regions {
+            let area = r.cells as f64 * self.grid.step().to_mm().powi(2);
+            writeln!(
+                f,
+                "  {} at X{:.3}..{:.3} Y{:.3}..{:.3}, area {area:.3} mm2, worst {} mm",
+                r.kind, r.min.0, r.max.0, r.min.1, r.max.1, r.worst
+            )?;
+        }
+        let verdict = if self.passed() { "PASS" } else { "FAIL" };
+        writeln!(f, "Result: {verdict}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Deviation};
+    use crate::render::{heightmap::HeightMap, Line, Render};
+    use crate::types::Micrometer;
+
+    fn mm(x: f64) -> Micrometer {
+        Micrometer::from_mm(x)
+    }
+
+    /// Groove along Y5 from X2 to X8 with its bottom at `depth`
+    fn groove(depth: f64) -> HeightMap {
+        let mut map = HeightMap::new(&"0,0,10,10,0".parse().unwrap(), mm(1.0));
+        map.line_to(mm(2.0), Line::Fast, (mm(2.0), mm(5.0)), mm(5.0));
+        map.line_to(mm(2.0), Line::Cut, (mm(2.0), mm(5.0)), mm(-depth));
+        map.line_to(mm(2.0), Line::Cut, (mm(8.0), mm(5.0)), mm(-depth));
+        map
+    }
+
+    #[test]
+    fn tolerance() {
+        let reference = groove(1.0);
+        let cmp = Comparison::new(&groove(1.0), &reference, mm(0.0)).unwrap();
+        assert!(cmp.passed());
+        assert_eq!(cmp.max, mm(0.0));
+
+        // 0.02 mm of material left
+        let part = groove(0.98);
+        let cmp = Comparison::new(&part, &reference, mm(0.05)).unwrap();
+        assert!(cmp.passed());
+        assert_eq!(cmp.max, mm(0.02));
+        assert!(cmp.to_string().ends_with("Result: PASS\n"));
+
+        let cmp = Comparison::new(&part, &reference, mm(0.01)).unwrap();
+        assert!(!cmp.passed());
+        assert_eq!(cmp.regions.len(), 1);
+        assert!(cmp.to_string().ends_with("Result: FAIL\n"));
+    }
+
+    #[test]
+    fn deviation_kinds() {
+        let reference = groove(1.0);
+        let cmp = Comparison::new(&groove(1.5), &reference, mm(0.05)).unwrap();
+        assert_eq!(cmp.regions.len(), 1);
+        let region = &cmp.regions[0];
+        assert_eq!(region.kind, Deviation::OverCut);
+        assert_eq!(region.worst, mm(0.5));
+        assert_eq!(region.cells, cmp.diff.iter().filter(|d| d.0 != 0).count());
+
+        let cmp = Comparison::new(&groove(0.5), &reference, mm(0.05)).unwrap();
+        assert_eq!(cmp.regions.len(), 1);
+        assert_eq!(cmp.regions[0].kind, Deviation::UnderCut);
+
+        // Uncut part against the groove
+        let stock = HeightMap::new(&"0,0,10,10,0".parse().unwrap(), mm(1.0));
+        let cmp = Comparison::new(&stock, &reference, mm(0.05)).unwrap();
+        assert!(cmp.regions.iter().all(|r| r.kind == Deviation::UnderCut));
+        assert_eq!(cmp.max, mm(1.0));
+
+        let coarse = HeightMap::new(&"0,0,10,10,0".parse().unwrap(), mm(2.0));
+        assert!(Comparison::new(&coarse, &reference, mm(0.05)).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Interactive step debugger
+
+use millsim::{
+    diagnostic::Diagnostic,
+    errors::LineError,
+    gcode::words::Word,
+    machine::{backtrace, Executor, Machine},
+};
+use std::{
+    fmt,
+    io::{BufRead, Error, Write},
+    str::FromStr,
+};
+
+/// Place to stop execution at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Before the block at file line
+    Line(u64),
+    /// Before the block with N number
+    Number(u32),
+    /// On entry into subprogram, by name like `L5` or `POCKET`
+    Sub(String),
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid breakpoint '{s}', expected LINE, N<number> or subprogram");
+        let numeric = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+        match s.strip_prefix('N') {
+            Some(n) if numeric(n) => n.parse().map(Breakpoint::Number).map_err(|_| err()),
+            _ if numeric(s) => s.parse().map(Breakpoint::Line).map_err(|_| err()),
+            _ if !s.is_empty() => Ok(Breakpoint::Sub(s.to_owned())),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Line(n) => write!(f, "line {n}"),
+            Breakpoint::Number(n) => write!(f, "block N{n}"),
+            Breakpoint::Sub(n) => write!(f, "entry into {n}"),
+        }
+    }
+}
+
+/// Reason of stopping execution
+#[derive(Debug)]
+enum Stop {
+    Step,
+    Breakpoint(Breakpoint),
+    Error(LineError),
+    End,
+}
+
+/// Executor and machine stepped together
+pub struct Debugger<'t> {
+    exec: Executor<'t>,
+    machine: Machine,
+    breakpoints: Vec<Breakpoint>,
+    /// Rule violations not shown yet
+    reported: Vec<Diagnostic>,
+}
+
+impl<'t> Debugger<'t> {
+    pub fn new(exec: Executor<'t>, machine: Machine) -> Self {
+        Self {
+            exec,
+            machine,
+            breakpoints: Vec::new(),
+            reported: Vec::new(),
+        }
+    }
+
+    /// Executor positioned at the next block
+    pub fn executor(&self) -> &Executor<'t> {
+        &self.exec
+    }
+
+    /// Machine state after the last executed block
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Run until the `count`-th time the next block matches `target`
+    ///
+    /// Returns `false` if the program ended before.
+    pub fn run_to(&mut self, target: Breakpoint, count: usize) -> Result<bool, LineError> {
+        self.breakpoints = vec![target];
+        let mut depth = self.exec.depth();
+        let mut hits = 0;
+        loop {
+            if self.hit(depth).is_some() {
+                hits += 1;
+                if hits >= count {
+                    return Ok(true);
+                }
+            }
+            depth = self.exec.depth();
+            match self.step() {
+                None => return Ok(false),
+                Some(r) => r?,
+            }
+        }
+    }
+
+    /// Execute one block, `None` at program end
+    fn step(&mut self) -> Option<Result<(), LineError>> {
+        let (line, cmd) = match self.exec.next()? {
+            Ok(c) => c,
+            Err(e) => return Some(Err(e)),
+        };
+        let reported = self.machine.execute_block(cmd, &self.exec, line);
+        Some(reported.map(|r| self.reported.extend(r)))
+    }
+
+    /// Breakpoint matching the next block, `depth` is the nesting before last step
+    fn hit(&self, depth: usize) -> Option<Breakpoint> {
+        let (line, words) = self.exec.peek()?;
+        let stack = self.exec.call_stack();
+        let entered = match stack.last() {
+            Some(f) if self.exec.depth() > depth => Some(&f.program),
+            _ => None,
+        };
+        self.breakpoints
+            .iter()
+            .find(|b| match b {
+                Breakpoint::Line(n) => *n == line,
+                Breakpoint::Number(n) => words.0.contains(&Word::N(*n)),
+                Breakpoint::Sub(n) => entered.is_some_and(|p| p.name() == n),
+            })
+            .cloned()
+    }
+
+    /// Execute blocks until `done` returns true, a breakpoint or an error
+    fn run(&mut self, breakpoints: bool, done: impl Fn(&Self) -> bool) -> Stop {
+        loop {
+            let depth = self.exec.depth();
+            match self.step() {
+                None => return Stop::End,
+                Some(Err(e)) => return Stop::Error(e),
+                Some(Ok(())) => (),
+            }
+            if done(self) {
+                return Stop::Step;
+            }
+            if let Some(b) = self.hit(depth).filter(|_| breakpoints) {
+                return Stop::Breakpoint(b);
+            }
+        }
+    }
+
+    fn report(&mut self, stop: Stop, out: &mut impl Write) -> Result<(), Error> {
+        for d in self.reported.drain(..) {
+            writeln!(out, "{d}")?;
+        }
+        match stop {
+            Stop::Step | Stop::End => (),
+            Stop::Breakpoint(b) => writeln!(out, "Breakpoint at {b}")?,
+            Stop::Error(e) => write!(out, "{e}")?,
+        }
+        match self.exec.peek() {
+            Some((line, words)) => writeln!(out, "{line}: {words}"),
+            None => writeln!(out, "Program finished"),
+        }
+    }
+
+    /// Read commands from `input` until the user quits
+    pub fn repl(mut self, mut input: impl BufRead, mut out: impl Write) -> Result<(), Error> {
+        self.report(Stop::Step, &mut out)?;
+        let mut last = String::new();
+        loop {
+            write!(out, "(millsim) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = match line.trim() {
+                "" => last.clone(),
+                l => l.to_owned(),
+            };
+            let mut args = line.split_whitespace();
+            let Some(cmd) = args.next() else {
+                continue;
+            };
+            last = line.clone();
+
+            let running = self.exec.peek().is_some();
+            let depth = self.exec.depth();
+            match cmd {
+                "s" | "step" | "n" | "next" | "f" | "finish" | "c" | "continue" | "e" | "error"
+                    if !running =>
+                {
+                    writeln!(out, "Program is not running")?;
+                }
+                "s" | "step" => {
+                    let stop = self.run(true, |_| true);
+                    self.report(stop, &mut out)?;
+                }
+                "n" | "next" => {
+                    let stop = self.run(true, |d| d.exec.depth() <= depth);
+                    self.report(stop, &mut out)?;
+                }
+                "f" | "finish" => {
+                    let stop = self.run(true, |d| d.exec.depth() < depth);
+                    self.report(stop, &mut out)?;
+                }
+                "c" | "continue" => {
+                    let stop = self.run(true, |_| false);
+                    self.report(stop, &mut out)?;
+                }
+                "e" | "error" => {
+                    let stop = self.run(false, |_| false);
+                    self.report(stop, &mut out)?;
+                }
+                "b" | "break" => match args.next().map(str::parse::<Breakpoint>) {
+                    Some(Ok(b)) => {
+                        writeln!(out, "Breakpoint {} at {b}", self.breakpoints.len() + 1)?;
+                        self.breakpoints.push(b);
+                    }
+                    Some(Err(e)) => writeln!(out, "{e}")?,
+                    None => {
+                        for (i, b) in self.breakpoints.iter().enumerate() {
+                            writeln!(out, "{}: {b}", i + 1)?;
+                        }
+                    }
+                },
+                "d" | "delete" => match args.next().map(str::parse::<usize>) {
+                    Some(Ok(i)) if (1..=self.breakpoints.len()).contains(&i) => {
+                        self.breakpoints.remove(i - 1);
+                    }
+                    None => self.breakpoints.clear(),
+                    _ => writeln!(out, "No such breakpoint")?,
+                },
+                "p" | "print" => write!(out, "{}", self.machine.state())?,
+                "r" | "params" => {
+                    for (n, v) in self.exec.parameters() {
+                        writeln!(out, "R{n}={v}")?;
+                    }
+                    for (name, ty, v) in self.exec.locals() {
+                        writeln!(out, "{ty} {name}={v}")?;
+                    }
+                }
+                "bt" | "backtrace" => {
+                    let stack = self.exec.call_stack();
+                    for call in backtrace(&stack) {
+                        writeln!(out, "{call}")?;
+                    }
+                    writeln!(out, "{}", stack[0])?;
+                }
+                "h" | "help" => write!(out, "{HELP}")?,
+                "q" | "quit" => return Ok(()),
+                _ => writeln!(out, "Unknown command '{cmd}', try 'help'")?,
+            }
+        }
+    }
+}
+
+const HELP: &str = "\
+step (s)            execute one block, entering subprograms
+next (n)            execute one block, stepping over subprogram calls
+finish (f)          run until the current subprogram returns
+continue (c)        run until a breakpoint, an error or the program end
+error (e)           run until the next error, ignoring breakpoints
+break (b) [WHERE]   stop before file line WHERE, at block N<n> or on entry into
+                    subprogram like L<n> or its name;
+                    list breakpoints without WHERE
+delete (d) [INDEX]  delete breakpoint, all breakpoints without INDEX
+print (p)           print machine state
+params (r)          print R parameters and local variables
+backtrace (bt)      print subprogram call stack
+quit (q)            leave the debugger
+";
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use millsim::{GCodeFile, Machine, Program};
+
+    const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D1
+R1=2
+L3 P1
+G0 Z150
+M5 M9
+M2
+%SPF3
+G1 X10 Z-1 F100
+G0 Z150
+M17
+";
+
+    fn program() -> Program {
+        let file = GCodeFile::parse("part.mpf", PART).unwrap();
+        Program::from_file(file).unwrap()
+    }
+
+    /// Output of the debugger reading the commands of `script`
+    fn session(script: &str) -> String {
+        let program = program();
+        let dbg = Debugger::new(program.execute(None).unwrap(), Machine::default());
+        let mut out = Vec::new();
+        dbg.repl(script.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn repl() {
+        // An empty line repeats the last command
+        let out = session("b L3\nb 9\nc\nbt\ns\n\nn\nf\nc\nc\ns\nq\n");
+        let expected = "\
+2: G0 Z150.000
+(millsim) Breakpoint 1 at entry into L3
+(millsim) Breakpoint 2 at line 9
+(millsim) Breakpoint at entry into L3
+12: G1 X10.000 Z-1.000 F100
+(millsim) in L3 (repeat 1 of 2) called from line 7 of %MPF1
+%MPF1
+(millsim) 13: G0 Z150.000
+(millsim) 14: M17
+(millsim) 12: G1 X10.000 Z-1.000 F100
+(millsim) 8: G0 Z150.000
+(millsim) Breakpoint at line 9
+9: M5 M9
+(millsim) Program finished
+(millsim) Program is not running
+(millsim) ";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let out = session("b N99999999999\nb 12\nb 13\nb\nd 1\nc\nd 3\nd\nc\n");
+        let expected = "\
+2: G0 Z150.000
+(millsim) Invalid breakpoint 'N99999999999', expected LINE, N<number> or subprogram
+(millsim) Breakpoint 1 at line 12
+(millsim) Breakpoint 2 at line 13
+(millsim) 1: line 12
+2: line 13
+(millsim) (millsim) Breakpoint at line 13
+13: G0 Z150.000
+(millsim) No such breakpoint
+(millsim) (millsim) Program finished
+(millsim) ";
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,430 @@
+//! Diagnostics with stable codes and their configuration
+//!
+//! Every problem the simulator reports belongs to a [`Rule`] with a code like
+//! `MS0012` and a name like `spindle-without-coolant`. Rules checked while the
+//! machine runs can be disabled, demoted or promoted by the machine
+//! configuration and by pragma comments in the G-code files:
+//!
+//! ```text
+//! ; millsim: spindle-without-coolant=warning, MS0025=off
+//! ```
+//!
+//! The remaining rules stand for syntax, program and runtime errors which
+//! always stop the program.
+
+use crate::{
+    errors::{location, Fix, LineError, SimpleError},
+    i18n::tr,
+    machine::{backtrace, Frame},
+};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use strum::{EnumIter, IntoEnumIterator, IntoStaticStr};
+
+/// How bad a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Worth knowing, nothing wrong
+    Note,
+    /// Program runs but does something questionable
+    Warning,
+    /// Program fails, never ends or may damage the machine
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Note => tr!("note").fmt(f),
+            Severity::Warning => tr!("warning").fmt(f),
+            Severity::Error => tr!("error").fmt(f),
+        }
+    }
+}
+
+/// Kind of problem with a stable code
+///
+/// The discriminant is the number of the code, never reuse one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Rule {
+    /// Line which doesn't parse
+    SyntaxError = 1,
+    /// Program structure the control rejects before running
+    ProgramError = 2,
+    /// Error stopping the running program
+    RuntimeError = 3,
+
+    /// Subprogram no program calls
+    UnusedSubprogram = 4,
+    /// Call of a subprogram defined nowhere
+    MissingSubprogram = 5,
+    /// Subprogram calling itself, directly or not
+    RecursiveCall = 6,
+    /// Calls nested deeper than the control allows
+    CallTooDeep = 7,
+    /// Blocks after the program end, dead or reached by skipping it
+    CodeAfterEnd = 8,
+
+    /// Program ends with the spindle running
+    EndWithSpindleOn = 10,
+    /// Program ends with the coolant on
+    EndWithCoolantOn = 11,
+    /// Spindle started with the coolant off
+    SpindleWithoutCoolant = 12,
+    /// Spindle started with no speed
+    SpindleWithoutSpeed = 13,
+    /// Spindle started counterclockwise
+    SpindleBackwards = 14,
+    /// Spindle stopped in the same block as a movement
+    SpindleOffWhileMoving = 15,
+    /// Coolant stopped in the same block as a movement
+    CoolantOffWhileMoving = 16,
+    /// Coolant stopped with the spindle running
+    CoolantOffWithSpindle = 17,
+    /// Program ends below the safe Z height
+    EndBelowSafeZ = 18,
+    /// First movement doesn't go to the safe Z height
+    FirstMoveBelowSafeZ = 19,
+    /// Movement from a position not fully known
+    UnsafeMoveFromUnknownPosition = 20,
+    /// Tool change with the spindle or coolant on
+    ToolChangeWithSpindleOn = 21,
+    /// Tool change below the safe Z height
+    ToolChangeBelowSafeZ = 22,
+    /// Tool change without a stop
+    ToolChangeWithoutStop = 23,
+    /// Cut with the spindle stopped
+    CutWithSpindleOff = 24,
+    /// Cut with the coolant off
+    CutWithoutCoolant = 25,
+    /// Spindle speed outside of the machine limits
+    SpeedOutOfRange = 26,
+    /// Feed outside of the machine limits
+    FeedOutOfRange = 27,
+    /// Cut with no tool selected
+    CutWithoutTool = 28,
+}
+
+impl Rule {
+    /// Stable code like `MS0012`
+    pub fn code(self) -> String {
+        format!("MS{:04}", self as u16)
+    }
+
+    /// Kebab-case name like `spindle-without-coolant`
+    pub fn name(self) -> &'static str {
+        self.into()
+    }
+
+    /// Severity unless configured otherwise
+    pub fn default_severity(self) -> Severity {
+        match self {
+            Rule::UnusedSubprogram => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// Errors which stop the program no matter the configuration
+    pub fn is_fatal(self) -> bool {
+        matches!(
+            self,
+            Rule::SyntaxError | Rule::ProgramError | Rule::RuntimeError
+        )
+    }
+
+    /// All rules in the order of their codes
+    pub fn all() -> impl Iterator<Item = Rule> {
+        Rule::iter()
+    }
+}
+
+impl FromStr for Rule {
+    type Err = SimpleError;
+
+    /// Find rule by code or name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Rule::all()
+            .find(|r| r.code().eq_ignore_ascii_case(s))
+            .or_else(|| Rule::all().find(|r| r.name() == s))
+            .ok_or_else(|| SimpleError(format!("Unknown rule '{s}'")))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.name())
+    }
+}
+
+/// Configured level of a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Rule is not checked
+    Off,
+    /// Reported as a note
+    Note,
+    /// Reported as a warning
+    Warning,
+    /// Reported as an error, stopping the program
+    Error,
+}
+
+impl Level {
+    /// Severity of the diagnostics, `None` if the rule is off
+    pub fn severity(self) -> Option<Severity> {
+        match self {
+            Level::Off => None,
+            Level::Note => Some(Severity::Note),
+            Level::Warning => Some(Severity::Warning),
+            Level::Error => Some(Severity::Error),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Level::Off),
+            "note" => Ok(Level::Note),
+            "warning" => Ok(Level::Warning),
+            "error" => Ok(Level::Error),
+            _ => Err(SimpleError(format!(
+                "Unknown rule level '{s}', expected off, note, warning or error"
+            ))),
+        }
+    }
+}
+
+/// Levels of rules differing from their default
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BTreeMap<String, Level>")]
+pub struct RuleSet(BTreeMap<Rule, Level>);
+
+impl RuleSet {
+    /// Prefix of the pragma comments configuring rules
+    pub const PRAGMA: &'static str = "millsim:";
+
+    /// Configure a rule, fatal ones can't be changed
+    pub fn set(&mut self, rule: Rule, level: Level) -> Result<(), SimpleError> {
+        if rule.is_fatal() {
+            return Err(SimpleError(format!("Rule {rule} can't be configured")));
+        }
+        self.0.insert(rule, level);
+        Ok(())
+    }
+
+    /// Configured level of a rule, `None` if it has the default one
+    pub fn level(&self, rule: Rule) -> Option<Level> {
+        self.0.get(&rule).copied()
+    }
+
+    /// Configuration overridden by another one
+    pub fn merged(&self, other: &RuleSet) -> RuleSet {
+        let mut rules = self.clone();
+        rules.0.extend(&other.0);
+        rules
+    }
+
+    /// Read a comment like `millsim: spindle-without-coolant=warning`,
+    /// `None` if the comment is no pragma
+    pub fn parse_pragma(comment: &str) -> Option<Result<RuleSet, SimpleError>> {
+        let settings = comment.trim().strip_prefix(Self::PRAGMA)?;
+        let mut rules = RuleSet::default();
+        let mut add = |setting: &str| {
+            let (rule, level) = setting.split_once('=').ok_or_else(|| {
+                SimpleError(format!(
+                    "Expected 'rule=level' in pragma, found '{setting}'"
+                ))
+            })?;
+            rules.set(rule.trim().parse()?, level.trim().parse()?)
+        };
+        let added = settings
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .try_for_each(&mut add);
+        Some(added.map(|()| rules))
+    }
+}
+
+impl TryFrom<BTreeMap<String, Level>> for RuleSet {
+    type Error = String;
+
+    fn try_from(map: BTreeMap<String, Level>) -> Result<Self, Self::Error> {
+        let mut rules = RuleSet::default();
+        for (rule, level) in map {
+            rule.parse()
+                .and_then(|rule| rules.set(rule, level))
+                .map_err(|e| e.0)?;
+        }
+        Ok(rules)
+    }
+}
+
+/// Problem with its code, severity and place in the source
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    /// Rule the problem breaks
+    #[serde(rename = "code", serialize_with = "code")]
+    pub rule: Rule,
+    /// Configured severity of the rule
+    pub severity: Severity,
+    /// Message in the chosen language
+    pub message: String,
+    /// File of the problem, if known
+    pub file: Option<PathBuf>,
+    /// Line numbered from 1
+    pub line: Option<u64>,
+    /// Column in characters, numbered from 1
+    pub column: Option<usize>,
+    /// Suggested change of the source
+    pub fix: Option<Fix>,
+    /// Subprogram call stack of runtime diagnostics, main program first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stack: Vec<Frame>,
+}
+
+fn code<S: Serializer>(rule: &Rule, serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Code {
+        id: String,
+        name: &'static str,
+    }
+    Code {
+        id: rule.code(),
+        name: rule.name(),
+    }
+    .serialize(serializer)
+}
+
+impl Diagnostic {
+    /// Diagnostic of a rule with its default severity and no place
+    pub fn new(rule: Rule, message: impl Into<String>) -> Self {
+        Self {
+            rule,
+            severity: rule.default_severity(),
+            message: message.into(),
+            file: None,
+            line: None,
+            column: None,
+            fix: None,
+            stack: Vec::new(),
+        }
+    }
+
+    /// Accompany with a suggested fix
+    pub fn with_fix(self, fix: Fix) -> Self {
+        Self {
+            fix: Some(fix),
+            ..self
+        }
+    }
+
+    /// Place the diagnostic at a line of a file
+    pub fn at(self, file: &Path, line: u64) -> Self {
+        Self {
+            file: Some(file.to_owned()),
+            line: Some(line),
+            ..self
+        }
+    }
+
+    /// Diagnostic of an error, `rule` unless the error knows better
+    pub fn from_error(error: &LineError, rule: Rule) -> Self {
+        Self {
+            rule: error.rule().unwrap_or(rule),
+            severity: Severity::Error,
+            message: error.message().to_owned(),
+            file: error.file().map(Path::to_owned),
+            line: error.line(),
+            column: error.column(),
+            fix: error.fix().cloned(),
+            stack: error.stack().to_vec(),
+        }
+    }
+
+    /// Error stopping the program
+    pub fn into_error(self) -> LineError {
+        let error = SimpleError(self.message);
+        let mut error = match self.line {
+            Some(line) => error.at_line(line),
+            None => error.no_line(),
+        }
+        .with_rule(self.rule)
+        .with_stack(self.stack);
+        if let Some(column) = self.column {
+            error = error.at_column(column);
+        }
+        if let Some(file) = &self.file {
+            error = error.in_file(file);
+        }
+        match self.fix {
+            Some(fix) => error.with_fix(fix),
+            None => error,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.severity)?;
+        if let Some(location) = location(self.file.as_deref(), self.line, self.column) {
+            write!(f, " {location}")?;
+        }
+        write!(f, ": {} [{}]", self.message, self.rule)?;
+        for call in backtrace(&self.stack) {
+            write!(f, "\n  {call}")?;
+        }
+        match &self.fix {
+            Some(fix) => write!(f, "\n{}", tr!("suggested-fix", fix = fix)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Level, Rule, RuleSet, Severity};
+
+    #[test]
+    fn rule_codes() {
+        assert_eq!(Rule::SpindleWithoutCoolant.code(), "MS0012");
+        assert_eq!(
+            Rule::SpindleWithoutCoolant.name(),
+            "spindle-without-coolant"
+        );
+        assert_eq!("ms0025".parse::<Rule>().unwrap(), Rule::CutWithoutCoolant);
+        assert_eq!("call-too-deep".parse::<Rule>().unwrap(), Rule::CallTooDeep);
+        assert!("spindle".parse::<Rule>().is_err());
+        assert_eq!(Rule::UnusedSubprogram.default_severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn pragmas() {
+        let rules = RuleSet::parse_pragma(" millsim: spindle-without-coolant=warning, MS0025=off")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rules.level(Rule::SpindleWithoutCoolant),
+            Some(Level::Warning)
+        );
+        assert_eq!(rules.level(Rule::CutWithoutCoolant), Some(Level::Off));
+        assert_eq!(rules.level(Rule::CutWithoutTool), None);
+        assert!(RuleSet::parse_pragma(" rapid move").is_none());
+        assert!(RuleSet::parse_pragma("millsim: runtime-error=off")
+            .unwrap()
+            .is_err());
+        assert!(RuleSet::parse_pragma("millsim: cut-without-tool")
+            .unwrap()
+            .is_err());
+    }
+}
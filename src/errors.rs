@@ -1,6 +1,15 @@
 //! G-Code processing errors
 
-use std::fmt;
+use crate::{
+    diagnostic::Rule,
+    i18n::tr,
+    machine::{backtrace, Frame},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 
 /// Simple error message from bottom level
 #[derive(Debug)]
@@ -12,6 +21,11 @@ impl SimpleError {
         LineError {
             error: self,
             line: Some(line),
+            column: None,
+            file: None,
+            fix: None,
+            rule: None,
+            stack: Box::default(),
         }
     }
 
@@ -20,28 +34,171 @@ impl SimpleError {
         LineError {
             error: self,
             line: None,
+            column: None,
+            file: None,
+            fix: None,
+            rule: None,
+            stack: Box::default(),
         }
     }
 }
 
 impl fmt::Display for SimpleError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "Error: {}", self.0)
+        writeln!(f, "{}: {}", tr!("error"), self.0)
     }
 }
 
-/// Error message with line number
+/// Change of the program source fixing an error
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fix {
+    /// Block to insert before the failing one
+    pub insert: String,
+}
+
+impl Fix {
+    /// Fix inserting `block` before the failing one
+    pub fn insert(block: impl Into<String>) -> Self {
+        Self {
+            insert: block.into(),
+        }
+    }
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        tr!("fix-insert", block = self.insert).fmt(f)
+    }
+}
+
+/// Error message with line number and file name
 #[derive(Debug)]
 pub struct LineError {
     error: SimpleError,
     line: Option<u64>,
+    /// Column in characters, numbered from 1
+    column: Option<usize>,
+    file: Option<PathBuf>,
+    /// Suggested fix of the failing block
+    fix: Option<Box<Fix>>,
+    /// Rule of the diagnostic the error comes from
+    rule: Option<Rule>,
+    /// Subprogram call stack, main program first
+    stack: Box<[Frame]>,
+}
+
+impl LineError {
+    /// Accompany with the file name unless it is already known
+    pub fn in_file(mut self, path: &Path) -> Self {
+        self.file.get_or_insert_with(|| path.to_owned());
+        self
+    }
+
+    /// Accompany with line number unless it is already known
+    pub fn at_line(mut self, line: u64) -> Self {
+        self.line.get_or_insert(line);
+        self
+    }
+
+    /// Accompany with the column of the problem in the line
+    pub fn at_column(self, column: usize) -> Self {
+        Self {
+            column: Some(column),
+            ..self
+        }
+    }
+
+    /// Accompany with the rule unless it is already known
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rule.get_or_insert(rule);
+        self
+    }
+
+    /// Accompany with the call stack unless it is already known
+    pub fn with_stack(mut self, stack: Vec<Frame>) -> Self {
+        if self.stack.is_empty() {
+            self.stack = stack.into_boxed_slice();
+        }
+        self
+    }
+
+    /// Accompany with a suggested fix of the failing block
+    pub fn with_fix(self, fix: Fix) -> Self {
+        Self {
+            fix: Some(Box::new(fix)),
+            ..self
+        }
+    }
+
+    /// Error message without the location
+    pub fn message(&self) -> &str {
+        &self.error.0
+    }
+
+    /// Line numbered from 1, if known
+    pub fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    /// Column in characters numbered from 1, if known
+    pub fn column(&self) -> Option<usize> {
+        self.column
+    }
+
+    /// File of the error, if known
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// Suggested fix of the failing block
+    pub fn fix(&self) -> Option<&Fix> {
+        self.fix.as_deref()
+    }
+
+    /// Rule of the diagnostic the error comes from
+    pub fn rule(&self) -> Option<Rule> {
+        self.rule
+    }
+
+    /// Subprogram call stack, main program first, empty if unknown
+    pub fn stack(&self) -> &[Frame] {
+        &self.stack
+    }
 }
 
 impl fmt::Display for LineError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(line) = self.line {
-            writeln!(f, "At line {line}:")?;
+        if let Some(location) = location(self.file.as_deref(), self.line, self.column) {
+            let mut chars = location.chars();
+            let first = chars.next().into_iter().flat_map(char::to_uppercase);
+            writeln!(f, "{}:", first.chain(chars).collect::<String>())?;
+        }
+        match self.rule {
+            Some(rule) => writeln!(f, "{}: {} [{rule}]", tr!("error"), self.error.0)?,
+            None => self.error.fmt(f)?,
         }
-        self.error.fmt(f)
+        for call in backtrace(&self.stack) {
+            writeln!(f, "  {call}")?;
+        }
+        match &self.fix {
+            Some(fix) => writeln!(f, "{}", tr!("suggested-fix", fix = fix)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Place of a problem like "at line 3, column 5 of 'part.mpf'"
+pub fn location(file: Option<&Path>, line: Option<u64>, column: Option<usize>) -> Option<String> {
+    let at = match (line, column) {
+        (Some(line), Some(column)) => Some(tr!("at-line-column", line = line, column = column)),
+        (Some(line), None) => Some(tr!("at-line", line = line)),
+        (None, _) => None,
+    };
+    let file = file.map(Path::display);
+    match (at, file) {
+        (Some(at), Some(file)) => Some(format!("{at} {}", tr!("of-file", file = file))),
+        (Some(at), None) => Some(at),
+        (None, Some(file)) => Some(tr!("in-file", file = file)),
+        (None, None) => None,
     }
 }
@@ -0,0 +1,213 @@
+//! Single main program with all subprogram calls unrolled
+//!
+//! Built-in cycles are kept as they are since the machine does not simulate
+//! them yet.
+
+use millsim::{
+    gcode::{
+        expr::Expr,
+        words::{MWord, Word, Words},
+    },
+    machine::{is_builtin, Executor, Frame, ProgramId},
+};
+use std::{collections::BTreeMap, fmt};
+
+/// Executed blocks written out as one main program
+#[derive(Debug, Default)]
+pub struct Flattened {
+    program: Option<ProgramId>,
+    blocks: Vec<Words>,
+    /// Write R parameters and expressions as literals
+    evaluate: bool,
+    /// R parameters after the last block
+    params: BTreeMap<u8, f64>,
+}
+
+impl Flattened {
+    /// Flatten with expressions evaluated or kept as in the source
+    pub fn new(evaluate: bool) -> Self {
+        Self {
+            evaluate,
+            ..Self::default()
+        }
+    }
+
+    /// Record executed block, `raw` is the block with expressions evaluated
+    pub fn record(&mut self, line: u64, raw: &Words, exec: &Executor) {
+        let stack = exec.block_stack();
+        self.program.get_or_insert_with(|| stack[0].program.clone());
+        let source = exec.source().expect("Bug: no block executed");
+        let origin = format!(
+            "{}:{line} {}",
+            exec.file()
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            Stack(&stack)
+        );
+
+        // The executor produces one evaluated word for every source word
+        let mut comment = None;
+        let mut block = Vec::new();
+        for (word, value) in source.0.iter().zip(&raw.0) {
+            let word = match word {
+                // Local variables don't exist outside of their subprogram
+                Word::R(_, e) | Word::Computed(_, e) if self.evaluate || e.has_variables() => value,
+                Word::R(..) if self.evaluate => continue,
+                Word::LineComment(c) => {
+                    comment = Some(c);
+                    continue;
+                }
+                // Line numbers are no jump targets any more
+                Word::N(_) | Word::Label(_) | Word::Goto(_) | Word::Control(_) => continue,
+                Word::Proc(..) | Word::Def(..) | Word::Assign(..) => continue,
+                Word::Call(..) | Word::P(_) | Word::M(MWord::M17) => continue,
+                Word::L(n) if !is_builtin(*n) => continue,
+                w => w,
+            };
+            block.push(word.clone());
+        }
+        if !self.evaluate {
+            // Loop counters are set by FOR and ENDFOR with no R word
+            for (n, v) in exec.parameters() {
+                let assigned = source
+                    .0
+                    .iter()
+                    .any(|w| matches!(w, Word::R(r, _) if r == n));
+                if self.params.get(n) != Some(v) && !assigned {
+                    block.push(Word::R(*n, Expr::Num(*v)));
+                }
+            }
+            self.params.clone_from(exec.parameters());
+        }
+
+        if !block.is_empty() {
+            let comment = match comment {
+                Some(c) => format!("{origin} {}", c.trim()),
+                None => origin,
+            };
+            block.push(Word::LineComment(comment));
+            self.blocks.push(Words(block));
+        }
+    }
+}
+
+impl fmt::Display for Flattened {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(p) = &self.program {
+            writeln!(f, "{p}")?;
+        }
+        for block in &self.blocks {
+            writeln!(f, "{block}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Call stack as `%MPF1:10>%SPF5`, main program first
+struct Stack<'t>(&'t [Frame]);
+
+impl fmt::Display for Stack<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.0.iter().enumerate() {
+            if let Some(line) = frame.call_line.filter(|_| i > 0) {
+                write!(f, ":{line}>")?;
+            }
+            frame.program.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Flattened;
+    use crate::report::stats::{Collector, Statistics};
+    use millsim::{render, GCodeFile, Machine, Program};
+
+    const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D2
+R1=2
+FOR R2=1 TO 2
+  POCKET(R2*R1, 3) ; pocket
+ENDFOR
+L3 P1
+G0 Z150
+M5 M9
+M2
+%_N_POCKET_SPF
+PROC POCKET(REAL DEPTH, INT SIDE)
+DEF REAL HALF=SIDE/2
+G1 Z=-DEPTH F200
+G1 X=HALF Y=HALF
+G2 X=SIDE Y=SIDE I=HALF J=0
+G0 Z150
+G0 X0 Y0
+M17
+%SPF3
+G1 X10 Z-1 F100
+G91 G1 X5
+G90 G0 Z150
+M17
+";
+
+    /// Machining time and statistics of the program, flattened with its run
+    fn simulate(text: &str, evaluate: bool) -> (f64, Statistics, String) {
+        let program = Program::from_file(GCodeFile::parse("part.mpf", text).unwrap()).unwrap();
+        let mut flat = Flattened::new(evaluate);
+        let mut machine = Machine::with_renders(vec![Box::<Collector>::default()]);
+        machine
+            .run(
+                program.execute(None).unwrap(),
+                |d| panic!("unexpected diagnostic {d}"),
+                |line, raw, exec, _| {
+                    flat.record(line, raw, exec);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let elapsed = machine.elapsed();
+        let mut renders = machine.finalize();
+        let collector = render::take::<Collector>(&mut renders).unwrap();
+        (elapsed, collector.into_statistics(0, 0), flat.to_string())
+    }
+
+    #[test]
+    fn same_run() {
+        let (time, stats, _) = simulate(PART, false);
+        assert!(time > 0.0 && stats.cut_length > 0.0);
+        for evaluate in [false, true] {
+            let (_, _, flat) = simulate(PART, evaluate);
+            assert!(!flat.contains("POCKET(") && !flat.contains("L3 "), "{flat}");
+            let (flat_time, flat_stats, _) = simulate(&flat, evaluate);
+            assert!((flat_time - time).abs() < 1e-9, "{evaluate}: {flat}");
+            assert_eq!(
+                serde_json::to_value(&flat_stats).unwrap(),
+                serde_json::to_value(&stats).unwrap(),
+                "{evaluate}: {flat}"
+            );
+        }
+    }
+
+    #[test]
+    fn origins() {
+        // The comment of a call names the program it ran in, not the called one
+        let (_, _, flat) = simulate("%MPF1\nR1=150 L3 P0\nM2\n%SPF3\nG0 Z=R1\nM17\n", false);
+        let origins = flat
+            .lines()
+            .filter_map(|l| l.split_once(';'))
+            .map(|(_, c)| c);
+        assert_eq!(
+            origins.collect::<Vec<_>>(),
+            [
+                "part.mpf:2 %MPF1",
+                "part.mpf:5 %MPF1:2>%SPF3",
+                "part.mpf:3 %MPF1"
+            ]
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! Arithmetic expressions over R parameters and variables
+
+use crate::{errors::SimpleError, i18n::tr};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, satisfy, space0, u8},
+    combinator::{map, not, value, verify},
+    multi::many0,
+    number::complete::double,
+    sequence::{delimited, pair, preceded, terminated},
+    IResult,
+};
+use std::fmt;
+
+/// Values visible to an expression
+pub trait Scope {
+    /// R parameter value
+    fn param(&self, n: u8) -> f64;
+    /// Local variable value
+    fn var(&self, name: &str) -> Result<f64, SimpleError>;
+}
+
+/// R parameters only, no local variables
+impl<F: Fn(u8) -> f64> Scope for F {
+    fn param(&self, n: u8) -> f64 {
+        self(n)
+    }
+
+    fn var(&self, name: &str) -> Result<f64, SimpleError> {
+        Err(SimpleError(tr!("MS0003-undefined", name = name)))
+    }
+}
+
+/// Binary operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `OR`
+    Or,
+    /// `AND`
+    And,
+    /// `==`
+    Eq,
+    /// `<>`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+    /// `+`
+    Add,
+    /// `-`
+    Sub,
+    /// `*`
+    Mul,
+    /// `/`
+    Div,
+}
+
+impl Op {
+    fn precedence(self) -> u8 {
+        use Op::*;
+        match self {
+            Or => 1,
+            And => 2,
+            Eq | Ne | Lt | Le | Gt | Ge => 3,
+            Add | Sub => 4,
+            Mul | Div => 5,
+        }
+    }
+
+    fn apply(self, a: f64, b: f64) -> Result<f64, SimpleError> {
+        use Op::*;
+        let bool = |v: bool| if v { 1.0 } else { 0.0 };
+        Ok(match self {
+            Or => bool(a != 0.0 || b != 0.0),
+            And => bool(a != 0.0 && b != 0.0),
+            Eq => bool(a == b),
+            Ne => bool(a != b),
+            Lt => bool(a < b),
+            Le => bool(a <= b),
+            Gt => bool(a > b),
+            Ge => bool(a >= b),
+            Add => a + b,
+            Sub => a - b,
+            Mul => a * b,
+            Div if b == 0.0 => return Err(SimpleError(tr!("MS0003-division"))),
+            Div => a / b,
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Op::*;
+        let s = match self {
+            Or => " OR ",
+            And => " AND ",
+            Eq => "==",
+            Ne => "<>",
+            Lt => "<",
+            Le => "<=",
+            Gt => ">",
+            Ge => ">=",
+            Add => "+",
+            Sub => "-",
+            Mul => "*",
+            Div => "/",
+        };
+        s.fmt(f)
+    }
+}
+
+/// Built-in functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Func {
+    /// Negation `-`
+    Neg,
+    /// Logical `NOT`
+    Not,
+    /// `ABS`
+    Abs,
+    /// `SQRT`
+    Sqrt,
+    /// `SIN` of degrees
+    Sin,
+    /// `COS` of degrees
+    Cos,
+    /// `TAN` of degrees
+    Tan,
+}
+
+impl Func {
+    fn apply(self, a: f64) -> Result<f64, SimpleError> {
+        use Func::*;
+        Ok(match self {
+            Neg => -a,
+            Not => (a == 0.0) as u8 as f64,
+            Abs => a.abs(),
+            Sqrt if a < 0.0 => return Err(SimpleError(tr!("MS0003-root", value = a))),
+            Sqrt => a.sqrt(),
+            // Angles are in degrees
+            Sin => a.to_radians().sin(),
+            Cos => a.to_radians().cos(),
+            Tan => a.to_radians().tan(),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        use Func::*;
+        match self {
+            Neg => "-",
+            Not => "NOT ",
+            Abs => "ABS",
+            Sqrt => "SQRT",
+            Sin => "SIN",
+            Cos => "COS",
+            Tan => "TAN",
+        }
+    }
+}
+
+/// Arithmetic expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// Numeric literal
+    Num(f64),
+    /// R parameter value
+    Param(u8),
+    /// Local variable or subprogram parameter
+    Var(String),
+    /// Function of one argument
+    Unary(Func, Box<Expr>),
+    /// Operator with its left and right operands
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluate with values from `scope`
+    pub fn eval(&self, scope: &impl Scope) -> Result<f64, SimpleError> {
+        match self {
+            Expr::Num(v) => Ok(*v),
+            Expr::Param(n) => Ok(scope.param(*n)),
+            Expr::Var(name) => scope.var(name),
+            Expr::Unary(f, a) => f.apply(a.eval(scope)?),
+            Expr::Binary(op, a, b) => op.apply(a.eval(scope)?, b.eval(scope)?),
+        }
+    }
+
+    /// Check if the value depends on no parameters or variables
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Expr::Num(_) => true,
+            Expr::Param(_) | Expr::Var(_) => false,
+            Expr::Unary(_, a) => a.is_constant(),
+            Expr::Binary(_, a, b) => a.is_constant() && b.is_constant(),
+        }
+    }
+
+    /// Check if the value depends on local variables
+    pub fn has_variables(&self) -> bool {
+        match self {
+            Expr::Num(_) | Expr::Param(_) => false,
+            Expr::Var(_) => true,
+            Expr::Unary(_, a) => a.has_variables(),
+            Expr::Binary(_, a, b) => a.has_variables() || b.has_variables(),
+        }
+    }
+
+    /// Parse from `nom`
+    pub fn parse(input: &str) -> IResult<&str, Expr> {
+        binary(1)(input)
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(op, _, _) => op.precedence(),
+            _ => u8::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(v) => v.fmt(f),
+            Expr::Param(n) => write!(f, "R{n}"),
+            Expr::Var(name) => name.fmt(f),
+            Expr::Unary(func @ (Func::Neg | Func::Not), a) if a.precedence() != u8::MAX => {
+                write!(f, "{}({a})", func.name())
+            }
+            Expr::Unary(func @ (Func::Neg | Func::Not), a) => write!(f, "{}{a}", func.name()),
+            Expr::Unary(func, a) => write!(f, "{}({a})", func.name()),
+            Expr::Binary(op, a, b) => {
+                let p = op.precedence();
+                if a.precedence() < p {
+                    write!(f, "({a})")?;
+                } else {
+                    a.fmt(f)?;
+                }
+                op.fmt(f)?;
+                // Operators are left associative
+                if b.precedence() <= p {
+                    write!(f, "({b})")
+                } else {
+                    b.fmt(f)
+                }
+            }
+        }
+    }
+}
+
+/// Name of a label, variable or subprogram: letters, digits and underscores,
+/// at least two characters
+pub fn identifier(s: &str) -> IResult<&str, String> {
+    map(
+        verify(take_while1(is_name_char), |id: &str| {
+            id.len() >= 2 && !id.starts_with(|c: char| c.is_ascii_digit())
+        }),
+        String::from,
+    )(s)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Keyword not followed by other name characters
+fn keyword<'a>(k: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag(k), not(satisfy(is_name_char)))
+}
+
+/// Binary operator of the precedence `level`, surrounded by optional spaces
+fn operator(level: u8) -> impl Fn(&str) -> IResult<&str, Op> {
+    move |input| {
+        use Op::*;
+        let (input, _) = space0(input)?;
+        let (input, op) = match level {
+            1 => value(Or, keyword("OR"))(input),
+            2 => value(And, keyword("AND"))(input),
+            3 => alt((
+                value(Eq, tag("==")),
+                value(Ne, tag("<>")),
+                value(Le, tag("<=")),
+                value(Ge, tag(">=")),
+                value(Lt, tag("<")),
+                value(Gt, tag(">")),
+            ))(input),
+            4 => alt((value(Add, char('+')), value(Sub, char('-'))))(input),
+            _ => alt((value(Mul, char('*')), value(Div, char('/'))))(input),
+        }?;
+        let (input, _) = space0(input)?;
+        Ok((input, op))
+    }
+}
+
+fn binary(level: u8) -> impl Fn(&str) -> IResult<&str, Expr> {
+    move |input| {
+        let operand = |i| {
+            if level >= 5 {
+                unary(i)
+            } else {
+                binary(level + 1)(i)
+            }
+        };
+        let (input, first) = operand(input)?;
+        let (input, rest) = many0(pair(operator(level), operand))(input)?;
+        let expr = rest.into_iter().fold(first, |a, (op, b)| {
+            Expr::Binary(op, Box::new(a), Box::new(b))
+        });
+        Ok((input, expr))
+    }
+}
+
+fn unary(input: &str) -> IResult<&str, Expr> {
+    let func = |name, func| {
+        map(
+            preceded(
+                pair(tag(name), space0),
+                delimited(char('('), delimited(space0, Expr::parse, space0), char(')')),
+            ),
+            move |e| Expr::Unary(func, Box::new(e)),
+        )
+    };
+
+    alt((
+        map(preceded(pair(char('-'), space0), unary), |e| {
+            Expr::Unary(Func::Neg, Box::new(e))
+        }),
+        map(preceded(pair(keyword("NOT"), space0), unary), |e| {
+            Expr::Unary(Func::Not, Box::new(e))
+        }),
+        func("ABS", Func::Abs),
+        func("SQRT", Func::Sqrt),
+        func("SIN", Func::Sin),
+        func("COS", Func::Cos),
+        func("TAN", Func::Tan),
+        map(preceded(char('R'), u8), Expr::Param),
+        map(identifier, Expr::Var),
+        map(double, Expr::Num),
+        delimited(
+            pair(char('('), space0),
+            Expr::parse,
+            pair(space0, char(')')),
+        ),
+    ))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expr;
+
+    fn eval(s: &str) -> f64 {
+        let (rest, e) = Expr::parse(s).unwrap();
+        assert_eq!(rest, "");
+        e.eval(&|n| n as f64).unwrap()
+    }
+
+    fn roundtrip(s: &str) -> String {
+        Expr::parse(s).unwrap().1.to_string()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("1+2*3"), 7.0);
+        assert_eq!(eval("(1+2)*3"), 9.0);
+        assert_eq!(eval("10-4-3"), 3.0);
+        assert_eq!(eval("-R2 + 1.5"), -0.5);
+        assert_eq!(eval("R3/R2"), 1.5);
+        assert_eq!(eval("SQRT(16)"), 4.0);
+        assert!(Expr::parse("1/0").unwrap().1.eval(&|_| 0.0).is_err());
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(eval("R1<5"), 1.0);
+        assert_eq!(eval("R1 >= 5"), 0.0);
+        assert_eq!(eval("R1==1 AND R2<>1"), 1.0);
+        assert_eq!(eval("R1>1 OR NOT R0"), 1.0);
+        assert_eq!(eval("NOT(R0)"), 1.0);
+    }
+
+    #[test]
+    fn variables() {
+        let (rest, e) = Expr::parse("DEPTH*2 ORIGIN").unwrap();
+        assert_eq!(rest, " ORIGIN");
+        assert_eq!(e.to_string(), "DEPTH*2");
+        assert!(e.eval(&|_| 0.0).is_err());
+        assert_eq!(Expr::parse("NOTE").unwrap().1, Expr::Var("NOTE".into()));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(roundtrip("R1+1"), "R1+1");
+        assert_eq!(roundtrip("(R1+1)*2"), "(R1+1)*2");
+        assert_eq!(roundtrip("R1-(R2-R3)"), "R1-(R2-R3)");
+        assert_eq!(roundtrip("-(R1+1)"), "-(R1+1)");
+        assert_eq!(roundtrip("R1<5 AND R2>=1.5"), "R1<5 AND R2>=1.5");
+    }
+}
@@ -1,38 +1,115 @@
 //! G-code file parser
 
-use super::parser::Line;
-use crate::errors::{LineError, SimpleError};
+use super::{
+    parser::{Line, SectionType},
+    syntax::{self, Edit, SourceLine},
+};
+use crate::{
+    diagnostic::Rule,
+    errors::{LineError, SimpleError},
+};
 use std::{
     fmt,
     fs::File,
     io::{BufRead, BufReader},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 /// Parsed G-Code file
+#[derive(Debug, Clone)]
 pub struct GCodeFile {
+    path: PathBuf,
     code: Vec<Line>,
+    /// Source text of every line
+    source: Vec<SourceLine>,
 }
 
 impl GCodeFile {
     /// Load file from disk
     pub fn load(path: impl AsRef<Path>) -> Result<Self, LineError> {
-        let fd =
-            File::open(path).map_err(|e| SimpleError(format!("Can't open file: {e}")).no_line())?;
-        let fd = BufReader::new(fd);
+        let path = path.as_ref();
+        let fd = File::open(path).map_err(|e| {
+            SimpleError(format!("Can't open file: {e}"))
+                .no_line()
+                .in_file(path)
+        })?;
+        Self::from_reader(path, BufReader::new(fd))
+    }
 
-        let code: Result<_, LineError> = fd
-            .lines()
-            .enumerate()
-            .map(|(no, line)| {
-                let no = no as u64 + 1;
-                line.map_err(|e| SimpleError(format!("I/O error {e}")).at_line(no))
-                    .and_then(|line| Line::parse(&line).map_err(|e| e.at_line(no)))
-            })
-            .collect();
-        let code = code?;
+    /// Read and parse the whole input like standard input, naming it `path`
+    pub fn from_reader(
+        path: impl AsRef<Path>,
+        mut reader: impl BufRead,
+    ) -> Result<Self, LineError> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(|e| {
+            SimpleError(format!("Can't read file: {e}"))
+                .no_line()
+                .in_file(path)
+        })?;
+        Self::parse(path, &text)
+    }
+
+    /// Parse file text as if it was loaded from `path`
+    pub fn parse(path: impl AsRef<Path>, text: &str) -> Result<Self, LineError> {
+        let path = path.as_ref();
+        let mut code = Vec::new();
+        let mut source = Vec::new();
+        // Inside an archive section which is not a program
+        let mut data = false;
+        let mut start = 0;
+        for (no, full) in (1..).zip(text.split_inclusive('\n')) {
+            let line = full.strip_suffix('\n').unwrap_or(full);
+            // Archives are often written with DOS line ends
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let mut words = Vec::new();
+            if data && !line.starts_with('%') {
+                code.push(Line::Data(line.into()));
+            } else {
+                let parsed;
+                (parsed, words) = Line::parse_spanned(line)
+                    .map_err(|e| e.at_line(no).in_file(path).with_rule(Rule::SyntaxError))?;
+                data = matches!(parsed, Line::Section(_, SectionType::Other(_)));
+                code.push(parsed);
+            }
+            source.push(SourceLine {
+                start,
+                text: line.into(),
+                end: full[line.len()..].into(),
+                words,
+            });
+            start += full.len();
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            code,
+            source,
+        })
+    }
+
+    /// Path the file was loaded from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Source text of a line, numbered from 1
+    pub fn source_line(&self, line: u64) -> Option<&SourceLine> {
+        self.source.get(usize::try_from(line).ok()?.checked_sub(1)?)
+    }
+
+    /// Source text exactly as loaded
+    pub fn text(&self) -> String {
+        self.source
+            .iter()
+            .map(|l| format!("{}{}", l.text, l.end))
+            .collect()
+    }
 
-        Ok(Self { code })
+    /// Source text with the edits applied, the rest as loaded
+    pub fn edit(&self, edits: Vec<Edit>) -> Result<String, SimpleError> {
+        syntax::apply(&self.text(), edits)
     }
 
     /// Iterate over file contents
@@ -52,7 +129,7 @@ impl GCodeFile {
     }
 
     /// Make printable version of code
-    pub fn printable(&self) -> Printable {
+    pub fn printable(&self) -> Printable<'_> {
         Printable(self)
     }
 }
@@ -0,0 +1,369 @@
+//! Canonical formatting of G-code files
+
+use super::{
+    file::GCodeFile,
+    parser::Line,
+    syntax::Edit,
+    words::{Control, GWord, Jump, MWord, Target, Word, Words},
+};
+use crate::errors::{LineError, SimpleError};
+use std::collections::{HashMap, HashSet};
+
+/// Formatter settings
+#[derive(Debug, Default, Clone)]
+pub struct FormatOptions {
+    /// Number the blocks of every program in steps of this
+    pub renumber: Option<u32>,
+    /// Remove G, F and S words repeating the modal state
+    pub remove_modal: bool,
+}
+
+impl GCodeFile {
+    /// Source text in canonical form
+    ///
+    /// Lines already in canonical form and the line ends are kept as loaded.
+    pub fn format(&self, opts: &FormatOptions) -> Result<String, LineError> {
+        let mut edits = Vec::new();
+        for ((no, _), text) in self.code().zip(format_lines(self.code(), opts)?) {
+            let source = self.source_line(no).expect("Bug: line without source");
+            match text {
+                Some(text) if text == source.text => (),
+                Some(text) => edits.push(Edit::new(source.span(), text)),
+                None => edits.push(Edit::new(source.full_span(), "")),
+            }
+        }
+        self.edit(edits).map_err(SimpleError::no_line)
+    }
+}
+
+/// Canonical text of every line, `None` for removed lines
+fn format_lines<'t>(
+    code: impl Iterator<Item = (u64, &'t Line)>,
+    opts: &FormatOptions,
+) -> Result<Vec<Option<String>>, LineError> {
+    let mut lines = code.map(|(no, l)| (no, l.clone())).collect::<Vec<_>>();
+    let mut start = 0;
+    for end in 1..=lines.len() {
+        let header = lines.get(end).is_some_and(|(_, l)| {
+            matches!(
+                l,
+                Line::MainProgram(_) | Line::SubProgram(_) | Line::Section(..)
+            )
+        });
+        if header || end == lines.len() {
+            format_program(&mut lines[start..end], opts)?;
+            start = end;
+        }
+    }
+
+    let mut text = Vec::new();
+    let mut depth = 0;
+    for (_, line) in lines {
+        let indent = match &line {
+            // Blocks which were only redundant words disappear
+            Line::Code(w) if w.0.is_empty() => {
+                text.push(None);
+                continue;
+            }
+            Line::Code(w) => {
+                let (before, after) = nesting(w);
+                depth -= before.min(depth);
+                let indent = "  ".repeat(depth);
+                depth += after;
+                indent
+            }
+            Line::MainProgram(_) | Line::SubProgram(_) | Line::Section(..) => {
+                depth = 0;
+                String::new()
+            }
+            // Blank and data lines stay unindented inside blocks
+            _ => String::new(),
+        };
+        text.push(Some(format!("{indent}{line}")));
+    }
+    Ok(text)
+}
+
+/// Levels a block closes before it and opens after it
+fn nesting(words: &Words) -> (usize, usize) {
+    let mut levels = (0, 0);
+    for w in &words.0 {
+        match w {
+            Word::Control(Control::If(_) | Control::While(_) | Control::For(..)) => levels.1 += 1,
+            Word::Control(Control::Else) => levels = (levels.0 + 1, levels.1 + 1),
+            Word::Control(Control::EndIf | Control::EndWhile | Control::EndFor) => {
+                if levels.1 > 0 {
+                    levels.1 -= 1;
+                } else {
+                    levels.0 += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+    levels
+}
+
+/// Format the lines of one program, jumps don't leave it
+fn format_program(lines: &mut [(u64, Line)], opts: &FormatOptions) -> Result<(), LineError> {
+    let targets = blocks(lines)
+        .flat_map(|(_, w)| &w.0)
+        .filter_map(|w| match w {
+            Word::Goto(Jump {
+                target: Target::Number(n),
+                ..
+            }) => Some(*n),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let mut modal = Modal::default();
+    for (_, words) in blocks_mut(lines) {
+        reorder(words);
+        if opts.remove_modal {
+            modal.simplify(words, &targets);
+        }
+    }
+
+    match opts.renumber {
+        Some(step) => renumber(lines, step),
+        None => Ok(()),
+    }
+}
+
+fn blocks(lines: &[(u64, Line)]) -> impl Iterator<Item = (u64, &Words)> {
+    lines.iter().filter_map(|(no, l)| match l {
+        Line::Code(w) => Some((*no, w)),
+        _ => None,
+    })
+}
+
+fn blocks_mut(lines: &mut [(u64, Line)]) -> impl Iterator<Item = (u64, &mut Words)> {
+    lines.iter_mut().filter_map(|(no, l)| match l {
+        Line::Code(w) => Some((*no, w)),
+        _ => None,
+    })
+}
+
+/// Position of the word in a canonical block
+fn rank(word: &Word) -> u8 {
+    use Word::*;
+    match word {
+        N(_) => 0,
+        Label(_) => 1,
+        Proc(..) | Def(..) | R(..) | Assign(..) => 2,
+        G(_) => 3,
+        X(_) | Computed('X', _) => 4,
+        Y(_) | Computed('Y', _) => 5,
+        Z(_) | Computed('Z', _) => 6,
+        I(_) | Computed('I', _) => 7,
+        J(_) | Computed('J', _) => 8,
+        F(_) | Computed('F', _) => 9,
+        S(_) | Computed('S', _) => 10,
+        D(_) | Computed('D', _) => 11,
+        M(_) => 12,
+        L(_) | P(_) | Call(..) | Computed(..) => 13,
+        Goto(_) | Control(_) => 14,
+        Comment(_) => 15,
+        LineComment(_) => 16,
+    }
+}
+
+/// Sort the words of a block into canonical order
+fn reorder(words: &mut Words) {
+    // Parameters are assigned from left to right, so a value computed
+    // before an assignment must stay there
+    let assigned = words
+        .0
+        .iter()
+        .rposition(|w| matches!(w, Word::R(..) | Word::Assign(..)));
+    let computed = words.0.iter().position(|w| matches!(w, Word::Computed(..)));
+    if matches!((computed, assigned), (Some(c), Some(a)) if c < a) {
+        return;
+    }
+    words.0.sort_by_key(rank);
+}
+
+/// Modal state known from the previous blocks
+#[derive(Debug, Default)]
+struct Modal {
+    motion: Option<GWord>,
+    distance: Option<GWord>,
+    feed: Option<f64>,
+    speed: Option<u16>,
+}
+
+impl Modal {
+    /// Remove words repeating the modal state and track the state
+    fn simplify(&mut self, words: &mut Words, targets: &HashSet<u32>) {
+        use Word::*;
+        // Jumps may arrive here with any state
+        let target = words
+            .0
+            .iter()
+            .any(|w| matches!(w, Label(_)) || matches!(w, N(n) if targets.contains(n)));
+        if target {
+            *self = Self::default();
+        }
+        // F and S of a dwell are no feed and speed
+        if words.0.contains(&G(GWord::G4)) {
+            return;
+        }
+
+        let moves = words.0.iter().any(|w| {
+            matches!(
+                w,
+                X(_) | Y(_) | Z(_) | I(_) | J(_) | Computed('X' | 'Y' | 'Z' | 'I' | 'J', _)
+            )
+        });
+        // The machine checks spindle and tool commands against a new movement
+        let plain = !words.0.iter().any(|w| matches!(w, M(_) | D(_)));
+        words.0.retain(|w| match w {
+            G(g @ (GWord::G0 | GWord::G1 | GWord::G2 | GWord::G3)) => {
+                let keep = !(moves && plain && self.motion.as_ref() == Some(g));
+                self.motion = Some(g.clone());
+                keep
+            }
+            G(g @ (GWord::G90 | GWord::G91)) => {
+                self.distance.replace(g.clone()).as_ref() != Some(g)
+            }
+            F(f) => self.feed.replace(*f) != Some(*f),
+            S(s) => self.speed.replace(*s) != Some(*s),
+            _ => true,
+        });
+
+        for w in &words.0 {
+            match w {
+                Computed('F', _) => self.feed = None,
+                Computed('S', _) => self.speed = None,
+                M(MWord::M5) => self.speed = None,
+                // Tool change, subprograms and control flow leave anything behind
+                M(MWord::M6) | D(_) | Computed('D', _) | L(_) | Call(..) | Goto(_) | Control(_) => {
+                    *self = Self::default()
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Number every block with executable code, updating the jumps
+fn renumber(lines: &mut [(u64, Line)], step: u32) -> Result<(), LineError> {
+    // Old number to the new one, `None` if the old one is ambiguous
+    let mut numbers = HashMap::<u32, Option<u32>>::new();
+    let mut next = Some(step);
+    for (no, words) in blocks_mut(lines) {
+        let numbered = matches!(words.0.first(), Some(Word::N(_)));
+        if !numbered && !words.0.iter().any(Word::is_executable) {
+            continue;
+        }
+        let new =
+            next.ok_or_else(|| SimpleError("Too many blocks to renumber".into()).at_line(no))?;
+        next = new.checked_add(step);
+        match words.0.first_mut() {
+            Some(Word::N(n)) => {
+                numbers
+                    .entry(*n)
+                    .and_modify(|e| *e = None)
+                    .or_insert(Some(new));
+                *n = new;
+            }
+            _ => words.0.insert(0, Word::N(new)),
+        }
+    }
+
+    for (no, words) in blocks_mut(lines) {
+        for w in &mut words.0 {
+            if let Word::Goto(Jump {
+                target: Target::Number(n),
+                ..
+            }) = w
+            {
+                match numbers.get(n) {
+                    Some(Some(new)) => *n = *new,
+                    Some(None) => {
+                        return Err(
+                            SimpleError(format!("Jump target N{n} is not unique")).at_line(no)
+                        )
+                    }
+                    // Undefined targets are reported when loading the program
+                    None => (),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_lines, FormatOptions};
+    use crate::gcode::Line;
+
+    fn format(text: &str, opts: &FormatOptions) -> String {
+        let lines = text
+            .lines()
+            .map(|l| Line::parse(l).unwrap())
+            .collect::<Vec<_>>();
+        format_lines((1..).zip(&lines), opts)
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .map(|l| l + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn word_order() {
+        let opts = FormatOptions::default();
+        assert_eq!(
+            format("M3 S1000 ;start\nX1 G1 F200 Y2.5 N10\nX=R1 R1=2\n", &opts),
+            "S1000 M3 ;start\nN10 G1 X1.000 Y2.500 F200\nX=R1 R1=2\n"
+        );
+        assert_eq!(
+            format("WHILE R1<2\nIF R1==1\nX1\nELSE\nX2\nENDIF\nR1=R1+1\nENDWHILE\nM2\n", &opts),
+            "WHILE R1<2\n  IF R1==1\n    X1.000\n  ELSE\n    X2.000\n  ENDIF\n  R1=R1+1\nENDWHILE\nM2\n"
+        );
+        assert_eq!(
+            format(
+                "IF R1==1\nG0 X1\n\nG0 X2\nWHILE R1<2\n\nENDWHILE\nENDIF\n",
+                &opts
+            ),
+            "IF R1==1\n  G0 X1.000\n\n  G0 X2.000\n  WHILE R1<2\n\n  ENDWHILE\nENDIF\n"
+        );
+    }
+
+    #[test]
+    fn modal_words() {
+        let opts = FormatOptions {
+            remove_modal: true,
+            ..FormatOptions::default()
+        };
+        let text = "%MPF1\nG90 G1 X1 F100\nG90 G1 Y1 F100\nG1 M5\nF100\nLOOP: G1 X2\nGOTOB LOOP\n";
+        assert_eq!(
+            format(text, &opts),
+            "%MPF1\nG90 G1 X1.000 F100\nY1.000\nG1 M5\nLOOP: G1 X2.000\nGOTOB LOOP\n"
+        );
+    }
+
+    #[test]
+    fn renumber() {
+        let opts = FormatOptions {
+            renumber: Some(10),
+            ..FormatOptions::default()
+        };
+        let text = "%MPF1\n; setup\nN5 G0 X0\nG1 X1\nN7 GOTOB N5\n%SPF2\nN100 M17\n";
+        assert_eq!(
+            format(text, &opts),
+            "%MPF1\n; setup\nN10 G0 X0.000\nN20 G1 X1.000\nN30 GOTOB N10\n%SPF2\nN10 M17\n"
+        );
+        assert!(format_lines(
+            (1..).zip(&[
+                Line::parse("N1 X1").unwrap(),
+                Line::parse("N1 GOTOB N1").unwrap()
+            ]),
+            &opts
+        )
+        .is_err());
+    }
+}
@@ -1,6 +1,13 @@
+//! G-code files, their syntax and words
+
+pub mod expr;
 mod file;
+mod format;
 mod parser;
+mod syntax;
 pub mod words;
 
 pub use self::file::GCodeFile;
-pub use self::parser::Line;
+pub use self::format::FormatOptions;
+pub use self::parser::{Line, SectionType};
+pub use self::syntax::{Edit, SourceLine, Span};
@@ -1,40 +1,105 @@
 //! G-Code parser
 
-use super::words::{GWord, MWord, Word, Words};
-use crate::{errors::SimpleError, types::Micrometer};
+use super::{
+    expr::{identifier, Expr},
+    syntax::Span,
+    words::{Control, Direction, GWord, Jump, MWord, Target, VarType, Word, Words},
+};
+use crate::{
+    errors::{LineError, SimpleError},
+    i18n::tr,
+    types::Micrometer,
+};
 use nom::{
     branch::alt,
-    bytes::complete::{is_a, is_not, tag},
-    character::complete::{char, u16, u32, u8},
-    combinator::{all_consuming, map, map_res, opt, value},
-    multi::many1,
-    sequence::{delimited, preceded, separated_pair},
+    bytes::complete::{is_a, is_not, tag, take_while1},
+    character::complete::{char, one_of, space0, space1, u16, u32, u8},
+    combinator::{all_consuming, consumed, map, map_res, opt, rest, value, verify},
+    multi::{many1, separated_list0, separated_list1},
+    number::complete::double,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     IResult,
 };
 use std::fmt;
 
+/// Parsed line of a G-code file
 #[derive(Debug, Clone)]
 pub enum Line {
     /// Empty line with no code
     Empty,
     /// Main program "%MPF" designator
-    MainProgram(u8),
+    MainProgram(u32),
     /// Sub program "%SPF" designator
-    SubProgram(u8),
+    SubProgram(u32),
+    /// Archive section `%_N_<NAME>_<TYPE>` designator
+    Section(String, SectionType),
+    /// Directory of the section, ";$PATH=/_N_SPF_DIR"
+    Path(String),
+    /// Raw line of an archive section which is not a program
+    Data(String),
     /// Code line
     Code(Words),
 }
 
+/// Type of an archive section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionType {
+    /// Main program, "MPF"
+    Main,
+    /// Subprogram, "SPF"
+    Sub,
+    /// Anything else like "INI" or "DEF", not simulated
+    Other(String),
+}
+
+impl fmt::Display for SectionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SectionType::Main => "MPF".fmt(f),
+            SectionType::Sub => "SPF".fmt(f),
+            SectionType::Other(s) => s.fmt(f),
+        }
+    }
+}
+
 impl Line {
     /// Parse program text line
-    pub fn parse(line: &str) -> Result<Line, SimpleError> {
-        parse_codes(line).map(|(_, l)| l).map_err(|e| {
-            use nom::Err::*;
-            SimpleError(match e {
-                Incomplete(_) => "Incomplete data".into(),
-                Error(e) | Failure(e) => format!("Invalid syntax at '{}'", e.input),
+    pub fn parse(line: &str) -> Result<Line, LineError> {
+        Self::parse_spanned(line).map(|(l, _)| l)
+    }
+
+    /// Parse program text line with the byte span of every code word
+    ///
+    /// Errors have the column where parsing failed, but no line number.
+    pub fn parse_spanned(line: &str) -> Result<(Line, Vec<Span>), LineError> {
+        parse_codes(line)
+            .map(|(_, (l, words))| {
+                // Words are slices of the line
+                let spans = words
+                    .iter()
+                    .map(|w| {
+                        let start = w.as_ptr() as usize - line.as_ptr() as usize;
+                        start..start + w.len()
+                    })
+                    .collect();
+                (l, spans)
+            })
+            .map_err(|e| {
+                use nom::Err::*;
+                match e {
+                    Incomplete(_) => SimpleError(tr!("MS0001-incomplete")).no_line(),
+                    Error(e) | Failure(e) => {
+                        let error = SimpleError(tr!("MS0001-invalid", rest = e.input));
+                        // The failing input is a slice of the line
+                        let offset =
+                            (e.input.as_ptr() as usize).wrapping_sub(line.as_ptr() as usize);
+                        match line.get(..offset) {
+                            Some(parsed) => error.no_line().at_column(parsed.chars().count() + 1),
+                            None => error.no_line(),
+                        }
+                    }
+                }
             })
-        })
     }
 }
 
@@ -45,12 +110,32 @@ impl fmt::Display for Line {
             Empty => Ok(()),
             MainProgram(x) => write!(f, "%MPF{x}"),
             SubProgram(x) => write!(f, "%SPF{x}"),
+            Section(name, ty) => write!(f, "%_N_{name}_{ty}"),
+            Path(p) => write!(f, ";$PATH={p}"),
+            Data(s) => s.fmt(f),
             Code(v) => v.fmt(f),
         }
     }
 }
 
-fn parse_codes(line: &str) -> IResult<&str, Line> {
+/// Parse line along with the text of every code word
+fn parse_codes(line: &str) -> IResult<&str, (Line, Vec<&str>)> {
+    let statements = (
+        map(terminated(identifier, char(':')), Word::Label),
+        map(jump, Word::Goto),
+        map(control, Word::Control),
+        proc,
+        def,
+        // Before the address words, `X1=2` is a variable
+        map(
+            separated_pair(
+                verify(identifier, |v: &str| !is_r_param(v)),
+                char('='),
+                Expr::parse,
+            ),
+            |(v, e)| Word::Assign(v, e),
+        ),
+    );
     let words = (
         map_res(preceded(char('G'), u8), |n| {
             GWord::from_number(n).map(Word::G)
@@ -65,40 +150,311 @@ fn parse_codes(line: &str) -> IResult<&str, Line> {
         map(preceded(char('J'), Micrometer::parse), Word::J),
         map(preceded(char('N'), u32), Word::N),
         map(preceded(char('S'), u16), Word::S),
-        map(preceded(char('F'), u16), Word::F),
-        map(preceded(char('L'), u8), Word::L),
+        map(
+            preceded(
+                char('F'),
+                verify(double, |f| (0.0..=u16::MAX as f64).contains(f)),
+            ),
+            Word::F,
+        ),
+        map(preceded(char('L'), u32), Word::L),
         map(preceded(char('P'), u16), Word::P),
         map(preceded(char('D'), u8), Word::D),
         map(
-            preceded(char('R'), separated_pair(u8, char('='), Micrometer::parse)),
+            preceded(char('R'), separated_pair(u8, char('='), Expr::parse)),
             |(a, b)| Word::R(a, b),
         ),
+        map(
+            separated_pair(one_of("XYZIJSFDP"), char('='), Expr::parse),
+            |(a, e)| Word::Computed(a, e),
+        ),
+        map(
+            pair(
+                identifier,
+                delimited(char('('), list(Expr::parse), char(')')),
+            ),
+            |(name, args)| Word::Call(name, Some(args)),
+        ),
+        map(verify(identifier, is_program_name), |name| {
+            Word::Call(name, None)
+        }),
         map(delimited(char('('), is_not(")"), opt(char(')'))), |s| {
             Word::Comment(String::from(s))
         }),
+        map(preceded(char(';'), rest), |s: &str| {
+            Word::LineComment(String::from(s))
+        }),
     );
 
-    all_consuming(alt((
-        map(delimited(tag("%MPF"), u8, spc), Line::MainProgram),
-        map(delimited(tag("%SPF"), u8, spc), Line::SubProgram),
-        map(many1(delimited(spc, alt(words), spc)), |c| {
-            Line::Code(Words(c))
+    let code = map(
+        many1(delimited(
+            spc,
+            consumed(alt((alt(statements), alt(words)))),
+            spc,
+        )),
+        |c| {
+            let (text, words) = c.into_iter().unzip();
+            (Line::Code(Words(words)), text)
+        },
+    );
+    let other = alt((
+        map(delimited(tag("%MPF"), u32, spc), Line::MainProgram),
+        map(delimited(tag("%SPF"), u32, spc), Line::SubProgram),
+        map(terminated(section, spc), |(name, ty)| {
+            Line::Section(name, ty)
+        }),
+        map(preceded(tag(";$PATH="), rest), |p: &str| {
+            Line::Path(p.trim_end().into())
         }),
-        value(Line::Empty, spc),
+    ));
+
+    all_consuming(alt((
+        map(other, |l| (l, Vec::new())),
+        code,
+        map(value(Line::Empty, spc), |l| (l, Vec::new())),
     )))(line)
 }
 
+/// `%_N_<NAME>_<TYPE>` header
+fn section(s: &str) -> IResult<&str, (String, SectionType)> {
+    map_res(
+        preceded(
+            tag("%_N_"),
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        ),
+        |id: &str| match id.rsplit_once('_') {
+            Some((name, ty)) if !name.is_empty() => Ok((
+                name.to_owned(),
+                match ty {
+                    "MPF" => SectionType::Main,
+                    "SPF" => SectionType::Sub,
+                    _ => SectionType::Other(ty.to_owned()),
+                },
+            )),
+            _ => Err(()),
+        },
+    )(s)
+}
+
+/// Subprogram called by bare name: starts with two letters or underscores,
+/// not a keyword
+fn is_program_name(name: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "IF", "ELSE", "ENDIF", "WHILE", "ENDWHILE", "FOR", "TO", "ENDFOR", "REPEAT", "GOTO",
+        "GOTOF", "GOTOB", "PROC", "DEF", "REAL", "INT", "BOOL", "AND", "OR", "NOT",
+    ];
+    name.chars()
+        .take(2)
+        .all(|c| c.is_ascii_alphabetic() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+fn is_r_param(name: &str) -> bool {
+    name.strip_prefix('R')
+        .is_some_and(|n| n.bytes().all(|c| c.is_ascii_digit()))
+}
+
+/// Comma separated list with optional spaces
+fn list<'a, T>(
+    item: impl FnMut(&'a str) -> IResult<&'a str, T>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<T>> {
+    delimited(
+        space0,
+        separated_list0(delimited(space0, char(','), space0), item),
+        space0,
+    )
+}
+
+fn var_type(s: &str) -> IResult<&str, VarType> {
+    alt((
+        value(VarType::Real, tag("REAL")),
+        value(VarType::Int, tag("INT")),
+        value(VarType::Bool, tag("BOOL")),
+    ))(s)
+}
+
+/// `PROC NAME(TYPE PARAM, ...)`
+fn proc(s: &str) -> IResult<&str, Word> {
+    map(
+        pair(
+            preceded(pair(tag("PROC"), space1), identifier),
+            opt(delimited(
+                char('('),
+                list(separated_pair(var_type, space1, identifier)),
+                char(')'),
+            )),
+        ),
+        |(name, params)| Word::Proc(name, params.unwrap_or_default()),
+    )(s)
+}
+
+/// `DEF TYPE NAME[=VALUE], ...`
+fn def(s: &str) -> IResult<&str, Word> {
+    let var = pair(
+        identifier,
+        opt(preceded(delimited(space0, char('='), space0), Expr::parse)),
+    );
+    map(
+        pair(
+            preceded(pair(tag("DEF"), space1), var_type),
+            preceded(
+                space1,
+                separated_list1(delimited(space0, char(','), space0), var),
+            ),
+        ),
+        |(ty, vars)| Word::Def(ty, vars),
+    )(s)
+}
+
+/// `[IF condition] GOTOF|GOTOB|GOTO target`
+fn jump(s: &str) -> IResult<&str, Jump> {
+    let direction = alt((
+        value(Direction::Forward, tag("GOTOF")),
+        value(Direction::Backward, tag("GOTOB")),
+        value(Direction::Any, tag("GOTO")),
+    ));
+    let target = map(identifier, |id| {
+        match id.strip_prefix('N').map(str::parse) {
+            Some(Ok(n)) => Target::Number(n),
+            _ => Target::Label(id),
+        }
+    });
+    map(
+        tuple((
+            opt(delimited(pair(tag("IF"), space0), Expr::parse, space1)),
+            direction,
+            preceded(space1, target),
+        )),
+        |(condition, direction, target)| Jump {
+            condition,
+            direction,
+            target,
+        },
+    )(s)
+}
+
+/// Structured control flow statement
+fn control(s: &str) -> IResult<&str, Control> {
+    let keyword = |k| pair(tag(k), space0);
+    alt((
+        map(preceded(keyword("IF"), Expr::parse), Control::If),
+        value(Control::Else, tag("ELSE")),
+        value(Control::EndIf, tag("ENDIF")),
+        map(preceded(keyword("WHILE"), Expr::parse), Control::While),
+        value(Control::EndWhile, tag("ENDWHILE")),
+        map(
+            tuple((
+                preceded(pair(tag("FOR"), space1), preceded(char('R'), u8)),
+                preceded(char('='), Expr::parse),
+                preceded(tuple((space1, tag("TO"), space1)), Expr::parse),
+            )),
+            |(n, from, to)| Control::For(n, from, to),
+        ),
+        value(Control::EndFor, tag("ENDFOR")),
+        map(
+            pair(
+                preceded(pair(tag("REPEAT"), space1), identifier),
+                opt(preceded(pair(space1, tag("P=")), Expr::parse)),
+            ),
+            |(label, count)| Control::Repeat(label, count),
+        ),
+    ))(s)
+}
+
 fn spc(s: &str) -> IResult<&str, &str> {
     map(opt(is_a(" ")), |x| x.unwrap_or(""))(s)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Line;
+    use super::{Line, Word};
 
     #[test]
     fn parse_g() {
         let s = "G0 G1 G2KG3 X15 Y60";
         eprintln!("{:?}", Line::parse(s));
     }
+
+    #[test]
+    fn error_column() {
+        let e = Line::parse("G0 X1 Q?").unwrap_err();
+        assert_eq!(e.column(), Some(7));
+        assert_eq!(e.line(), None);
+    }
+
+    #[test]
+    fn parse_jumps() {
+        for s in [
+            "N10 LOOP: G1 X=R1*2 R1=R1+1",
+            "IF R1<5 GOTOB LOOP",
+            "IF R1 >= 2 AND R2<>0 GOTOF N100",
+            "GOTO END_1",
+        ] {
+            let line = Line::parse(s).unwrap();
+            assert_eq!(line.to_string().replace(' ', ""), s.replace(' ', ""));
+        }
+        assert!(Line::parse("GOTOF").is_err());
+        assert!(Line::parse("M30").is_err());
+    }
+
+    #[test]
+    fn parse_control() {
+        for s in [
+            "N10 IF R1>5",
+            "ELSE",
+            "ENDIF",
+            "WHILE R2<>0 AND R3<1",
+            "ENDWHILE",
+            "FOR R1=1 TO R2*2",
+            "ENDFOR",
+            "REPEAT POCKET P=3",
+            "REPEAT POCKET",
+        ] {
+            assert_eq!(Line::parse(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn parse_headers() {
+        for s in [
+            "%MPF1000",
+            "%SPF1234",
+            "%_N_POCKET_SPF",
+            "%_N_MY_PART_MPF",
+            "%_N_COMPLETE_TEA_INI",
+            ";$PATH=/_N_SPF_DIR",
+        ] {
+            assert_eq!(Line::parse(s).unwrap().to_string(), s);
+        }
+        assert!(Line::parse("%_N_SPF").is_err());
+    }
+
+    #[test]
+    fn parse_procedures() {
+        for s in [
+            "PROC POCKET(REAL DEPTH, INT COUNT, BOOL FINISH)",
+            "PROC SIMPLE()",
+            "DEF REAL X1, X2=DEPTH/2",
+            "X1=X1+R1 G1 X=X1",
+            "POCKET(2.5, R1*2, 1)",
+            "N10 DONE()",
+            "CONTOUR1 P2",
+            "L1234 ;finishing pass",
+        ] {
+            assert_eq!(Line::parse(s).unwrap().to_string(), s);
+        }
+        assert!(matches!(
+            Line::parse("R1=5").unwrap(),
+            Line::Code(w) if matches!(w.0[..], [Word::R(1, _)])
+        ));
+    }
+
+    #[test]
+    fn word_spans() {
+        let s = "N10  G1 X1.5 (rough) ;cut";
+        let (_, spans) = Line::parse_spanned(s).unwrap();
+        let words = spans.into_iter().map(|w| &s[w]).collect::<Vec<_>>();
+        assert_eq!(words, ["N10", "G1", "X1.5", "(rough)", ";cut"]);
+        assert!(Line::parse_spanned("%MPF1").unwrap().1.is_empty());
+    }
 }
@@ -0,0 +1,101 @@
+//! Source text as written, beneath the parsed lines
+//!
+//! Every parsed line keeps its exact text, line end and the byte spans of its
+//! words, so tools can edit the source without rewriting unrelated parts.
+
+use crate::errors::SimpleError;
+use std::ops::Range;
+
+/// Byte range in the source text
+pub type Span = Range<usize>;
+
+/// Source line with the spans of its words
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    /// Byte offset of the line in the file
+    pub start: usize,
+    /// Line text without the line end
+    pub text: String,
+    /// Line end as written, `\n`, `\r\n` or nothing at the end of file
+    pub end: String,
+    /// Spans of the code words within the line, in the order of the words
+    pub words: Vec<Span>,
+}
+
+impl SourceLine {
+    /// Line text in the file, without the line end
+    pub fn span(&self) -> Span {
+        self.start..self.start + self.text.len()
+    }
+
+    /// Line text in the file with the line end
+    pub fn full_span(&self) -> Span {
+        self.start..self.start + self.text.len() + self.end.len()
+    }
+
+    /// Word number `idx` in the file
+    pub fn word_span(&self, idx: usize) -> Option<Span> {
+        self.words
+            .get(idx)
+            .map(|w| self.start + w.start..self.start + w.end)
+    }
+}
+
+/// Replacement of a range of the source text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    /// Replaced bytes
+    pub span: Span,
+    /// Replacement text
+    pub text: String,
+}
+
+impl Edit {
+    /// Replace the bytes of `span` with `text`
+    pub fn new(span: Span, text: impl Into<String>) -> Self {
+        Self {
+            span,
+            text: text.into(),
+        }
+    }
+}
+
+/// Apply edits to the source text, the edits must not overlap
+pub fn apply(source: &str, mut edits: Vec<Edit>) -> Result<String, SimpleError> {
+    edits.sort_by_key(|e| (e.span.start, e.span.end));
+    let mut text = String::with_capacity(source.len());
+    let mut pos = 0;
+    for edit in edits {
+        let Range { start, end } = edit.span;
+        if start < pos || end < start {
+            return Err(SimpleError(format!(
+                "Overlapping edits at byte {start} of the source"
+            )));
+        }
+        let kept = source.get(pos..start).zip(source.get(start..end));
+        let Some((kept, _)) = kept else {
+            return Err(SimpleError(format!(
+                "Edit at bytes {start}..{end} is outside of the source"
+            )));
+        };
+        text += kept;
+        text += &edit.text;
+        pos = end;
+    }
+    text += &source[pos..];
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Edit};
+
+    #[test]
+    fn edits() {
+        let source = "N10 G1 X1.5 ;cut\r\nM2\r\n";
+        let edits = vec![Edit::new(13..16, "done"), Edit::new(7..11, "X2")];
+        assert_eq!(apply(source, edits).unwrap(), "N10 G1 X2 ;done\r\nM2\r\n");
+        assert!(apply(source, vec![Edit::new(0..5, ""), Edit::new(4..6, "")]).is_err());
+        assert!(apply(source, vec![Edit::new(20..30, "")]).is_err());
+    }
+}
@@ -1,11 +1,12 @@
 //! G-Code words
 
-use crate::{errors::SimpleError, types::Micrometer};
+use super::expr::Expr;
+use crate::{errors::SimpleError, i18n::tr, types::Micrometer};
 use std::fmt;
 use strum::FromRepr;
 
 /// All supported code words
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Word {
     /// N line number
     N(u32),
@@ -17,8 +18,8 @@ pub enum Word {
     D(u8),
     /// S spindle speed
     S(u16),
-    /// F milling feed
-    F(u16),
+    /// F milling feed, or dwell time in seconds with G4
+    F(f64),
     /// I coordinate
     I(Micrometer),
     /// J coordinate
@@ -30,19 +31,133 @@ pub enum Word {
     /// Z coordinate
     Z(Micrometer),
     /// L subprogram call
-    L(u8),
+    L(u32),
     /// P subprogram counter
     P(u16),
-    /// R parameter
-    R(u8, Micrometer),
+    /// R parameter assignment
+    R(u8, Expr),
+    /// Address with computed value like `X=R1+2`
+    Computed(char, Expr),
+    /// Jump label like `LOOP:`
+    Label(String),
+    /// GOTOF, GOTOB or GOTO jump, possibly conditional
+    Goto(Jump),
+    /// Structured control flow statement
+    Control(Control),
+    /// `PROC NAME(REAL A, ...)` subprogram definition
+    Proc(String, Vec<(VarType, String)>),
+    /// `DEF REAL A, B=1` local variables
+    Def(VarType, Vec<(String, Option<Expr>)>),
+    /// Local variable assignment
+    Assign(String, Expr),
+    /// Subprogram call by name, arguments if given in parentheses
+    Call(String, Option<Vec<Expr>>),
     /// String comment
     Comment(String),
+    /// Comment till the end of line after ';'
+    LineComment(String),
 }
 
 impl Word {
     /// Check if the word is executable
     pub fn is_executable(&self) -> bool {
-        !matches!(self, Word::N(_) | Word::Comment(_))
+        !matches!(
+            self,
+            Word::N(_) | Word::Label(_) | Word::Proc(..) | Word::Comment(_) | Word::LineComment(_)
+        )
+    }
+
+    /// Literal word for an address with computed `value`
+    pub fn with_value(address: char, value: f64) -> Result<Self, SimpleError> {
+        let out_of_range = || {
+            SimpleError(tr!(
+                "MS0003-out-of-range",
+                value = value,
+                address = address
+            ))
+        };
+        let int = |max: f64| {
+            let v = value.round();
+            if (0.0..=max).contains(&v) {
+                Ok(v)
+            } else {
+                Err(out_of_range())
+            }
+        };
+        let decimal = |max: f64| {
+            if (0.0..=max).contains(&value) {
+                Ok(value)
+            } else {
+                Err(out_of_range())
+            }
+        };
+        let len = || Micrometer::try_from_mm(value).ok_or_else(out_of_range);
+        Ok(match address {
+            'X' => Word::X(len()?),
+            'Y' => Word::Y(len()?),
+            'Z' => Word::Z(len()?),
+            'I' => Word::I(len()?),
+            'J' => Word::J(len()?),
+            'S' => Word::S(int(u16::MAX as f64)? as u16),
+            'F' => Word::F(decimal(u16::MAX as f64)?),
+            'P' => Word::P(int(u16::MAX as f64)? as u16),
+            'D' => Word::D(int(u8::MAX as f64)? as u8),
+            _ => return Err(SimpleError(tr!("MS0003-not-computed", address = address))),
+        })
+    }
+}
+
+/// Jump search direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// GOTOF, towards the program end
+    Forward,
+    /// GOTOB, towards the program start
+    Backward,
+    /// GOTO, forward first, then backward
+    Any,
+}
+
+/// Jump destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// Label like `LOOP:`
+    Label(String),
+    /// Block number like `N100`
+    Number(u32),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Label(l) => l.fmt(f),
+            Target::Number(n) => write!(f, "N{n}"),
+        }
+    }
+}
+
+/// Jump inside the current program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Jump {
+    /// Jump is taken only if the condition is non-zero
+    pub condition: Option<Expr>,
+    /// Where to search for the target
+    pub direction: Direction,
+    /// Label or block number to jump to
+    pub target: Target,
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(c) = &self.condition {
+            write!(f, "IF {c} ")?;
+        }
+        let g = match self.direction {
+            Direction::Forward => "GOTOF",
+            Direction::Backward => "GOTOB",
+            Direction::Any => "GOTO",
+        };
+        write!(f, "{g} {}", self.target)
     }
 }
 
@@ -64,12 +179,127 @@ impl fmt::Display for Word {
             L(x) => write!(f, "L{x}"),
             P(x) => write!(f, "P{x}"),
             R(x, y) => write!(f, "R{x}={y}"),
+            Computed(a, e) => write!(f, "{a}={e}"),
+            Label(l) => write!(f, "{l}:"),
+            Goto(j) => j.fmt(f),
+            Control(c) => c.fmt(f),
+            Proc(name, params) => {
+                write!(f, "PROC {name}(")?;
+                for (i, (ty, p)) in params.iter().enumerate() {
+                    let c = if i == 0 { "" } else { ", " };
+                    write!(f, "{c}{ty} {p}")?;
+                }
+                write!(f, ")")
+            }
+            Def(ty, vars) => {
+                write!(f, "DEF {ty}")?;
+                for (i, (v, init)) in vars.iter().enumerate() {
+                    let c = if i == 0 { " " } else { ", " };
+                    write!(f, "{c}{v}")?;
+                    if let Some(e) = init {
+                        write!(f, "={e}")?;
+                    }
+                }
+                Ok(())
+            }
+            Assign(v, e) => write!(f, "{v}={e}"),
+            Call(name, None) => name.fmt(f),
+            Call(name, Some(args)) => {
+                write!(f, "{name}(")?;
+                for (i, a) in args.iter().enumerate() {
+                    let c = if i == 0 { "" } else { ", " };
+                    write!(f, "{c}{a}")?;
+                }
+                write!(f, ")")
+            }
             Comment(c) => write!(f, "({c})"),
+            LineComment(c) => write!(f, ";{c}"),
+        }
+    }
+}
+
+/// Type of a local variable or subprogram parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarType {
+    /// `REAL`, any finite number
+    Real,
+    /// `INT`, whole number
+    Int,
+    /// `BOOL`, 0 or 1
+    Bool,
+}
+
+impl VarType {
+    /// Check if `value` fits the type
+    pub fn check(self, value: f64) -> Result<f64, SimpleError> {
+        let ok = match self {
+            VarType::Real => value.is_finite(),
+            VarType::Int => value.fract() == 0.0,
+            VarType::Bool => value == 0.0 || value == 1.0,
+        };
+        if ok {
+            Ok(value)
+        } else {
+            Err(SimpleError(tr!(
+                "MS0003-not-type",
+                value = value,
+                ty = self
+            )))
+        }
+    }
+}
+
+impl fmt::Display for VarType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            VarType::Real => "REAL",
+            VarType::Int => "INT",
+            VarType::Bool => "BOOL",
+        };
+        s.fmt(f)
+    }
+}
+
+/// Structured control flow statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// `IF <condition>`
+    If(Expr),
+    /// `ELSE` of the innermost IF
+    Else,
+    /// `ENDIF`
+    EndIf,
+    /// `WHILE <condition>`
+    While(Expr),
+    /// `ENDWHILE`
+    EndWhile,
+    /// `FOR R<n>=<from> TO <to>`, counting up by one
+    For(u8, Expr, Expr),
+    /// `ENDFOR`
+    EndFor,
+    /// `REPEAT <label> P=<count>`, repeat the code from the label
+    Repeat(String, Option<Expr>),
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Control::*;
+        match self {
+            If(c) => write!(f, "IF {c}"),
+            Else => write!(f, "ELSE"),
+            EndIf => write!(f, "ENDIF"),
+            While(c) => write!(f, "WHILE {c}"),
+            EndWhile => write!(f, "ENDWHILE"),
+            For(n, from, to) => write!(f, "FOR R{n}={from} TO {to}"),
+            EndFor => write!(f, "ENDFOR"),
+            Repeat(l, Some(p)) => write!(f, "REPEAT {l} P={p}"),
+            Repeat(l, None) => write!(f, "REPEAT {l}"),
         }
     }
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Words of a block in the order written
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Words(pub Vec<Word>);
 
 impl fmt::Display for Words {
@@ -93,6 +323,8 @@ pub enum GWord {
     G2 = 2,
     /// Counter-clockwise circular feed
     G3 = 3,
+    /// Dwell time
+    G4 = 4,
     /// Use absolute coordinates
     G90 = 90,
     /// Use relative coordinates
@@ -102,7 +334,7 @@ pub enum GWord {
 impl GWord {
     /// Convert integer designator to G code number
     pub fn from_number(n: u8) -> Result<Self, SimpleError> {
-        GWord::from_repr(n as usize).ok_or_else(|| SimpleError(format!("Unknown G code 'G{n}'")))
+        GWord::from_repr(n as usize).ok_or_else(|| SimpleError(tr!("MS0001-unknown-g", n = n)))
     }
 }
 
@@ -136,7 +368,7 @@ pub enum MWord {
 impl MWord {
     /// Convert integer designator to M code number
     pub fn from_number(n: u8) -> Result<Self, SimpleError> {
-        MWord::from_repr(n as usize).ok_or_else(|| SimpleError(format!("Unknown M code 'M{n}'")))
+        MWord::from_repr(n as usize).ok_or_else(|| SimpleError(tr!("MS0001-unknown-m", n = n)))
     }
 }
 
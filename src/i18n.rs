@@ -0,0 +1,702 @@
+//! Message catalog with English and German texts
+//!
+//! Messages are keyed by their diagnostic code, with a suffix if the code
+//! has several messages, and have `{name}` placeholders filled in by `tr!`.
+//! Command descriptions are keyed by their G or M word.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Language of the messages
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    /// English
+    #[default]
+    En,
+    /// German
+    De,
+}
+
+impl Lang {
+    /// Language of the locale environment variables, if known
+    pub fn from_env() -> Option<Self> {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(|v| std::env::var(v).ok())
+            .find(|v| !v.is_empty())
+            .and_then(|v| v.parse().ok())
+    }
+}
+
+impl FromStr for Lang {
+    type Err = ();
+
+    /// Parse locale like `de_DE.UTF-8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lang = s.split(['_', '.', '@', '-']).next().unwrap_or_default();
+        match lang {
+            "en" | "C" | "POSIX" => Ok(Lang::En),
+            "de" => Ok(Lang::De),
+            _ => Err(()),
+        }
+    }
+}
+
+static LANG: AtomicU8 = AtomicU8::new(0);
+
+/// Choose the language of all messages
+pub fn set_lang(lang: Lang) {
+    LANG.store(lang as u8, Ordering::Relaxed);
+}
+
+/// Language of the messages, English unless chosen otherwise
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::De,
+        _ => Lang::En,
+    }
+}
+
+/// Catalog message in the chosen language with the placeholders filled in
+///
+/// Unknown keys are returned as they are.
+pub fn text(key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let Some((_, en, de)) = CATALOG.iter().find(|(k, _, _)| *k == key) else {
+        return key.to_owned();
+    };
+    let mut text = match lang() {
+        Lang::En => en.to_string(),
+        Lang::De => de.to_string(),
+    };
+    for (name, value) in args {
+        text = text.replace(&format!("{{{name}}}"), &value.to_string());
+    }
+    text
+}
+
+/// Catalog message like `tr!("MS0026-low", speed = 100)`
+macro_rules! tr {
+    ($key:expr $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::i18n::text($key, &[$((stringify!($name), &$value as &dyn std::fmt::Display)),*])
+    };
+}
+pub(crate) use tr;
+
+/// Display of an enum through the catalog, keyed by its strum name
+macro_rules! localized {
+    ($($ty:ty),+) => {
+        $(impl std::fmt::Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                let key: &'static str = self.into();
+                f.write_str(&$crate::i18n::text(key, &[]))
+            }
+        })+
+    };
+}
+pub(crate) use localized;
+
+/// Key, English and German text
+const CATALOG: &[(&str, &str, &str)] = &[
+    // Message frames
+    ("error", "Error", "Fehler"),
+    ("warning", "Warning", "Warnung"),
+    ("note", "Note", "Hinweis"),
+    ("at-line", "at line {line}", "in Zeile {line}"),
+    (
+        "at-line-column",
+        "at line {line}, column {column}",
+        "in Zeile {line}, Spalte {column}",
+    ),
+    ("of-file", "of '{file}'", "von '{file}'"),
+    ("in-file", "in '{file}'", "in '{file}'"),
+    ("main-program", "Main program", "Hauptprogramm"),
+    ("subprogram", "Subprogram", "Unterprogramm"),
+    (
+        "frame",
+        "in {program} called from line {line} of {caller}",
+        "in {program}, aufgerufen in Zeile {line} von {caller}",
+    ),
+    (
+        "frame-repeat",
+        "in {program} (repeat {run} of {runs}) called from line {line} of {caller}",
+        "in {program} (Wiederholung {run} von {runs}), aufgerufen in Zeile {line} von {caller}",
+    ),
+    (
+        "frame-repeats-left",
+        "{program} called from line {line}, {repeats} repeats left",
+        "{program}, aufgerufen in Zeile {line}, noch {repeats} Wiederholungen",
+    ),
+    (
+        "suggested-fix",
+        "Suggested fix: {fix}",
+        "Korrekturvorschlag: {fix}",
+    ),
+    (
+        "fix-insert",
+        "insert '{block}' before the block",
+        "'{block}' vor dem Satz einfügen",
+    ),
+    // Syntax errors
+    (
+        "MS0001-invalid",
+        "Invalid syntax at '{rest}'",
+        "Ungültige Syntax bei '{rest}'",
+    ),
+    (
+        "MS0001-incomplete",
+        "Incomplete data",
+        "Unvollständige Daten",
+    ),
+    (
+        "MS0001-unknown-g",
+        "Unknown G code 'G{n}'",
+        "Unbekannter G-Befehl 'G{n}'",
+    ),
+    (
+        "MS0001-unknown-m",
+        "Unknown M code 'M{n}'",
+        "Unbekannter M-Befehl 'M{n}'",
+    ),
+    // Program errors
+    (
+        "MS0002-no-program",
+        "Code line with no program",
+        "Codezeile außerhalb eines Programms",
+    ),
+    (
+        "MS0002-proc-in-main",
+        "PROC in a main program",
+        "PROC in einem Hauptprogramm",
+    ),
+    (
+        "MS0002-proc-first",
+        "PROC must be the first statement of the subprogram",
+        "PROC muss die erste Anweisung des Unterprogramms sein",
+    ),
+    (
+        "MS0002-defined",
+        "Program {id} is already defined at line {line}",
+        "Programm {id} ist bereits in Zeile {line} definiert",
+    ),
+    (
+        "MS0002-defined-in",
+        "Program {id} is already defined at line {line} of '{file}'",
+        "Programm {id} ist bereits in Zeile {line} von '{file}' definiert",
+    ),
+    (
+        "MS0002-proc-defined",
+        "Subprogram {name} is already defined in {other}",
+        "Unterprogramm {name} ist bereits in {other} definiert",
+    ),
+    (
+        "MS0002-not-found",
+        "Program {name} not found",
+        "Programm {name} nicht gefunden",
+    ),
+    (
+        "MS0002-no-main",
+        "No main programs found",
+        "Keine Hauptprogramme gefunden",
+    ),
+    (
+        "MS0002-end",
+        "{kind} {program} does not end with {word}",
+        "{kind} {program} endet nicht mit {word}",
+    ),
+    (
+        "MS0002-label-defined",
+        "Label {label} is already defined at line {line}",
+        "Marke {label} ist bereits in Zeile {line} definiert",
+    ),
+    (
+        "MS0002-target-undefined",
+        "Jump target of '{word}' is not defined in the program",
+        "Sprungziel von '{word}' ist im Programm nicht definiert",
+    ),
+    (
+        "MS0002-control-alone",
+        "'{control}' must be alone in a block",
+        "'{control}' muss allein in einem Satz stehen",
+    ),
+    (
+        "MS0002-control-mismatch",
+        "'{control}' does not match '{open}' at line {line}",
+        "'{control}' passt nicht zu '{open}' in Zeile {line}",
+    ),
+    (
+        "MS0002-control-unopened",
+        "'{control}' without opening statement",
+        "'{control}' ohne öffnende Anweisung",
+    ),
+    (
+        "MS0002-repeat-label",
+        "Label {label} not found before '{control}'",
+        "Marke {label} vor '{control}' nicht gefunden",
+    ),
+    (
+        "MS0002-control-unclosed",
+        "'{control}' is not closed",
+        "'{control}' wird nicht geschlossen",
+    ),
+    // Runtime errors
+    (
+        "MS0003-undefined",
+        "Variable {name} is not defined",
+        "Variable {name} ist nicht definiert",
+    ),
+    ("MS0003-division", "Division by zero", "Division durch null"),
+    (
+        "MS0003-out-of-range",
+        "Value {value} out of range for address {address}",
+        "Wert {value} außerhalb des Bereichs für Adresse {address}",
+    ),
+    (
+        "MS0003-not-computed",
+        "Address {address} can't be computed",
+        "Adresse {address} kann nicht berechnet werden",
+    ),
+    (
+        "MS0003-not-type",
+        "Value {value} is not {ty}",
+        "Wert {value} ist kein {ty}",
+    ),
+    (
+        "MS0003-root",
+        "Square root of negative value {value}",
+        "Quadratwurzel des negativen Werts {value}",
+    ),
+    (
+        "MS0003-outside",
+        "'{control}' reached outside of its structure, jump into a control structure?",
+        "'{control}' außerhalb seiner Struktur erreicht, Sprung in eine Kontrollstruktur?",
+    ),
+    (
+        "MS0003-target-forward",
+        "Jump target {target} not found in {program} (searching forward)",
+        "Sprungziel {target} in {program} nicht gefunden (Suche vorwärts)",
+    ),
+    (
+        "MS0003-target-backward",
+        "Jump target {target} not found in {program} (searching backward)",
+        "Sprungziel {target} in {program} nicht gefunden (Suche rückwärts)",
+    ),
+    (
+        "MS0003-target-any",
+        "Jump target {target} not found in {program} (searching both ways)",
+        "Sprungziel {target} in {program} nicht gefunden (Suche in beide Richtungen)",
+    ),
+    (
+        "MS0003-value-for",
+        "{error} for {name}",
+        "{error} für {name}",
+    ),
+    (
+        "MS0003-calls",
+        "More than one call in a block",
+        "Mehr als ein Aufruf in einem Satz",
+    ),
+    (
+        "MS0003-jumps",
+        "More than one jump in a block",
+        "Mehr als ein Sprung in einem Satz",
+    ),
+    (
+        "MS0003-sub-not-found",
+        "Subprogram {name} not found",
+        "Unterprogramm {name} nicht gefunden",
+    ),
+    (
+        "MS0003-defined-as",
+        "defined as {definition}",
+        "definiert als {definition}",
+    ),
+    (
+        "MS0003-defined-at",
+        "defined as {id} at line {line}",
+        "definiert als {id} in Zeile {line}",
+    ),
+    (
+        "MS0003-arguments",
+        "{name} expects {expected} arguments, got {got}",
+        "{name} erwartet {expected} Argumente, erhalten {got}",
+    ),
+    (
+        "MS0003-argument",
+        "{error} for parameter {param} of {name}",
+        "{error} für Parameter {param} von {name}",
+    ),
+    (
+        "MS0003-repeats",
+        "Invalid repeat count {count}",
+        "Ungültige Wiederholungsanzahl {count}",
+    ),
+    (
+        "MS0003-repeats-undefined",
+        "Repeats count for subroutine L{n} not defined",
+        "Wiederholungsanzahl für Unterprogramm L{n} nicht definiert",
+    ),
+    (
+        "MS0003-call-with",
+        "Call of {name} combined with {word}",
+        "Aufruf von {name} kombiniert mit {word}",
+    ),
+    (
+        "MS0003-call-with-jump",
+        "Call of {name} combined with '{jump}'",
+        "Aufruf von {name} kombiniert mit '{jump}'",
+    ),
+    (
+        "MS0003-jump-with",
+        "Jump '{jump}' combined with {word}",
+        "Sprung '{jump}' kombiniert mit {word}",
+    ),
+    (
+        "MS0003-return-without-call",
+        "Subroutine return (M17) without subroutine call",
+        "Rücksprung (M17) ohne Unterprogrammaufruf",
+    ),
+    (
+        "MS0003-return-not-last",
+        "Subroutine return (M17) is not the last statement",
+        "Rücksprung (M17) ist nicht die letzte Anweisung",
+    ),
+    (
+        "MS0003-end-in-sub",
+        "Program end (M2) in a subroutine",
+        "Programmende (M2) in einem Unterprogramm",
+    ),
+    (
+        "MS0003-end-not-last",
+        "Program end (M2) is not the last statement",
+        "Programmende (M2) ist nicht die letzte Anweisung",
+    ),
+    (
+        "MS0003-past-end",
+        "Execution ran past the end of {program}, {word} was skipped",
+        "Ausführung lief über das Ende von {program} hinaus, {word} wurde übersprungen",
+    ),
+    (
+        "MS0003-budget",
+        "Block budget of {budget} exceeded, the program probably loops forever",
+        "Satzbudget von {budget} überschritten, das Programm läuft vermutlich endlos",
+    ),
+    (
+        "MS0003-relative",
+        "Relative coordinates can only be used with fully defined position",
+        "Relative Koordinaten gehen nur mit vollständig bekannter Position",
+    ),
+    (
+        "MS0003-double",
+        "Double command: '{first}' and '{second}'",
+        "Doppelter Befehl: '{first}' und '{second}'",
+    ),
+    (
+        "MS0003-double-word",
+        "Double '{name}' command: '{first}' and '{second}'",
+        "Doppelter '{name}'-Befehl: '{first}' und '{second}'",
+    ),
+    (
+        "MS0003-dwell-revolutions",
+        "Dwell in revolutions with spindle off",
+        "Verweilzeit in Umdrehungen bei ausgeschalteter Spindel",
+    ),
+    (
+        "MS0003-dwell-time",
+        "Required parameter 'F' or 'S'",
+        "Parameter 'F' oder 'S' erforderlich",
+    ),
+    (
+        "MS0003-dwell-both",
+        "Dwell with both 'F' and 'S'",
+        "Verweilzeit mit 'F' und 'S' zugleich",
+    ),
+    (
+        "MS0003-cut-unknown",
+        "Trying to cut from undefined position",
+        "Schnitt von unbekannter Position",
+    ),
+    (
+        "MS0003-circle-end",
+        "Circle end point not on the circle (radius = {radius}, start at ({x}, {y})",
+        "Endpunkt liegt nicht auf dem Kreis (Radius = {radius}, Start bei ({x}, {y})",
+    ),
+    (
+        "MS0003-required",
+        "Required parameter '{name}'",
+        "Parameter '{name}' erforderlich",
+    ),
+    (
+        "MS0003-dangerous",
+        "Parameter '{name}' is dangerous here",
+        "Parameter '{name}' ist hier gefährlich",
+    ),
+    // Static analysis
+    (
+        "MS0004",
+        "Subprogram {program} is never called",
+        "Unterprogramm {program} wird nie aufgerufen",
+    ),
+    (
+        "MS0005",
+        "Subprogram {name} not found",
+        "Unterprogramm {name} nicht gefunden",
+    ),
+    (
+        "MS0006",
+        "Recursive call {chain}",
+        "Rekursiver Aufruf {chain}",
+    ),
+    (
+        "MS0007",
+        "Call depth {depth} exceeds the limit of {limit} levels: {chain}",
+        "Aufruftiefe {depth} überschreitet die Grenze von {limit} Ebenen: {chain}",
+    ),
+    (
+        "MS0008",
+        "Code after {end} in {program} is never executed",
+        "Code nach {end} in {program} wird nie ausgeführt",
+    ),
+    (
+        "MS0008-skipped",
+        "Jump skips {end} in {program}, execution runs past the end",
+        "Sprung überspringt {end} in {program}, die Ausführung läuft über das Ende hinaus",
+    ),
+    (
+        "max-depth",
+        "Maximal call depth: {depth} of {limit} levels",
+        "Maximale Aufruftiefe: {depth} von {limit} Ebenen",
+    ),
+    (
+        "max-depth-recursive",
+        "Maximal call depth: unlimited, calls are recursive",
+        "Maximale Aufruftiefe: unbegrenzt, Aufrufe sind rekursiv",
+    ),
+    (
+        "worst-case",
+        "Worst case of {program}: {blocks} blocks executed",
+        "Ungünstigster Fall von {program}: {blocks} Sätze ausgeführt",
+    ),
+    (
+        "worst-case-unbounded",
+        "Worst case of {program}: unbounded, loops without fixed count",
+        "Ungünstigster Fall von {program}: unbegrenzt, Schleifen ohne feste Anzahl",
+    ),
+    // Machine rules
+    (
+        "MS0010",
+        "Ending program with spindle on",
+        "Programmende bei laufender Spindel",
+    ),
+    (
+        "MS0011",
+        "Ending program with coolant on",
+        "Programmende bei eingeschaltetem Kühlmittel",
+    ),
+    (
+        "MS0012",
+        "Trying to start spindle without ensuring coolant flow",
+        "Spindelstart ohne gesicherte Kühlmittelzufuhr",
+    ),
+    (
+        "MS0013",
+        "Trying to start spindle without any speed",
+        "Spindelstart ohne Drehzahl",
+    ),
+    (
+        "MS0014",
+        "Trying to start spindle backwards",
+        "Spindelstart in Gegenrichtung",
+    ),
+    (
+        "MS0015",
+        "Trying to turn off spindle while moving",
+        "Spindel während einer Bewegung ausgeschaltet",
+    ),
+    (
+        "MS0016",
+        "Trying to turn off coolant while moving",
+        "Kühlmittel während einer Bewegung ausgeschaltet",
+    ),
+    (
+        "MS0017",
+        "Coolant turned off while spindle still running",
+        "Kühlmittel bei laufender Spindel ausgeschaltet",
+    ),
+    (
+        "MS0018",
+        "Ending program with too low Z",
+        "Programmende mit zu niedrigem Z",
+    ),
+    (
+        "MS0019",
+        "First movement should be to safe Z height",
+        "Die erste Bewegung muss auf die sichere Z-Höhe gehen",
+    ),
+    (
+        "MS0020",
+        "Unsafe movement without fully defininig the position",
+        "Unsichere Bewegung ohne vollständig bekannte Position",
+    ),
+    (
+        "MS0021",
+        "Turn off spindle and coolant before performing tool change",
+        "Vor dem Werkzeugwechsel Spindel und Kühlmittel ausschalten",
+    ),
+    (
+        "MS0022",
+        "Must be high enough to perform tool change",
+        "Für den Werkzeugwechsel muss Z hoch genug sein",
+    ),
+    (
+        "MS0023",
+        "Tool change without stopping",
+        "Werkzeugwechsel ohne Anhalten",
+    ),
+    (
+        "MS0024",
+        "Trying to cut with spindle off",
+        "Schnitt bei ausgeschalteter Spindel",
+    ),
+    (
+        "MS0025",
+        "Trying to cut without coolant",
+        "Schnitt ohne Kühlmittel",
+    ),
+    (
+        "MS0026-low",
+        "Speed {speed} is too low",
+        "Drehzahl {speed} ist zu niedrig",
+    ),
+    (
+        "MS0026-high",
+        "Speed {speed} is too high",
+        "Drehzahl {speed} ist zu hoch",
+    ),
+    (
+        "MS0027-low",
+        "Feed {feed} is too low",
+        "Vorschub {feed} ist zu niedrig",
+    ),
+    (
+        "MS0027-high",
+        "Feed {feed} is too high",
+        "Vorschub {feed} ist zu hoch",
+    ),
+    (
+        "MS0028",
+        "Trying to cut with no tool",
+        "Schnitt ohne Werkzeug",
+    ),
+    // Machine state
+    (
+        "state-position",
+        "Position: X{x} Y{y} Z{z}",
+        "Position: X{x} Y{y} Z{z}",
+    ),
+    ("state-movement", "Movement: {movement}", "Bewegung: {movement}"),
+    (
+        "state-speed",
+        "Speed: S{speed} Feed: F{feed} Tool: D{tool}",
+        "Drehzahl: S{speed} Vorschub: F{feed} Werkzeug: D{tool}",
+    ),
+    (
+        "state-spindle",
+        "Spindle: {spindle} Coolant: {coolant}",
+        "Spindel: {spindle} Kühlmittel: {coolant}",
+    ),
+    ("on", "on", "ein"),
+    ("off", "off", "aus"),
+    (
+        "state-coordinates",
+        "Coordinates: {coordinates}",
+        "Koordinaten: {coordinates}",
+    ),
+    ("relative", "relative", "relativ"),
+    ("absolute", "absolute", "absolut"),
+    ("state-time", "Time: {seconds}s", "Zeit: {seconds}s"),
+    // Commands
+    ("L", "L (subroutine call)", "L (Unterprogrammaufruf)"),
+    (
+        "L-cycle",
+        "L (builtin subroutine)",
+        "L (eingebauter Zyklus)",
+    ),
+    (
+        "M17",
+        "M17 (subroutine return)",
+        "M17 (Rücksprung aus Unterprogramm)",
+    ),
+    ("M2", "M2 (program end)", "M2 (Programmende)"),
+    ("G0", "G0 (fast move)", "G0 (Eilgang)"),
+    ("G1", "G1 (linear move)", "G1 (Geradeninterpolation)"),
+    (
+        "G2",
+        "G2 (circular move CW)",
+        "G2 (Kreisbewegung im Uhrzeigersinn)",
+    ),
+    (
+        "G3",
+        "G3 (circular move CCW)",
+        "G3 (Kreisbewegung gegen den Uhrzeigersinn)",
+    ),
+    ("M6", "M6 (tool change)", "M6 (Werkzeugwechsel)"),
+    ("G4", "G4 (dwell)", "G4 (Verweilzeit)"),
+    (
+        "M3",
+        "M3 (spindle on CW)",
+        "M3 (Spindel ein im Uhrzeigersinn)",
+    ),
+    (
+        "M4",
+        "M4 (spindle on CCW)",
+        "M4 (Spindel ein gegen den Uhrzeigersinn)",
+    ),
+    ("M5", "M5 (spindle off)", "M5 (Spindel aus)"),
+    ("M8", "M8 (coolant on)", "M8 (Kühlmittel ein)"),
+    ("M9", "M9 (coolant off)", "M9 (Kühlmittel aus)"),
+    ("G90", "G90 (absolute coordinates)", "G90 (Absolutmaß)"),
+    ("G91", "G91 (relative coordinates)", "G91 (Kettenmaß)"),
+    // Command words
+    ("word-N", "N[umber]", "N[ummer]"),
+    ("word-S", "S[peed]", "S[Drehzahl]"),
+    ("word-F", "F[eed]", "F[Vorschub]"),
+    ("word-D", "D (tool)", "D (Werkzeug)"),
+    ("word-I", "I (center X)", "I (Mittelpunkt X)"),
+    ("word-J", "J (center Y)", "J (Mittelpunkt Y)"),
+    ("word-P", "P (repeat count)", "P (Wiederholungen)"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{Lang, CATALOG};
+    use std::collections::HashSet;
+
+    /// Placeholders of a catalog text
+    fn placeholders(text: &str) -> HashSet<&str> {
+        text.split('{')
+            .skip(1)
+            .filter_map(|s| s.split_once('}').map(|(name, _)| name))
+            .collect()
+    }
+
+    #[test]
+    fn translations() {
+        let mut keys = HashSet::new();
+        for (key, en, de) in CATALOG {
+            assert!(keys.insert(key), "duplicate key {key}");
+            assert_eq!(placeholders(en), placeholders(de), "placeholders of {key}");
+        }
+    }
+
+    #[test]
+    fn locales() {
+        assert_eq!("de_DE.UTF-8".parse(), Ok(Lang::De));
+        assert_eq!("de".parse(), Ok(Lang::De));
+        assert_eq!("en_US".parse(), Ok(Lang::En));
+        assert_eq!("C.UTF-8".parse(), Ok(Lang::En));
+        assert_eq!("fr_FR".parse::<Lang>(), Err(()));
+    }
+}
@@ -0,0 +1,40 @@
+//! Milling machine G-code simulator
+//!
+//! A program is loaded from [`GCodeFile`]s into a [`Program`], which resolves
+//! the main programs and subprograms. Its [`Executor`] takes the blocks in the
+//! order the control would run them, and a [`Machine`] configured by a
+//! [`MachineConfig`] executes them, passing movements to [`Render`]s and
+//! reporting [`Diagnostic`]s about unsafe or questionable code.
+//!
+//! Messages are in English unless [`set_lang`] chose another [`Lang`] for the
+//! whole process.
+//!
+//! ```no_run
+//! use millsim::{GCodeFile, Machine, Program};
+//!
+//! let file = GCodeFile::load("part.mpf")?;
+//! let program = Program::from_files(vec![file], &[])?;
+//! let mut machine = Machine::default();
+//! let exec = program.execute(None).map_err(|e| e.no_line())?;
+//! machine.run(exec, |d| eprintln!("{d}"), |_, _, _, _| Ok(()))?;
+//! println!("{}", machine.state());
+//! # Ok::<(), millsim::LineError>(())
+//! ```
+
+#![warn(missing_docs)]
+
+pub mod compare;
+pub mod diagnostic;
+pub mod errors;
+pub mod gcode;
+mod i18n;
+pub mod machine;
+pub mod render;
+pub mod types;
+
+pub use diagnostic::{Diagnostic, Level, Rule, RuleSet, Severity};
+pub use errors::{Fix, LineError, SimpleError};
+pub use gcode::GCodeFile;
+pub use i18n::{lang, set_lang, Lang};
+pub use machine::{Analysis, Executor, Machine, MachineConfig, Program};
+pub use render::Render;
@@ -0,0 +1,568 @@
+//! Language server for editors, speaking LSP over standard input and output
+//!
+//! Every change of an open document runs the parser, the program checks, the
+//! static analysis and a simulation, and publishes their problems. Suggested
+//! fixes travel in the diagnostic data and come back as quick fixes.
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+        Notification as _, PublishDiagnostics,
+    },
+    request::{CodeActionRequest, GotoDefinition, HoverRequest, InlayHintRequest, Request as _},
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
+    CodeActionProviderCapability, Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, InlayHint,
+    InlayHintLabel, InlayHintParams, InlayHintTooltip, Location, MarkedString, NumberOrString,
+    OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url, WorkspaceEdit,
+};
+use millsim::{
+    diagnostic::{self, Rule, Severity},
+    errors::{Fix, LineError},
+    gcode::{words::Word, GCodeFile, Line, Span},
+    machine::{
+        backtrace, describe, is_builtin, Analysis, Machine, MachineConfig, MachineState, Program,
+    },
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+/// Where to look for subprograms and how to simulate
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub search_path: Vec<PathBuf>,
+    pub libraries: Vec<PathBuf>,
+    pub max_blocks: u64,
+    pub machine: MachineConfig,
+}
+
+/// Serve one editor session on standard input and output
+pub fn run(config: ServerConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (connection, io) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        inlay_hint_provider: Some(OneOf::Left(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server {
+        connection,
+        config,
+        documents: HashMap::new(),
+    };
+    server.serve()?;
+    // The writer thread ends once the connection is gone
+    drop(server);
+    io.join()?;
+    Ok(())
+}
+
+struct Server {
+    connection: Connection,
+    config: ServerConfig,
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn serve(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        while let Ok(msg) = self.connection.receiver.recv() {
+            match msg {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        break;
+                    }
+                    let response = self.request(req);
+                    self.connection.sender.send(response.into())?;
+                }
+                Message::Notification(n) => self.notification(n)?,
+                Message::Response(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, req: Request) -> Response {
+        let result = match req.method.as_str() {
+            HoverRequest::METHOD => handle(req.params, |p: HoverParams| {
+                let p = p.text_document_position_params;
+                self.documents.get(&p.text_document.uri)?.hover(p.position)
+            }),
+            GotoDefinition::METHOD => handle(req.params, |p: GotoDefinitionParams| {
+                let p = p.text_document_position_params;
+                self.documents
+                    .get(&p.text_document.uri)?
+                    .definition(p.position)
+                    .map(GotoDefinitionResponse::Scalar)
+            }),
+            CodeActionRequest::METHOD => handle(req.params, |p: CodeActionParams| {
+                let uri = p.text_document.uri;
+                let document = self.documents.get(&uri)?;
+                Some(document.code_actions(&uri, p.context.diagnostics))
+            }),
+            InlayHintRequest::METHOD => handle(req.params, |p: InlayHintParams| {
+                self.documents
+                    .get(&p.text_document.uri)
+                    .map(|d| d.inlay_hints(p.range))
+            }),
+            _ => {
+                return Response::new_err(
+                    req.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unknown request '{}'", req.method),
+                )
+            }
+        };
+        match result {
+            Ok(value) => Response::new_ok(req.id, value),
+            Err(e) => Response::new_err(req.id, ErrorCode::InvalidParams as i32, e.to_string()),
+        }
+    }
+
+    fn notification(&mut self, n: Notification) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match n.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let p: DidOpenTextDocumentParams = serde_json::from_value(n.params)?;
+                self.update(p.text_document.uri, p.text_document.text)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let p: DidChangeTextDocumentParams = serde_json::from_value(n.params)?;
+                // Full text sync, the last change is the whole document
+                if let Some(change) = p.content_changes.into_iter().last() {
+                    self.update(p.text_document.uri, change.text)?;
+                }
+            }
+            DidSaveTextDocument::METHOD => {
+                // Subprograms of the other documents may come from the saved file
+                let open = self
+                    .documents
+                    .iter()
+                    .map(|(uri, d)| (uri.clone(), d.text.clone()))
+                    .collect::<Vec<_>>();
+                for (uri, text) in open {
+                    self.update(uri, text)?;
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let p: DidCloseTextDocumentParams = serde_json::from_value(n.params)?;
+                self.documents.remove(&p.text_document.uri);
+                self.publish(p.text_document.uri, Vec::new())?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Check the new document text and publish its problems
+    fn update(&mut self, uri: Url, text: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = uri
+            .to_file_path()
+            .unwrap_or_else(|()| PathBuf::from(uri.path()));
+        let (document, diagnostics) = Document::check(path, text, &self.config);
+        self.documents.insert(uri.clone(), document);
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(
+        &self,
+        uri: Url,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        let n = Notification::new(PublishDiagnostics::METHOD.into(), params);
+        self.connection.sender.send(n.into())?;
+        Ok(())
+    }
+}
+
+/// Decode request parameters and encode the result
+fn handle<P: serde::de::DeserializeOwned, R: serde::Serialize>(
+    params: Value,
+    f: impl FnOnce(P) -> Option<R>,
+) -> Result<Value, serde_json::Error> {
+    serde_json::to_value(f(serde_json::from_value(params)?))
+}
+
+/// Open document with the results of its last check
+struct Document {
+    path: PathBuf,
+    text: String,
+    /// Parsed text, `None` on syntax errors
+    file: Option<GCodeFile>,
+    /// Program with its subprograms, `None` if it doesn't load
+    program: Option<Program>,
+    /// Machine state after the first execution of each line
+    states: BTreeMap<u64, MachineState>,
+}
+
+impl Document {
+    fn check(path: PathBuf, text: String, config: &ServerConfig) -> (Self, Vec<Diagnostic>) {
+        let mut problems = Vec::new();
+        let mut doc = Self {
+            file: GCodeFile::parse(&path, &text)
+                .map_err(|e| {
+                    let e = e.in_file(&path);
+                    problems.push(diagnostic::Diagnostic::from_error(&e, Rule::SyntaxError))
+                })
+                .ok(),
+            path,
+            text,
+            program: None,
+            states: BTreeMap::new(),
+        };
+
+        if let Some(file) = &doc.file {
+            let program =
+                Program::with_libraries(file.clone(), &config.libraries, &config.search_path);
+            match program {
+                Ok(program) => doc.program = Some(program),
+                Err(e) => problems.push(diagnostic::Diagnostic::from_error(
+                    &e.in_file(&doc.path),
+                    Rule::ProgramError,
+                )),
+            }
+        }
+
+        if let Some(program) = &doc.program {
+            let depth_limit = Analysis::DEFAULT_DEPTH_LIMIT;
+            let analysis = Analysis::new(program, depth_limit, config.machine.rules());
+            problems.extend(analysis.findings.iter().map(|f| f.diagnostic()));
+            let machine = Machine::with_config(config.machine.clone());
+            let simulated = simulate(
+                program,
+                &doc.path,
+                config.max_blocks,
+                machine,
+                &mut doc.states,
+                &mut problems,
+            );
+            if let Err(e) = simulated {
+                problems.push(diagnostic::Diagnostic::from_error(&e, Rule::RuntimeError));
+            }
+        }
+
+        let diagnostics = problems
+            .into_iter()
+            .map(|d| {
+                let file = d.file.as_deref().unwrap_or(&doc.path);
+                let calls: String = backtrace(&d.stack).map(|c| format!("\n{c}")).collect();
+                let (range, message, fix) = if file == doc.path {
+                    let message = format!("{}{calls}", d.message);
+                    (doc.line_range(d.line.unwrap_or(1)), message, d.fix)
+                } else {
+                    // Problems of other files show at the start of this one
+                    let at = d.line.map(|l| format!(" at line {l}")).unwrap_or_default();
+                    let message = format!("In '{}'{at}: {}{calls}", file.display(), d.message);
+                    (doc.line_range(1), message, None)
+                };
+                let severity = match d.severity {
+                    Severity::Note => DiagnosticSeverity::INFORMATION,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                };
+                Diagnostic {
+                    range,
+                    severity: Some(severity),
+                    code: Some(NumberOrString::String(d.rule.code())),
+                    source: Some("millsim".into()),
+                    message,
+                    data: fix.and_then(|f| serde_json::to_value(f).ok()),
+                    ..Diagnostic::default()
+                }
+            })
+            .collect();
+        (doc, diagnostics)
+    }
+
+    /// Text of a line numbered from 1
+    fn line_text(&self, line: u64) -> &str {
+        let idx = line.saturating_sub(1) as usize;
+        self.text.lines().nth(idx).unwrap_or_default()
+    }
+
+    /// Whole line numbered from 1
+    fn line_range(&self, line: u64) -> Range {
+        let text = self.line_text(line);
+        let line = line.saturating_sub(1) as u32;
+        Range::new(Position::new(line, 0), Position::new(line, utf16_len(text)))
+    }
+
+    /// Code word under the cursor with its range
+    fn word_at(&self, pos: Position) -> Option<(&Word, Range)> {
+        let file = self.file.as_ref()?;
+        let source = file.source_line(pos.line as u64 + 1)?;
+        let Line::Code(words) = file.code().nth(pos.line as usize)?.1 else {
+            return None;
+        };
+        let byte = byte_offset(&source.text, pos.character);
+        let idx = source
+            .words
+            .iter()
+            .position(|w| w.start <= byte && byte <= w.end)?;
+        let range = span_range(pos.line, &source.text, source.words[idx].clone());
+        Some((&words.0[idx], range))
+    }
+
+    fn hover(&self, pos: Position) -> Option<Hover> {
+        let (word, range) = self.word_at(pos)?;
+        let text = match word {
+            Word::G(_) | Word::M(_) | Word::L(_) => describe(word)?,
+            _ => return None,
+        };
+        Some(Hover {
+            contents: HoverContents::Scalar(MarkedString::String(text)),
+            range: Some(range),
+        })
+    }
+
+    fn definition(&self, pos: Position) -> Option<Location> {
+        let name = match self.word_at(pos)?.0 {
+            Word::L(n) if !is_builtin(*n) => format!("L{n}"),
+            Word::Call(name, _) => name.clone(),
+            _ => return None,
+        };
+        let (path, line) = self.program.as_ref()?.definition(&name)?;
+        let line = line.saturating_sub(1) as u32;
+        Some(Location::new(
+            Url::from_file_path(path).ok()?,
+            Range::new(Position::new(line, 0), Position::new(line, 0)),
+        ))
+    }
+
+    /// Quick fixes of the diagnostics with a suggested fix
+    fn code_actions(&self, uri: &Url, diagnostics: Vec<Diagnostic>) -> Vec<CodeActionOrCommand> {
+        let mut actions = Vec::new();
+        for d in diagnostics {
+            let Some(fix) = d
+                .data
+                .clone()
+                .and_then(|v| serde_json::from_value::<Fix>(v).ok())
+            else {
+                continue;
+            };
+            let line = d.range.start.line;
+            let text = self.line_text(line as u64 + 1);
+            let indent = &text[..text.len() - text.trim_start().len()];
+            let edit = TextEdit::new(
+                Range::new(Position::new(line, 0), Position::new(line, 0)),
+                format!("{indent}{}\n", fix.insert),
+            );
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Insert '{}'", fix.insert),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![d]),
+                edit: Some(WorkspaceEdit::new(HashMap::from([(
+                    uri.clone(),
+                    vec![edit],
+                )]))),
+                is_preferred: Some(true),
+                ..CodeAction::default()
+            }));
+        }
+        actions
+    }
+
+    /// Machine state after the blocks in the range, at the line ends
+    fn inlay_hints(&self, range: Range) -> Vec<InlayHint> {
+        let lines = range.start.line as u64 + 1..=range.end.line as u64 + 1;
+        self.states
+            .range(lines)
+            .map(|(&line, state)| InlayHint {
+                position: Position::new(line as u32 - 1, utf16_len(self.line_text(line))),
+                label: InlayHintLabel::String(summary(state)),
+                kind: None,
+                text_edits: None,
+                tooltip: Some(InlayHintTooltip::String(state.to_string())),
+                padding_left: Some(true),
+                padding_right: None,
+                data: None,
+            })
+            .collect()
+    }
+}
+
+/// Run the program until its end or the first error, recording the
+/// machine state after the lines of `path` and the rule violations
+fn simulate(
+    program: &Program,
+    path: &Path,
+    max_blocks: u64,
+    mut machine: Machine,
+    states: &mut BTreeMap<u64, MachineState>,
+    reported: &mut Vec<diagnostic::Diagnostic>,
+) -> Result<(), LineError> {
+    // Subprogram files have no main program to run
+    let Ok(exec) = program.execute(None) else {
+        return Ok(());
+    };
+    let exec = exec.with_budget(max_blocks);
+    let report = |d| reported.push(d);
+    machine.run(exec, report, |line, _, exec, machine| {
+        if exec.file() == path {
+            states.entry(line).or_insert_with(|| machine.state());
+        }
+        Ok(())
+    })
+}
+
+/// Machine state in one line, unknown values left out
+fn summary(state: &MachineState) -> String {
+    let mut parts = Vec::new();
+    for (axis, v) in [("X", state.x), ("Y", state.y), ("Z", state.z)] {
+        parts.extend(v.map(|v| format!("{axis}{v}")));
+    }
+    parts.extend(state.feed.map(|f| format!("F{f}")));
+    parts.extend(state.speed.map(|s| format!("S{s}")));
+    parts.extend(state.tool.map(|d| format!("D{d}")));
+    if state.spindle_on {
+        parts.push("spindle on".into());
+    }
+    if state.coolant_on {
+        parts.push("coolant on".into());
+    }
+    parts.join(" ")
+}
+
+/// Positions count UTF-16 code units
+fn utf16_len(text: &str) -> u32 {
+    text.encode_utf16().count() as u32
+}
+
+fn byte_offset(text: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (idx, c) in text.char_indices() {
+        if units >= character {
+            return idx;
+        }
+        units += c.len_utf16() as u32;
+    }
+    text.len()
+}
+
+fn span_range(line: u32, text: &str, span: Span) -> Range {
+    Range::new(
+        Position::new(line, utf16_len(&text[..span.start])),
+        Position::new(line, utf16_len(&text[..span.end])),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Document, ServerConfig};
+    use lsp_types::{
+        CodeActionOrCommand, HoverContents, MarkedString, NumberOrString, Position, Range, Url,
+    };
+    use millsim::{machine::Executor, MachineConfig};
+    use std::path::PathBuf;
+
+    /// Spindle starts without coolant behind a comment with a non-ASCII letter
+    const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+  (Maß) M3 S1000 D1
+L3 P1
+G0 Z150
+M5 M9
+M2
+%SPF3
+G1 X10 Z-1 F100
+G0 Z150
+M17
+";
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!("millsim-{}-part.mpf", std::process::id()))
+    }
+
+    fn check(text: &str) -> (Document, Vec<lsp_types::Diagnostic>) {
+        let config = ServerConfig {
+            search_path: Vec::new(),
+            libraries: Vec::new(),
+            max_blocks: Executor::DEFAULT_BUDGET,
+            machine: MachineConfig::default(),
+        };
+        Document::check(path(), text.into(), &config)
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (_, diagnostics) = check(PART);
+        assert_eq!(diagnostics.len(), 1);
+        let d = &diagnostics[0];
+        assert_eq!(d.code, Some(NumberOrString::String("MS0012".into())));
+        // 'ß' is two bytes but one UTF-16 unit
+        assert_eq!(d.range, range(3, 0, 19));
+        assert!(d.data.is_some());
+
+        let (_, diagnostics) = check("%MPF1\nG0 X1 Q?\nM2\n");
+        assert_eq!(diagnostics[0].range, range(1, 0, 8));
+    }
+
+    #[test]
+    fn hover() {
+        let (doc, _) = check(PART);
+        let hover = doc.hover(Position::new(3, 9)).unwrap();
+        assert_eq!(hover.range, Some(range(3, 8, 10)));
+        let HoverContents::Scalar(MarkedString::String(text)) = hover.contents else {
+            panic!("unexpected hover {:?}", hover.contents);
+        };
+        assert!(!text.is_empty());
+
+        // Words without a description
+        assert!(doc.hover(Position::new(3, 12)).is_none());
+        assert!(doc.hover(Position::new(1, 3)).is_none());
+    }
+
+    #[test]
+    fn definition() {
+        let (doc, _) = check(PART);
+        let location = doc.definition(Position::new(4, 1)).unwrap();
+        assert_eq!(location.uri, Url::from_file_path(path()).unwrap());
+        assert_eq!(location.range, range(8, 0, 0));
+        assert!(doc.definition(Position::new(3, 9)).is_none());
+    }
+
+    #[test]
+    fn quick_fix() {
+        let (doc, diagnostics) = check(PART);
+        let uri = Url::from_file_path(path()).unwrap();
+        let actions = doc.code_actions(&uri, diagnostics);
+        assert_eq!(actions.len(), 1);
+        let CodeActionOrCommand::CodeAction(action) = &actions[0] else {
+            panic!("unexpected action {:?}", actions[0]);
+        };
+        let edits = &action.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri];
+        assert_eq!(edits.len(), 1);
+        // Inserted before the line with its indentation
+        assert_eq!(edits[0].range, range(3, 0, 0));
+        assert_eq!(edits[0].new_text, "  M8\n");
+    }
+
+    #[test]
+    fn inlay_hints() {
+        let (doc, _) = check(PART);
+        // The simulation stops at the spindle start
+        let hints = doc.inlay_hints(Range::new(Position::new(0, 0), Position::new(11, 0)));
+        let positions: Vec<_> = hints.iter().map(|h| h.position).collect();
+        assert_eq!(positions, [Position::new(1, 7), Position::new(2, 8)]);
+    }
+}
@@ -3,43 +3,80 @@
 use crate::{
     errors::SimpleError,
     gcode::words::{GWord, MWord, Word, Words},
+    i18n::{localized, text, tr},
     types::Micrometer,
 };
 use std::fmt;
-use strum::Display;
+use strum::IntoStaticStr;
 
+/// Block decoded into the actions of the machine
 #[derive(Debug, Default)]
 pub struct Command {
+    /// Program flow: subprogram call, return or program end
     pub global: Option<Global>,
+    /// Movement or tool change
     pub movement: Option<Movement>,
+    /// Dwell with G4
+    pub dwell: Option<Dwell>,
 
+    /// Spindle start or stop
     pub spindle_action: Option<SpindleAction>,
+    /// Coolant on or off
     pub water_action: Option<WaterAction>,
+    /// Switch between absolute and relative coordinates
     pub coord_switch: Option<CoordSwitch>,
 
+    /// X as written, absolute or relative
     pub raw_x: Option<Micrometer>,
+    /// Y as written, absolute or relative
     pub raw_y: Option<Micrometer>,
+    /// Z as written, absolute or relative
     pub raw_z: Option<Micrometer>,
+    /// Arc center X relative to the start point
     pub i: Option<Micrometer>,
+    /// Arc center Y relative to the start point
     pub j: Option<Micrometer>,
 
+    /// S spindle speed
     pub speed: Option<u16>,
-    pub feed: Option<u16>,
+    /// F feed, or dwell time in seconds with G4
+    pub feed: Option<f64>,
+    /// D tool number
     pub tool: Option<u8>,
 
+    /// N block number
     pub n: Option<u32>,
+    /// P subprogram repeat count
     pub p: Option<u16>,
 
+    /// Comments of the block
     pub comment: String,
 
+    /// Literal words of the block, expressions evaluated
     pub raw: Words,
 }
 
-fn is_builtin(l: u8) -> bool {
-    l >= 80
+/// Check if `L<n>` is a built-in cycle rather than a subprogram call
+pub fn is_builtin(l: u32) -> bool {
+    (80..=255).contains(&l)
+}
+
+/// Description of a G, M or L word like "G2 (circular move CW)"
+pub fn describe(word: &Word) -> Option<String> {
+    let cmd = Command::from_gcode(std::slice::from_ref(word)).ok()?;
+    let descriptions = [
+        cmd.global.map(|a| a.to_string()),
+        cmd.movement.map(|a| a.to_string()),
+        cmd.dwell.map(|a| a.to_string()),
+        cmd.spindle_action.map(|a| a.to_string()),
+        cmd.water_action.map(|a| a.to_string()),
+        cmd.coord_switch.map(|a| a.to_string()),
+    ];
+    descriptions.into_iter().flatten().next()
 }
 
 impl Command {
+    /// Decode the words of a block, rejecting conflicting ones
     pub fn from_gcode(gcode: &[Word]) -> Result<Self, SimpleError> {
         let mut cmd = Self::default();
 
@@ -50,11 +87,16 @@ impl Command {
             use MWord::*;
             use Word::*;
             match word {
-                L(n) if is_builtin(*n) => cmd.movement.set(Movement::BuiltinCycle(*n))?,
+                L(n) if is_builtin(*n) => cmd.movement.set(Movement::BuiltinCycle(*n as u8))?,
                 L(n) => cmd.global.set(Global::CallSub(*n))?,
-                N(n) => cmd.n.setn("N[umber]", *n)?,
-                Comment(s) => cmd.comment.push_str(s),
-                R(a, b) => (), //unimplemented!(),
+                N(n) => cmd.n.setn("word-N", *n)?,
+                Comment(s) | LineComment(s) => cmd.comment.push_str(s),
+                // Evaluated by the executor
+                R(..) | Label(_) | Goto(_) | Control(_) => (),
+                Proc(..) | Def(..) | Assign(..) | Call(..) => (),
+                Computed(..) => {
+                    return Err(SimpleError(format!("Bug: unevaluated expression '{word}'")))
+                }
 
                 M(M2) => cmd.global.set(Global::EndProgram)?,
                 M(M17) => cmd.global.set(Global::ReturnSub)?,
@@ -65,6 +107,7 @@ impl Command {
                 G(G1) => cmd.movement.set(Movement::Line)?,
                 G(G2) => cmd.movement.set(Movement::CircleCW)?,
                 G(G3) => cmd.movement.set(Movement::CircleCCW)?,
+                G(G4) => cmd.dwell.set(Dwell::Dwell)?,
 
                 G(G90) => cmd.coord_switch.set(CoordSwitch::Absolute)?,
                 G(G91) => cmd.coord_switch.set(CoordSwitch::Relative)?,
@@ -76,17 +119,17 @@ impl Command {
                 M(M8) => cmd.water_action.set(WaterAction::WaterOn)?,
                 M(M9) => cmd.water_action.set(WaterAction::WaterOff)?,
 
-                S(n) => cmd.speed.setn("S[peed]", *n)?,
-                F(n) => cmd.feed.setn("F[eed]", *n)?,
-                D(n) => cmd.tool.setn("D (tool)", *n)?,
+                S(n) => cmd.speed.setn("word-S", *n)?,
+                F(n) => cmd.feed.setn("word-F", *n)?,
+                D(n) => cmd.tool.setn("word-D", *n)?,
 
                 X(n) => cmd.raw_x.setn("X", *n)?,
                 Y(n) => cmd.raw_y.setn("Y", *n)?,
                 Z(n) => cmd.raw_z.setn("Z", *n)?,
-                I(n) => cmd.i.setn("I (center X)", *n)?,
-                J(n) => cmd.j.setn("J (center Y)", *n)?,
+                I(n) => cmd.i.setn("word-I", *n)?,
+                J(n) => cmd.j.setn("word-J", *n)?,
 
-                P(n) => cmd.p.setn("P (repeat count)", *n)?,
+                P(n) => cmd.p.setn("word-P", *n)?,
             }
         }
 
@@ -106,8 +149,10 @@ impl<T: Sized + fmt::Display> SetOrError<T> for Option<T> {
                 *self = Some(value);
                 Ok(())
             }
-            Some(old) => Err(SimpleError(format!(
-                "Double command: '{old}' and '{value}'"
+            Some(old) => Err(SimpleError(tr!(
+                "MS0003-double",
+                first = old,
+                second = value
             ))),
         }
     }
@@ -118,61 +163,102 @@ impl<T: Sized + fmt::Display> SetOrError<T> for Option<T> {
                 *self = Some(value);
                 Ok(())
             }
-            Some(old) => Err(SimpleError(format!(
-                "Double '{name}' command: '{old}' and '{value}'"
+            Some(old) => Err(SimpleError(tr!(
+                "MS0003-double-word",
+                name = text(name, &[]),
+                first = old,
+                second = value
             ))),
         }
     }
 }
 
-#[derive(Debug, Display)]
+/// Change of the program flow
+#[derive(Debug, IntoStaticStr)]
 pub enum Global {
-    #[strum(serialize = "L (subroutine call)")]
-    CallSub(u8),
-    #[strum(serialize = "M17 (subroutine return)")]
+    /// `L<n>` subprogram call
+    #[strum(serialize = "L")]
+    CallSub(u32),
+    /// `M17` return from a subprogram
+    #[strum(serialize = "M17")]
     ReturnSub,
-    #[strum(serialize = "M2 (program end)")]
+    /// `M2` end of the main program
+    #[strum(serialize = "M2")]
     EndProgram,
 }
 
-#[derive(Debug, Display)]
+/// Modal movement command
+#[derive(Debug, Clone, IntoStaticStr)]
 pub enum Movement {
-    #[strum(serialize = "G0 (fast move)")]
+    /// `G0` rapid traverse
+    #[strum(serialize = "G0")]
     FastLine,
-    #[strum(serialize = "G1 (linear move)")]
+    /// `G1` straight cut
+    #[strum(serialize = "G1")]
     Line,
-    #[strum(serialize = "G2 (circular move CW)")]
+    /// `G2` clockwise arc
+    #[strum(serialize = "G2")]
     CircleCW,
-    #[strum(serialize = "G3 (circular move CCW)")]
+    /// `G3` counterclockwise arc
+    #[strum(serialize = "G3")]
     CircleCCW,
-    #[strum(serialize = "M6 (tool change)")]
+    /// `M6` tool change
+    #[strum(serialize = "M6")]
     ToolChange,
-    #[strum(serialize = "L (builtin subroutine)")]
+    /// `L<n>` built-in cycle of the control
+    #[strum(serialize = "L-cycle")]
     BuiltinCycle(u8),
 }
 
-#[derive(Debug, Display)]
+/// Dwell command
+#[derive(Debug, IntoStaticStr)]
+pub enum Dwell {
+    /// `G4` waiting F seconds or S revolutions
+    #[strum(serialize = "G4")]
+    Dwell,
+}
+
+/// Spindle start or stop
+#[derive(Debug, IntoStaticStr)]
 pub enum SpindleAction {
-    #[strum(serialize = "M3 (spindle on CW)")]
+    /// `M3` clockwise
+    #[strum(serialize = "M3")]
     SpindleOnCW,
-    #[strum(serialize = "M4 (spindle on CCW)")]
+    /// `M4` counterclockwise
+    #[strum(serialize = "M4")]
     SpindleOnCCW,
-    #[strum(serialize = "M5 (spindle off)")]
+    /// `M5` stop
+    #[strum(serialize = "M5")]
     SpindleOff,
 }
 
-#[derive(Debug, Display)]
+/// Coolant switch
+#[derive(Debug, IntoStaticStr)]
 pub enum WaterAction {
-    #[strum(serialize = "M8 (coolant on)")]
+    /// `M8` coolant on
+    #[strum(serialize = "M8")]
     WaterOn,
-    #[strum(serialize = "M9 (coolant off)")]
+    /// `M9` coolant off
+    #[strum(serialize = "M9")]
     WaterOff,
 }
 
-#[derive(Debug, Display)]
+/// Coordinate mode switch
+#[derive(Debug, IntoStaticStr)]
 pub enum CoordSwitch {
-    #[strum(serialize = "G90 (absolute coordinates)")]
+    /// `G90` absolute coordinates
+    #[strum(serialize = "G90")]
     Absolute,
-    #[strum(serialize = "G91 (relative coordinates)")]
+    /// `G91` coordinates relative to the position
+    #[strum(serialize = "G91")]
     Relative,
 }
+
+localized!(
+    Global,
+    Movement,
+    Dwell,
+    SpindleAction,
+    WaterAction,
+    CoordSwitch
+);
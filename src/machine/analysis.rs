@@ -0,0 +1,582 @@
+//! Static analysis of program structure
+
+use super::{
+    actions::is_builtin,
+    program::{CodeBlock, CodeLine, Program, ProgramId, ProgramType},
+};
+use crate::{
+    diagnostic::{Diagnostic, Rule, RuleSet, Severity},
+    gcode::{
+        expr::Expr,
+        words::{Control, Direction, Jump, Target, Word},
+    },
+    i18n::tr,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+/// Problem found without running the program
+#[derive(Debug)]
+pub enum Problem {
+    /// Subprogram is not called from any main program
+    Unused {
+        /// The unused subprogram
+        program: ProgramId,
+    },
+    /// Called subprogram is not defined
+    Missing {
+        /// Name in the call
+        name: String,
+    },
+    /// Subprograms calling each other, the first one is repeated at the end
+    Recursion {
+        /// Programs of the cycle
+        cycle: Vec<ProgramId>,
+    },
+    /// Call nesting deeper than the control allows, main program first
+    TooDeep {
+        /// Levels of the chain
+        depth: usize,
+        /// Levels the control allows
+        limit: usize,
+        /// Programs of the deepest calls
+        chain: Vec<ProgramId>,
+    },
+    /// Executable code after M2 or M17
+    AfterEnd {
+        /// Program with the code
+        program: ProgramId,
+        /// A jump at the line of the finding can skip the end word,
+        /// otherwise the code is never executed
+        skipped: bool,
+    },
+}
+
+impl Problem {
+    /// Rule the problem breaks
+    pub fn rule(&self) -> Rule {
+        match self {
+            Problem::Unused { .. } => Rule::UnusedSubprogram,
+            Problem::Missing { .. } => Rule::MissingSubprogram,
+            Problem::Recursion { .. } => Rule::RecursiveCall,
+            Problem::TooDeep { .. } => Rule::CallTooDeep,
+            Problem::AfterEnd { .. } => Rule::CodeAfterEnd,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let chain = |ids: &[ProgramId]| {
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        };
+        match self {
+            Problem::Unused { program } => tr!("MS0004", program = program).fmt(f),
+            Problem::Missing { name } => tr!("MS0005", name = name).fmt(f),
+            Problem::Recursion { cycle } => tr!("MS0006", chain = chain(cycle)).fmt(f),
+            Problem::TooDeep {
+                depth,
+                limit,
+                chain: ids,
+            } => tr!("MS0007", depth = depth, limit = limit, chain = chain(ids)).fmt(f),
+            Problem::AfterEnd { program, skipped } => {
+                let end = match program {
+                    ProgramId::Main(_) => ProgramType::Main,
+                    ProgramId::Sub(_) => ProgramType::Sub,
+                }
+                .final_word();
+                let key = if *skipped { "MS0008-skipped" } else { "MS0008" };
+                tr!(key, end = end, program = program).fmt(f)
+            }
+        }
+    }
+}
+
+/// Problem with its place in the source
+#[derive(Debug)]
+pub struct Finding {
+    /// Configured severity of the rule
+    pub severity: Severity,
+    /// File of the problem
+    pub file: PathBuf,
+    /// Line numbered from 1
+    pub line: u64,
+    /// What is wrong
+    pub problem: Problem,
+}
+
+impl Finding {
+    /// Diagnostic with the rule of the problem
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            severity: self.severity,
+            ..Diagnostic::new(self.problem.rule(), self.problem.to_string())
+                .at(&self.file, self.line)
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.diagnostic().fmt(f)
+    }
+}
+
+/// Result of the static analysis
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    /// Findings reported as diagnostics
+    #[serde(skip)]
+    pub findings: Vec<Finding>,
+    /// Deepest subprogram nesting, 1 for the main program alone,
+    /// `None` with recursive calls
+    pub max_depth: Option<usize>,
+    /// Levels the control allows, the main program included
+    pub depth_limit: usize,
+    /// Executed blocks of every main program in the worst case,
+    /// `None` if loops have no fixed count
+    pub worst_case: BTreeMap<String, Option<u64>>,
+}
+
+impl Analysis {
+    /// Program levels of Sinumerik 840D, the main program included
+    pub const DEFAULT_DEPTH_LIMIT: usize = 16;
+
+    /// Analyze the program, allowing `depth_limit` program levels
+    ///
+    /// Findings have the severity configured by `rules` and the pragmas.
+    pub fn new(program: &Program, depth_limit: usize, rules: &RuleSet) -> Self {
+        let graph = CallGraph::new(program);
+        let mut findings = Vec::new();
+        let mut finding = |code: &CodeBlock, line, problem: Problem| {
+            let file = program.file_of(code);
+            if let Some(severity) = program.severity(rules, problem.rule(), file) {
+                findings.push(Finding {
+                    severity,
+                    file: file.to_owned(),
+                    line,
+                    problem,
+                })
+            }
+        };
+
+        for (id, node) in &graph.nodes {
+            for call in node.calls.iter().filter(|c| c.target.is_none()) {
+                let name = call.name.clone();
+                finding(node.code, call.line, Problem::Missing { name });
+            }
+            if let Some((line, skipped)) = code_after_end(node.code) {
+                let program = id.clone();
+                finding(node.code, line, Problem::AfterEnd { program, skipped });
+            }
+        }
+
+        let mut walk = Walk {
+            graph: &graph,
+            visits: BTreeMap::new(),
+            path: Vec::new(),
+            cycles: Vec::new(),
+        };
+        let mains = graph
+            .nodes
+            .keys()
+            .filter(|id| matches!(id, ProgramId::Main(_)));
+        let depths = mains.clone().map(|id| walk.depth(id)).collect::<Vec<_>>();
+        let max_depth = depths
+            .into_iter()
+            .try_fold(0, |max, d| d.map(|d| max.max(d)));
+        // Report unused subprograms only if there is something to use them
+        if mains.clone().next().is_some() {
+            for (id, node) in &graph.nodes {
+                if !walk.visits.contains_key(id) {
+                    let program = id.clone();
+                    finding(node.code, node.code.file_line, Problem::Unused { program });
+                }
+            }
+        }
+        // Recursion is an error even in unused subprograms
+        for id in graph.nodes.keys() {
+            walk.depth(id);
+        }
+        for (caller, line, cycle) in std::mem::take(&mut walk.cycles) {
+            finding(
+                graph.nodes[&caller].code,
+                line,
+                Problem::Recursion { cycle },
+            );
+        }
+
+        for id in mains.clone() {
+            let depth = walk.depth(id).unwrap_or(0);
+            if depth > depth_limit {
+                let line = graph.nodes[id].code.file_line;
+                let chain = walk.deepest_chain(id);
+                finding(
+                    graph.nodes[id].code,
+                    line,
+                    Problem::TooDeep {
+                        depth,
+                        limit: depth_limit,
+                        chain,
+                    },
+                );
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        let worst_case = mains
+            .map(|id| (id.name().to_owned(), graph.blocks(id, &walk, &mut blocks)))
+            .collect();
+
+        findings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+        Self {
+            findings,
+            max_depth,
+            depth_limit,
+            worst_case,
+        }
+    }
+
+    /// Check if no errors were found, warnings are allowed
+    pub fn passed(&self) -> bool {
+        self.findings.iter().all(|f| f.severity != Severity::Error)
+    }
+}
+
+impl fmt::Display for Analysis {
+    /// Summary without the findings
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let depth = match self.max_depth {
+            Some(d) => tr!("max-depth", depth = d, limit = self.depth_limit),
+            None => tr!("max-depth-recursive"),
+        };
+        writeln!(f, "{depth}")?;
+        for (name, blocks) in &self.worst_case {
+            let worst = match blocks {
+                Some(n) => tr!("worst-case", program = name, blocks = n),
+                None => tr!("worst-case-unbounded", program = name),
+            };
+            writeln!(f, "{worst}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Subprogram call in a block
+struct Call {
+    /// Index of the calling line
+    idx: usize,
+    line: u64,
+    name: String,
+    /// Called program, `None` if not defined
+    target: Option<ProgramId>,
+    /// Times the subprogram runs
+    passes: u64,
+}
+
+struct Node<'t> {
+    code: &'t CodeBlock,
+    calls: Vec<Call>,
+}
+
+/// Programs with calls between them
+struct CallGraph<'t> {
+    nodes: BTreeMap<ProgramId, Node<'t>>,
+}
+
+impl<'t> CallGraph<'t> {
+    fn new(program: &'t Program) -> Self {
+        let nodes = program
+            .programs()
+            .map(|(id, code)| {
+                let calls = code
+                    .code
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(idx, line)| {
+                        // Repeats are executed after the first pass
+                        let passes = line
+                            .words
+                            .0
+                            .iter()
+                            .find_map(|w| match w {
+                                Word::P(p) => Some(*p as u64 + 1),
+                                _ => None,
+                            })
+                            .unwrap_or(1);
+                        line.words.0.iter().filter_map(move |w| {
+                            let name = match w {
+                                Word::L(n) if !is_builtin(*n) => format!("L{n}"),
+                                Word::Call(name, _) => name.clone(),
+                                _ => return None,
+                            };
+                            Some((idx, line.file_line, name, passes))
+                        })
+                    })
+                    .map(|(idx, line, name, passes)| Call {
+                        idx,
+                        line,
+                        target: program
+                            .sub_program(&name)
+                            .map(|(n, _)| ProgramId::Sub(n.to_owned())),
+                        name,
+                        passes,
+                    })
+                    .collect();
+                (id, Node { code, calls })
+            })
+            .collect();
+        Self { nodes }
+    }
+
+    /// Executed blocks of the program in the worst case, memoized in `known`
+    fn blocks(
+        &self,
+        id: &ProgramId,
+        walk: &Walk,
+        known: &mut BTreeMap<ProgramId, Option<u64>>,
+    ) -> Option<u64> {
+        if let Some(n) = known.get(id) {
+            return *n;
+        }
+        // Recursion makes every count unbounded
+        walk.visits.get(id).copied().flatten()?;
+
+        let node = &self.nodes[id];
+        let mut total = Some(0u64);
+        for (idx, mult) in multipliers(node.code).into_iter().enumerate() {
+            let mut line = Some(1u64);
+            for call in node.calls.iter().filter(|c| c.idx == idx) {
+                let sub = match &call.target {
+                    Some(t) => self.blocks(t, walk, known),
+                    // Missing subprogram stops the program
+                    None => Some(0),
+                };
+                line = add(line, mul(sub, Some(call.passes)));
+            }
+            total = add(total, mul(mult, line));
+        }
+        known.insert(id.clone(), total);
+        total
+    }
+}
+
+/// Depth first search over the call graph
+struct Walk<'g, 't> {
+    graph: &'g CallGraph<'t>,
+    /// Nesting depth of visited programs, `None` while on the path or if recursive
+    visits: BTreeMap<ProgramId, Option<usize>>,
+    path: Vec<ProgramId>,
+    /// Recursive calls found as caller, call line and cycle
+    cycles: Vec<(ProgramId, u64, Vec<ProgramId>)>,
+}
+
+impl Walk<'_, '_> {
+    /// Program levels below and including `id`, `None` if calls are recursive
+    fn depth(&mut self, id: &ProgramId) -> Option<usize> {
+        if let Some(d) = self.visits.get(id) {
+            return *d;
+        }
+        self.visits.insert(id.clone(), None);
+        self.path.push(id.clone());
+
+        let mut deepest = Some(0);
+        for call in &self.graph.nodes[id].calls {
+            let Some(target) = &call.target else {
+                continue;
+            };
+            if let Some(start) = self.path.iter().position(|p| p == target) {
+                let mut cycle = self.path[start..].to_vec();
+                cycle.push(target.clone());
+                self.cycles.push((id.clone(), call.line, cycle));
+                deepest = None;
+                continue;
+            }
+            let d = self.depth(target);
+            deepest = deepest.zip(d).map(|(a, b)| a.max(b));
+        }
+
+        self.path.pop();
+        let depth = deepest.map(|d| d + 1);
+        self.visits.insert(id.clone(), depth);
+        depth
+    }
+
+    /// Chain of calls reaching the maximal depth from `id`
+    fn deepest_chain(&self, id: &ProgramId) -> Vec<ProgramId> {
+        let mut chain = vec![id.clone()];
+        let mut id = id;
+        while let Some(next) = self.graph.nodes[id]
+            .calls
+            .iter()
+            .filter_map(|c| c.target.as_ref())
+            .max_by_key(|t| self.visits.get(*t).copied().flatten())
+        {
+            chain.push(next.clone());
+            id = next;
+        }
+        chain
+    }
+}
+
+fn add(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    Some(a?.saturating_add(b?))
+}
+
+fn mul(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        // Never executed, even if unbounded
+        (Some(0), _) | (_, Some(0)) => Some(0),
+        (a, b) => Some(a?.saturating_mul(b?)),
+    }
+}
+
+/// Times every line runs in loops, `None` if unbounded
+fn multipliers(code: &CodeBlock) -> Vec<Option<u64>> {
+    let constant = |e: &Expr| e.is_constant().then(|| e.eval(&|_| 0.0).ok()).flatten();
+    let mut mult = vec![Some(1); code.code.len()];
+    for (idx, line) in code.code.iter().enumerate() {
+        let backward = line
+            .words
+            .0
+            .iter()
+            .any(|w| matches!(w, Word::Goto(j) if j.direction != Direction::Forward));
+        if backward {
+            // The target is only known at run time
+            return vec![None; code.code.len()];
+        }
+
+        let partner = line.partner.unwrap_or(idx);
+        let (range, factor) = match line.control() {
+            Some(Control::For(_, from, to)) => {
+                let count = constant(from)
+                    .zip(constant(to))
+                    .map(|(a, b)| (b - a + 1.0).max(0.0) as u64);
+                (idx + 1..=partner, count)
+            }
+            Some(Control::While(_)) => (idx + 1..=partner, None),
+            // Jumps back to the label `count` times, once by default
+            Some(Control::Repeat(_, count)) => {
+                let count = match count {
+                    Some(c) => constant(c).map(|c| c.max(0.0) as u64 + 1),
+                    None => Some(2),
+                };
+                (partner..=idx, count)
+            }
+            _ => continue,
+        };
+        for m in &mut mult[range] {
+            *m = mul(*m, factor);
+        }
+    }
+    mult
+}
+
+/// Line of the first executable block after the program end
+fn code_after_end(code: &CodeBlock) -> Option<(u64, bool)> {
+    let end = [
+        ProgramType::Main.final_word(),
+        ProgramType::Sub.final_word(),
+    ];
+    let executable = |line: &&CodeLine| line.executable_code().next().is_some();
+    let end_index = code
+        .code
+        .iter()
+        .position(|line| executable(&line) && line.words.0.iter().any(|w| end.contains(w)))?;
+    // Forward searches find the first target after the jump, so code behind
+    // the end word is live if a jump before it finds its target there
+    let is_target = |line: &CodeLine, jump: &Jump| {
+        line.words.0.iter().any(|w| match (w, &jump.target) {
+            (Word::Label(l), Target::Label(t)) => l == t,
+            (Word::N(n), Target::Number(t)) => n == t,
+            _ => false,
+        })
+    };
+    for (i, line) in code.code[..end_index].iter().enumerate() {
+        for word in &line.words.0 {
+            let Word::Goto(jump) = word else { continue };
+            if jump.direction == Direction::Backward {
+                continue;
+            }
+            let target = code.code[i + 1..].iter().position(|l| is_target(l, jump));
+            if target.is_some_and(|t| i + 1 + t > end_index) {
+                return Some((line.file_line, true));
+            }
+        }
+    }
+    code.code[end_index + 1..]
+        .iter()
+        .find(executable)
+        .map(|line| (line.file_line, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Analysis, Problem};
+    use crate::diagnostic::RuleSet;
+    use crate::{gcode::GCodeFile, machine::Program};
+
+    fn analyze(name: &str, code: &str) -> Analysis {
+        let program = Program::from_file(GCodeFile::parse(name, code).unwrap()).unwrap();
+        Analysis::new(&program, 3, &RuleSet::default())
+    }
+
+    #[test]
+    fn call_graph() {
+        let a = analyze(
+            "graph.ngc",
+            "%MPF1\nL1 P0\nFOO\nM2\nG0 X1\n%SPF1\nL2 P1\nM17\n%SPF2\nL1 P0\nM17\n%SPF3\nM17\n",
+        );
+        let kinds = a
+            .findings
+            .iter()
+            .map(|f| match &f.problem {
+                Problem::Unused { .. } => "unused",
+                Problem::Missing { .. } => "missing",
+                Problem::Recursion { .. } => "recursion",
+                Problem::TooDeep { .. } => "deep",
+                Problem::AfterEnd { .. } => "after end",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["missing", "after end", "recursion", "unused"]);
+        assert_eq!(a.findings[2].line, 10);
+        assert_eq!(a.max_depth, None);
+        assert!(!a.passed());
+    }
+
+    #[test]
+    fn after_end() {
+        let sub = "%_N_PART_SPF\nPROC PART\nGOTOF SKIP\nM17\nSKIP:\nG0 X5\n";
+        let a = analyze("skipped.ngc", &format!("%MPF1\nPART\nM2\n{sub}"));
+        assert!(matches!(
+            a.findings[..],
+            [ref f] if f.line == 6 && matches!(f.problem, Problem::AfterEnd { skipped: true, .. })
+        ));
+        assert!(a.findings[0].to_string().contains("Jump skips M17"));
+
+        // A label before the end word doesn't make the code behind it live
+        let main = "%MPF1\nBACK:\nG0 X1\nGOTOB BACK\nM2\nN10 G0 X2\n";
+        let a = analyze("dead.ngc", main);
+        assert!(matches!(
+            a.findings[..],
+            [ref f] if f.line == 6 && matches!(f.problem, Problem::AfterEnd { skipped: false, .. })
+        ));
+    }
+
+    #[test]
+    fn worst_case() {
+        let a = analyze(
+            "worst.ngc",
+            "%MPF1\nL1 P2\nM2\n%SPF1\nL2 P0\nFOR R1=1 TO 4\nG1 X=R1\nENDFOR\nM17\n%SPF2\nL3 P0\nM17\n%SPF3\nG1 X1\nM17\n",
+        );
+        // Main 2 + 3 passes of (L1: 3 + 2 * 4 loop blocks + L2: 2 + L3: 2)
+        assert_eq!(a.worst_case["MPF1"], Some(2 + 3 * (3 + 8 + 2 + 2)));
+        assert_eq!(a.max_depth, Some(4));
+        assert!(
+            matches!(a.findings[..], [ref f] if matches!(f.problem, Problem::TooDeep { depth: 4, .. }))
+        );
+    }
+}
@@ -1,14 +1,24 @@
 //! The milling machine simulator
 
-use super::actions::{Command, Global, Movement, SpindleAction, WaterAction, CoordSwitch};
+use super::{
+    actions::{Command, CoordSwitch, Global, Movement, SpindleAction, WaterAction},
+    program::Executor,
+    time::TimeModel,
+};
 use crate::{
-    errors::SimpleError,
+    diagnostic::{Diagnostic, Rule, RuleSet, Severity},
+    errors::{Fix, LineError, SimpleError},
+    gcode::words::Words,
+    i18n::tr,
     render::{Circle, Line, Render},
     types::Micrometer,
 };
+use serde::{Deserialize, Serialize, Serializer};
+use std::{any::Any, fmt, path::Path};
 
 /// Machine configuration
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MachineConfig {
     /// Safe Z height to use at beginning and ending of machining cycle
     safe_z: Micrometer,
@@ -20,6 +30,11 @@ pub struct MachineConfig {
     min_feed: u16,
     /// Maximal allowed F value
     max_feed: u16,
+    /// Machine dynamics for time estimation
+    #[serde(skip)]
+    time: TimeModel,
+    /// Levels of the rules checked while running
+    rules: RuleSet,
 }
 
 impl Default for MachineConfig {
@@ -30,15 +45,46 @@ impl Default for MachineConfig {
             max_speed: 5000,
             min_feed: 10,
             max_feed: 400,
+            time: TimeModel::default(),
+            rules: RuleSet::default(),
         }
     }
 }
 
+impl MachineConfig {
+    /// Safe Z height for movements above the part
+    pub fn safe_z(&self) -> Micrometer {
+        self.safe_z
+    }
+
+    /// Configured levels of the rules
+    pub fn rules(&self) -> &RuleSet {
+        &self.rules
+    }
+
+    /// Load configuration from a JSON file, unset values are the defaults
+    pub fn load(path: &Path) -> Result<Self, LineError> {
+        Self::read(path).map_err(|e| e.no_line().in_file(path))
+    }
+
+    fn read(path: &Path) -> Result<Self, SimpleError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| SimpleError(format!("Can't open file: {e}")))?;
+        serde_json::from_str(&text)
+            .map_err(|e| SimpleError(format!("Invalid machine configuration: {e}")))
+    }
+
+    /// The same machine with the given dynamics
+    pub fn with_time_model(self, time: TimeModel) -> Self {
+        Self { time, ..self }
+    }
+}
+
 /// The machine simulator
 #[derive(Debug, Default)]
 pub struct Machine {
     cfg: MachineConfig,
-    render: Option<Box<dyn Render>>,
+    renders: Vec<Box<dyn Render>>,
 
     movement: Option<Movement>,
 
@@ -53,18 +99,79 @@ pub struct Machine {
     water_on: bool,
 
     relative: bool,
+
+    /// Machining time spent so far in seconds
+    elapsed: f64,
+
+    /// Rule violations of the block being executed
+    reported: Vec<Diagnostic>,
+}
+
+/// Read-only snapshot of the modal machine state
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineState {
+    /// X position, `None` until known
+    pub x: Option<Micrometer>,
+    /// Y position, `None` until known
+    pub y: Option<Micrometer>,
+    /// Z position, `None` until known
+    pub z: Option<Micrometer>,
+    /// Modal movement command
+    #[serde(serialize_with = "display")]
+    pub movement: Option<Movement>,
+    /// Spindle speed in revolutions per minute
+    pub speed: Option<u16>,
+    /// Feed in millimeters per minute
+    pub feed: Option<u16>,
+    /// Selected tool
+    pub tool: Option<u8>,
+    /// Spindle is running
+    pub spindle_on: bool,
+    /// Coolant flows
+    pub coolant_on: bool,
+    /// Coordinates are relative with G91
+    pub relative: bool,
+    /// Machining time since program start in seconds
+    pub elapsed: f64,
+}
+
+impl fmt::Display for MachineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn opt(v: Option<impl fmt::Display>) -> String {
+            v.map_or_else(|| "?".into(), |v| v.to_string())
+        }
+        fn on(v: bool) -> String {
+            tr!(if v { "on" } else { "off" })
+        }
+
+        let (x, y, z) = (opt(self.x), opt(self.y), opt(self.z));
+        writeln!(f, "{}", tr!("state-position", x = x, y = y, z = z))?;
+        let movement = opt(self.movement.as_ref());
+        writeln!(f, "{}", tr!("state-movement", movement = movement))?;
+        let (speed, feed, tool) = (opt(self.speed), opt(self.feed), opt(self.tool));
+        writeln!(f, "{}", tr!("state-speed", speed = speed, feed = feed, tool = tool))?;
+        let (spindle, coolant) = (on(self.spindle_on), on(self.coolant_on));
+        writeln!(f, "{}", tr!("state-spindle", spindle = spindle, coolant = coolant))?;
+        let coord = tr!(if self.relative { "relative" } else { "absolute" });
+        writeln!(f, "{}", tr!("state-coordinates", coordinates = coord))?;
+        writeln!(f, "{}", tr!("state-time", seconds = format!("{:.1}", self.elapsed)))
+    }
+}
+
+fn display<S: Serializer>(v: &Option<Movement>, serializer: S) -> Result<S::Ok, S::Error> {
+    v.as_ref().map(ToString::to_string).serialize(serializer)
 }
 
 impl Machine {
-    #[allow(dead_code)]
-    pub fn with_render(render: Option<Box<dyn Render>>) -> Self {
+    /// Machine with the default configuration drawing to `renders`
+    pub fn with_renders(renders: Vec<Box<dyn Render>>) -> Self {
         Self {
-            render,
+            renders,
             ..Self::default()
         }
     }
 
-    #[allow(dead_code)]
+    /// Machine with no renders
     pub fn with_config(cfg: MachineConfig) -> Self {
         Self {
             cfg,
@@ -72,41 +179,142 @@ impl Machine {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn with_render_and_config(render: Option<Box<dyn Render>>, cfg: MachineConfig) -> Self {
+    /// Machine drawing to `renders`
+    pub fn with_renders_and_config(renders: Vec<Box<dyn Render>>, cfg: MachineConfig) -> Self {
         Self {
             cfg,
-            render,
+            renders,
             ..Self::default()
         }
     }
 
-    pub fn finalize(self) -> Option<Box<dyn Render>> {
-        self.render
+    /// Renders after the program ended, to be finalized by the caller
+    pub fn finalize(self) -> Vec<Box<dyn Render>> {
+        self.renders
     }
 
-    pub fn execute_command(&mut self, code: Command) -> Result<(), SimpleError> {
-        if let Some(Global::EndProgram) = code.global {
-            if self.spindle_on {
-                return Err(SimpleError("Ending program with spindle on".into()));
+    /// Estimated machining time since program start in seconds
+    pub fn elapsed(&self) -> f64 {
+        self.elapsed
+    }
+
+    /// Machine configuration
+    pub fn config(&self) -> &MachineConfig {
+        &self.cfg
+    }
+
+    /// Current modal state
+    pub fn state(&self) -> MachineState {
+        MachineState {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            movement: self.movement.clone(),
+            speed: self.speed,
+            feed: self.feed,
+            tool: self.tool,
+            spindle_on: self.spindle_on,
+            coolant_on: self.water_on,
+            relative: self.relative,
+            elapsed: self.elapsed,
+        }
+    }
+
+    /// Access attached render of the given type
+    pub fn render_mut<T: Render>(&mut self) -> Option<&mut T> {
+        self.renders
+            .iter_mut()
+            .find_map(|r| (r.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Run the program taken by `exec` to its end or the first error
+    ///
+    /// Rule violations which don't stop the program go to `report`. After
+    /// each executed block `observe` gets its file line, the raw block, the
+    /// executor and the machine.
+    pub fn run(
+        &mut self,
+        mut exec: Executor,
+        mut report: impl FnMut(Diagnostic),
+        mut observe: impl FnMut(u64, &Words, &Executor, &mut Machine) -> Result<(), LineError>,
+    ) -> Result<(), LineError> {
+        while let Some(cmd) = exec.next() {
+            let (line, cmd) = cmd?;
+            let raw = cmd.raw.clone();
+            self.execute_block(cmd, &exec, line)?.into_iter().for_each(&mut report);
+            observe(line, &raw, &exec, self)?;
+        }
+        Ok(())
+    }
+
+    /// Execute a block taken by `exec` from `line`
+    ///
+    /// Rule violations with error severity stop the program like other
+    /// errors, the others are returned.
+    pub fn execute_block(
+        &mut self,
+        code: Command,
+        exec: &Executor,
+        line: u64,
+    ) -> Result<Vec<Diagnostic>, LineError> {
+        let file = exec.file();
+        let result = self.execute_command(code);
+        let mut reported = Vec::new();
+        for d in std::mem::take(&mut self.reported) {
+            let Some(severity) = exec.program().severity(&self.cfg.rules, d.rule, file) else {
+                continue;
+            };
+            let d = Diagnostic {
+                severity,
+                stack: exec.block_stack(),
+                ..d.at(file, line)
+            };
+            if severity == Severity::Error {
+                return Err(d.into_error());
             }
+            reported.push(d);
+        }
+        result.map_err(|e| {
+            e.at_line(line)
+                .in_file(file)
+                .with_rule(Rule::RuntimeError)
+                .with_stack(exec.block_stack())
+        })?;
+        Ok(reported)
+    }
 
-            if self.water_on {
-                return Err(SimpleError("Ending program with coolant on".into()));
+    /// Execute a block, errors stop the program, rule violations are only
+    /// recorded for `execute_block` to return
+    fn execute_command(&mut self, code: Command) -> Result<(), SimpleError> {
+        if let Some(Global::EndProgram) = code.global {
+            if self.spindle_on {
+                let fix = Fix::insert("M5 M9");
+                self.report(Rule::EndWithSpindleOn, tr!("MS0010"), Some(fix));
+            } else if self.water_on {
+                let fix = Fix::insert("M9");
+                self.report(Rule::EndWithCoolantOn, tr!("MS0011"), Some(fix));
             }
 
             if self.z.unwrap_or(self.cfg.safe_z) < self.cfg.safe_z {
-                return Err(SimpleError("Ending program with too low Z".into()));
+                self.report(Rule::EndBelowSafeZ, tr!("MS0018"), None);
             }
         }
 
+        if code.dwell.is_some() {
+            return self.dwell(&code);
+        }
+
         self.speed.upd(code.speed);
-        self.feed.upd(code.feed);
+        // Feeds are whole millimeters per minute
+        self.feed.upd(code.feed.map(|f| f.round() as u16));
 
         let tool_changed = {
             let tc = self.tool.is_some();
             self.tool.upd(code.tool) && tc
         };
+        if let Some(tool) = code.tool {
+            self.notify(|r| r.tool_change(tool));
+        }
 
         if let Some(csw) = code.coord_switch {
             match csw {
@@ -125,7 +333,7 @@ impl Machine {
             let (x, y, z) = if let (Some(x), Some(y), Some(z)) = (self.x, self.y, self.z) {
                 (x, y, z)
             } else {
-                return Err(SimpleError("Relative coordinates can only be used with fully defined position".into()))
+                return Err(SimpleError(tr!("MS0003-relative")))
             };
             
             Coord {
@@ -145,27 +353,35 @@ impl Machine {
 
         if let Some(sp) = code.spindle_action {
             match sp {
-                SpindleAction::SpindleOnCCW => {
-                    return Err(SimpleError("Trying to start spindle backwards".into()))
-                }
-                SpindleAction::SpindleOnCW => {
+                SpindleAction::SpindleOnCW | SpindleAction::SpindleOnCCW => {
+                    if let SpindleAction::SpindleOnCCW = sp {
+                        self.report(Rule::SpindleBackwards, tr!("MS0014"), None);
+                    }
                     if !self.water_on {
-                        return Err(SimpleError(
-                            "Trying to start spindle without ensuring coolant flow".into(),
-                        ));
+                        let fix = Fix::insert("M8");
+                        self.report(
+                            Rule::SpindleWithoutCoolant,
+                            tr!("MS0012"),
+                            Some(fix),
+                        );
                     }
                     if self.speed.is_none() {
-                        return Err(SimpleError(
-                            "Trying to start spindle without any speed".into(),
-                        ));
+                        self.report(
+                            Rule::SpindleWithoutSpeed,
+                            tr!("MS0013"),
+                            None,
+                        );
                     }
                     self.spindle_on = true;
+                    self.notify(|r| r.spindle(true));
                 }
                 SpindleAction::SpindleOff => {
                     if new_move {
-                        return Err(SimpleError(
-                            "Trying to turn off spindle while moving".into(),
-                        ));
+                        self.report(
+                            Rule::SpindleOffWhileMoving,
+                            tr!("MS0015"),
+                            None,
+                        );
                     }
                     coord.x.prohibit("X")?;
                     coord.y.prohibit("Y")?;
@@ -174,6 +390,7 @@ impl Machine {
                     code.j.prohibit("J")?;
                     self.spindle_on = false;
                     self.speed = None;
+                    self.notify(|r| r.spindle(false));
                 }
             }
         }
@@ -182,17 +399,22 @@ impl Machine {
             match wt {
                 WaterAction::WaterOn => {
                     self.water_on = true;
+                    self.notify(|r| r.coolant(true));
                 }
                 WaterAction::WaterOff => {
                     if new_move {
-                        return Err(SimpleError(
-                            "Trying to turn off coolant while moving".into(),
-                        ));
+                        self.report(
+                            Rule::CoolantOffWhileMoving,
+                            tr!("MS0016"),
+                            None,
+                        );
                     }
                     if self.spindle_on {
-                        return Err(SimpleError(
-                            "Coolant turned off while spindle still running".into(),
-                        ));
+                        self.report(
+                            Rule::CoolantOffWithSpindle,
+                            tr!("MS0017"),
+                            None,
+                        );
                     }
                     coord.x.prohibit("X")?;
                     coord.y.prohibit("Y")?;
@@ -200,6 +422,7 @@ impl Machine {
                     code.i.prohibit("I")?;
                     code.j.prohibit("J")?;
                     self.water_on = false;
+                    self.notify(|r| r.coolant(false));
                 }
             }
         }
@@ -213,6 +436,7 @@ impl Machine {
         if let Some(mv) = mv {
             match mv {
                 Movement::FastLine => {
+                    let from = self.position();
                     if new_move {
                         code.tool.prohibit("D")?;
                     }
@@ -226,18 +450,23 @@ impl Machine {
                         let z = coord.z.require("Z")?;
 
                         if z != self.cfg.safe_z {
-                            return Err(SimpleError(
-                                "First movement should be to safe Z height".into(),
-                            ));
+                            let fix = Fix::insert(format!("G0 Z{}", self.cfg.safe_z));
+                            self.report(
+                                Rule::FirstMoveBelowSafeZ,
+                                tr!("MS0019"),
+                                Some(fix),
+                            );
                         }
                         self.z = Some(z);
                     } else if self.x.is_none() || self.y.is_none() {
                         self.z.upd(coord.z);
                         let z = self.z.unwrap();
                         if z < self.cfg.safe_z {
-                            return Err(SimpleError(
-                                "Unsafe movement without fully defininig the position".into(),
-                            ));
+                            self.report(
+                                Rule::UnsafeMoveFromUnknownPosition,
+                                tr!("MS0020"),
+                                None,
+                            );
                         }
 
                         self.x.upd(coord.x);
@@ -248,6 +477,8 @@ impl Machine {
                         self.z.upd(coord.z);
                     }
 
+                    let delta = self.distance(from);
+                    self.spend(self.cfg.time.rapid_move(delta));
                     self.line(Line::Fast);
                 }
 
@@ -256,10 +487,14 @@ impl Machine {
                     code.i.prohibit("I")?;
                     code.j.prohibit("J")?;
                     self.prepare_cut()?;
+                    let from = self.position();
                     self.x.upd(coord.x);
                     self.y.upd(coord.y);
                     self.z.upd(coord.z);
 
+                    let [dx, dy, dz] = self.distance(from);
+                    let length = dx.hypot(dy).hypot(dz);
+                    self.spend(self.cfg.time.feed_move(length, self.feed.unwrap_or(0)));
                     self.line(Line::Cut);
                 }
 
@@ -297,21 +532,26 @@ impl Machine {
                     bad_tool_change = false;
 
                     if self.spindle_on || self.water_on {
-                        return Err(SimpleError(
-                            "Turn off spindle and coolant before performing tool change".into(),
-                        ));
+                        self.report(
+                            Rule::ToolChangeWithSpindleOn,
+                            tr!("MS0021"),
+                            None,
+                        );
                     }
 
                     if self.z.unwrap_or(self.cfg.safe_z) < self.cfg.safe_z {
-                        return Err(SimpleError(
-                            "Must be high enough to perform tool change".into(),
-                        ));
+                        self.report(
+                            Rule::ToolChangeBelowSafeZ,
+                            tr!("MS0022"),
+                            None,
+                        );
                     }
 
                     self.spindle_on = false;
                     self.water_on = false;
                     self.speed = None;
                     self.feed = None;
+                    self.spend(self.cfg.time.tool_change);
 
                     self.movement = None;
                     self.z = None;
@@ -335,43 +575,118 @@ impl Machine {
         }
 
         if bad_tool_change {
-            return Err(SimpleError("Tool change without stopping".into()))
+            self.report(Rule::ToolChangeWithoutStop, tr!("MS0023"), None);
+        }
+
+        Ok(())
+    }
+
+    fn dwell(&mut self, code: &Command) -> Result<(), SimpleError> {
+        let other = [
+            code.movement.as_ref().map(ToString::to_string),
+            code.spindle_action.as_ref().map(ToString::to_string),
+            code.water_action.as_ref().map(ToString::to_string),
+            code.coord_switch.as_ref().map(ToString::to_string),
+        ];
+        if let Some(other) = other.into_iter().flatten().next() {
+            return Err(SimpleError(tr!(
+                "MS0003-double",
+                first = tr!("G4"),
+                second = other
+            )));
         }
+        code.tool.prohibit("D")?;
+        code.raw_x.prohibit("X")?;
+        code.raw_y.prohibit("Y")?;
+        code.raw_z.prohibit("Z")?;
+        code.i.prohibit("I")?;
+        code.j.prohibit("J")?;
+
+        let seconds = match (code.feed, code.speed) {
+            (Some(f), None) => f,
+            (None, Some(s)) => {
+                // Dwell for a number of spindle revolutions
+                let speed = self.speed.filter(|_| self.spindle_on).ok_or_else(|| {
+                    SimpleError(tr!("MS0003-dwell-revolutions"))
+                })?;
+                s as f64 * 60.0 / speed as f64
+            }
+            (None, None) => return Err(SimpleError(tr!("MS0003-dwell-time"))),
+            (Some(_), Some(_)) => {
+                return Err(SimpleError(tr!("MS0003-dwell-both")))
+            }
+        };
 
+        self.spend(seconds);
         Ok(())
     }
 
-    fn prepare_cut(&self) -> Result<(), SimpleError> {
+    /// Record a rule violation, the block goes on
+    fn report(&mut self, rule: Rule, message: impl Into<String>, fix: Option<Fix>) {
+        let d = Diagnostic::new(rule, message);
+        self.reported.push(match fix {
+            Some(fix) => d.with_fix(fix),
+            None => d,
+        });
+    }
+
+    fn spend(&mut self, seconds: f64) {
+        self.elapsed += seconds;
+        self.notify(|r| r.elapse(seconds));
+    }
+
+    fn notify(&mut self, event: impl Fn(&mut dyn Render)) {
+        for render in &mut self.renders {
+            event(render.as_mut());
+        }
+    }
+
+    fn position(&self) -> [Option<Micrometer>; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    /// Distance travelled along each axis since `from`, unknown axes do not count
+    fn distance(&self, from: [Option<Micrometer>; 3]) -> [f64; 3] {
+        let mut d = [0.0; 3];
+        for ((d, a), b) in d.iter_mut().zip(from).zip(self.position()) {
+            if let (Some(a), Some(b)) = (a, b) {
+                *d = (b - a).to_mm();
+            }
+        }
+        d
+    }
+
+    fn prepare_cut(&mut self) -> Result<(), SimpleError> {
         if !self.spindle_on {
-            return Err(SimpleError("Trying to cut with spindle off".into()));
+            self.report(Rule::CutWithSpindleOff, tr!("MS0024"), None);
         }
 
         if !self.water_on {
-            return Err(SimpleError("Trying to cut without coolant".into()));
+            self.report(Rule::CutWithoutCoolant, tr!("MS0025"), None);
         }
 
         let speed = self.speed.unwrap_or(0);
         if speed < self.cfg.min_speed {
-            return Err(SimpleError(format!("Speed {speed} is too low")));
+            self.report(Rule::SpeedOutOfRange, tr!("MS0026-low", speed = speed), None);
         }
         if speed > self.cfg.max_speed {
-            return Err(SimpleError(format!("Speed {speed} is too high")));
+            self.report(Rule::SpeedOutOfRange, tr!("MS0026-high", speed = speed), None);
         }
 
         let feed = self.feed.unwrap_or(0);
         if feed < self.cfg.min_feed {
-            return Err(SimpleError(format!("Feed {feed} is too low")));
+            self.report(Rule::FeedOutOfRange, tr!("MS0027-low", feed = feed), None);
         }
         if feed > self.cfg.max_feed {
-            return Err(SimpleError(format!("Feed {feed} is too high")));
+            self.report(Rule::FeedOutOfRange, tr!("MS0027-high", feed = feed), None);
         }
 
         if self.x.is_none() || self.y.is_none() || self.z.is_none() {
-            return Err(SimpleError("Trying to cut from undefined position".into()));
+            return Err(SimpleError(tr!("MS0003-cut-unknown")));
         }
 
         if self.tool.is_none() {
-            return Err(SimpleError("Trying to cut with no tool".into()));
+            self.report(Rule::CutWithoutTool, tr!("MS0028"), None);
         }
 
         Ok(())
@@ -383,10 +698,10 @@ impl Machine {
 
     fn line(&mut self, ty: Line) {
         let tool = self.choose_tool();
-        if let (Some(render), Some(x), Some(y), Some(z)) =
-            (&mut self.render, &self.x, &self.y, &self.z)
-        {
-            render.line_to(tool, ty, (*x, *y), *z);
+        if let (Some(x), Some(y), Some(z)) = (self.x, self.y, self.z) {
+            for render in &mut self.renders {
+                render.line_to(tool, ty, (x, y), z);
+            }
         }
     }
 
@@ -413,13 +728,17 @@ impl Machine {
 
         let r_mm = Micrometer::from_mm(r);
         if Micrometer::from_mm(r2) != r_mm {
-            return Err(SimpleError(format!("Circle end point not on the circle (radius = {r_mm}, start at ({start_x}, {start_y})")));
+            return Err(SimpleError(tr!("MS0003-circle-end", radius = r_mm, x = start_x, y = start_y)));
         }
 
-        if let Some(render) = &mut self.render {
+        for render in &mut self.renders {
             render.arc_to(tool, ty, (cx, cy), (x, y));
         }
 
+        let mm = |(x, y): (Micrometer, Micrometer)| (x.to_mm(), y.to_mm());
+        let (_, sweep) = ty.sweep(mm((start_x, start_y)), mm((cx, cy)), mm((x, y)));
+        self.spend(self.cfg.time.feed_move(r * sweep, self.feed.unwrap_or(0)));
+
         self.x = Some(x);
         self.y = Some(y);
         Ok(())
@@ -446,12 +765,12 @@ trait Require<T: Copy + Sized> {
 
     fn require(&self, msg: &str) -> Result<T, SimpleError> {
         self.provided()
-            .ok_or_else(|| SimpleError(format!("Required parameter '{msg}'")))
+            .ok_or_else(|| SimpleError(tr!("MS0003-required", name = msg)))
     }
 
     fn prohibit(&self, msg: &str) -> Result<(), SimpleError> {
         if self.provided().is_some() {
-            Err(SimpleError(format!("Parameter '{msg}' is dangerous here")))
+            Err(SimpleError(tr!("MS0003-dangerous", name = msg)))
         } else {
             Ok(())
         }
@@ -460,6 +779,6 @@ trait Require<T: Copy + Sized> {
 
 impl<T: Copy + Sized> Require<T> for Option<T> {
     fn provided(&self) -> Option<T> {
-        self.as_ref().map(Clone::clone)
+        *self
     }
 }
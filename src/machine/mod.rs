@@ -1,6 +1,15 @@
+//! Program execution and the machine simulator
+
 mod actions;
+mod analysis;
 mod mach;
 mod program;
+mod time;
 
-pub use mach::Machine;
-pub use program::Program;
+pub use actions::{
+    describe, is_builtin, Command, CoordSwitch, Dwell, Global, Movement, SpindleAction, WaterAction,
+};
+pub use analysis::{Analysis, Finding, Problem};
+pub use mach::{Machine, MachineConfig, MachineState};
+pub use program::{backtrace, Executor, Frame, Program, ProgramId};
+pub use time::TimeModel;
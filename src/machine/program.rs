@@ -1,122 +1,723 @@
 //! Program checker and decoder
 
-use super::actions::{Command, Global};
+use super::actions::{is_builtin, Command, Global};
 use crate::{
+    diagnostic::{Rule, RuleSet, Severity},
     errors::{LineError, SimpleError},
     gcode::{
-        words::{MWord, Word, Words},
-        GCodeFile, Line,
+        expr::{Expr, Scope},
+        words::{Control, Direction, Jump, MWord, Target, VarType, Word, Words},
+        GCodeFile, Line, SectionType,
     },
+    i18n::{text, tr},
+};
+use serde::{Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
 };
-use std::{collections::BTreeMap, fmt};
 
 #[derive(Debug)]
-struct CodeLine {
-    file_line: u64,
-    words: Words,
+pub(super) struct CodeLine {
+    pub(super) file_line: u64,
+    pub(super) words: Words,
+    /// Index of the matching line for control statements
+    pub(super) partner: Option<usize>,
 }
 
 impl CodeLine {
-    fn executable_code(&self) -> impl Iterator<Item = Word> + '_ {
+    pub(super) fn executable_code(&self) -> impl Iterator<Item = Word> + '_ {
         self.words.0.iter().filter(|w| w.is_executable()).cloned()
     }
+
+    pub(super) fn control(&self) -> Option<&Control> {
+        self.words.0.iter().find_map(|w| match w {
+            Word::Control(c) => Some(c),
+            _ => None,
+        })
+    }
 }
 
 #[derive(Debug)]
-struct CodeBlock {
+pub(super) struct CodeBlock {
+    /// Index in `Program::files`
+    pub(super) file: usize,
+    pub(super) file_line: u64,
+    pub(super) code: Vec<CodeLine>,
+    /// Name and parameters from PROC
+    proc: Option<Proc>,
+}
+
+/// Subprogram definition
+#[derive(Debug)]
+struct Proc {
     file_line: u64,
-    code: Vec<CodeLine>,
+    name: String,
+    params: Vec<(VarType, String)>,
+}
+
+impl fmt::Display for Proc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let word = Word::Proc(self.name.clone(), self.params.clone());
+        write!(f, "{word} at line {}", self.file_line)
+    }
+}
+
+/// Main program or subprogram name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProgramId {
+    /// `%MPF<n>` is named `MPF<n>`
+    Main(String),
+    /// `%SPF<n>` is named `L<n>` the way it is called
+    Sub(String),
+}
+
+impl ProgramId {
+    /// Program name as used in calls
+    pub fn name(&self) -> &str {
+        match self {
+            ProgramId::Main(n) | ProgramId::Sub(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for ProgramId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = |name: &str, prefix| {
+            name.strip_prefix(prefix)
+                .filter(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+                .map(str::to_owned)
+        };
+        match self {
+            ProgramId::Main(n) => match number(n, "MPF") {
+                Some(n) => write!(f, "%MPF{n}"),
+                None => write!(f, "%_N_{n}_MPF"),
+            },
+            ProgramId::Sub(n) => match number(n, "L") {
+                Some(n) => write!(f, "%SPF{n}"),
+                None => write!(f, "%_N_{n}_SPF"),
+            },
+        }
+    }
+}
+
+impl Serialize for ProgramId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Rules configured by the pragma comments of a file
+fn pragmas(file: &GCodeFile) -> Result<RuleSet, LineError> {
+    let mut rules = RuleSet::default();
+    for (no, line) in file.code() {
+        let Line::Code(words) = line else {
+            continue;
+        };
+        for w in &words.0 {
+            if let Word::LineComment(c) = w {
+                if let Some(pragma) = RuleSet::parse_pragma(c) {
+                    let pragma = pragma.map_err(|e| e.at_line(no).with_rule(Rule::ProgramError))?;
+                    rules = rules.merged(&pragma);
+                }
+            }
+        }
+    }
+    Ok(rules)
 }
 
 /// Decoded program
 #[derive(Debug)]
 pub struct Program {
-    main_programs: BTreeMap<u8, CodeBlock>,
-    sub_programs: BTreeMap<u8, CodeBlock>,
+    /// Source files the programs come from
+    files: Vec<PathBuf>,
+    /// Source text of every file as loaded
+    texts: Vec<String>,
+    /// Rules configured by pragma comments of every file
+    pragmas: Vec<RuleSet>,
+    main_programs: BTreeMap<String, CodeBlock>,
+    sub_programs: BTreeMap<String, CodeBlock>,
+    /// Subprogram names by PROC name if they differ
+    procs: BTreeMap<String, String>,
 }
 
 impl Program {
+    /// Decode a single file with no subprogram search
     pub fn from_file(file: GCodeFile) -> Result<Self, LineError> {
+        Self::from_files(vec![file], &[])
+    }
+
+    /// Assemble from several files, subprograms called but not defined in
+    /// them are loaded from `<name>.SPF` files in `search_path`
+    pub fn from_files(files: Vec<GCodeFile>, search_path: &[PathBuf]) -> Result<Self, LineError> {
+        let mut program = Program {
+            files: Vec::new(),
+            texts: Vec::new(),
+            pragmas: Vec::new(),
+            main_programs: BTreeMap::new(),
+            sub_programs: BTreeMap::new(),
+            procs: BTreeMap::new(),
+        };
+        for file in files {
+            program.add_file(file)?;
+        }
+
+        // Names looked up already, the file found may define something else
+        let mut searched = BTreeSet::new();
+        loop {
+            let missing = program
+                .called()
+                .filter(|name| !program.is_defined(name) && !searched.contains(name))
+                .collect::<BTreeSet<_>>();
+            if missing.is_empty() {
+                break;
+            }
+            for name in missing {
+                let found = find_file(search_path, &name);
+                searched.insert(name);
+                if let Some(path) = found.filter(|p| !program.files.contains(p)) {
+                    program.add_file(GCodeFile::load(path)?)?;
+                }
+            }
+        }
+
+        program
+            .resolve_procs()
+            .map_err(|e| e.with_rule(Rule::ProgramError))?;
+        Ok(program)
+    }
+
+    /// Assemble `file` with the subprogram `libraries`, subprograms
+    /// defined in none of them are searched in `search_path`, then in the
+    /// directory of `file`
+    pub fn with_libraries(
+        file: GCodeFile,
+        libraries: &[PathBuf],
+        search_path: &[PathBuf],
+    ) -> Result<Self, LineError> {
+        let mut search_path = search_path.to_vec();
+        search_path.extend(file.path().parent().map(Path::to_owned));
+        let files = std::iter::once(Ok(file))
+            .chain(libraries.iter().map(GCodeFile::load))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::from_files(files, &search_path)
+    }
+
+    /// Decode programs of a file, checking each of them
+    fn add_file(&mut self, file: GCodeFile) -> Result<(), LineError> {
+        let path = file.path().to_owned();
+        self.files.push(path.clone());
+        self.texts.push(file.text());
+        self.pragmas
+            .push(pragmas(&file).map_err(|e| e.in_file(&path))?);
+        self.read_file(file)
+            .map_err(|e| e.in_file(&path).with_rule(Rule::ProgramError))
+    }
+
+    fn read_file(&mut self, file: GCodeFile) -> Result<(), LineError> {
         enum Prog {
             Unknown,
-            Main(u8),
-            Sub(u8),
+            Main(String),
+            Sub(String),
+            /// Archive section which is not a program
+            Other,
         }
+        let index = self.files.len() - 1;
+        // Program files may contain a single program with no header
+        let implicit = implicit_program(file.path());
         let mut program = Prog::Unknown;
-        let mut main_programs = BTreeMap::<u8, CodeBlock>::new();
-        let mut sub_programs = BTreeMap::<u8, CodeBlock>::new();
-        let mut def_line = 0;
+        let mut defined = Vec::new();
         for (file_line, code_line) in file.into_code() {
-            let code = match code_line {
-                Line::Empty => continue,
-                Line::MainProgram(n) => {
-                    def_line = file_line;
-                    program = Prog::Main(n);
+            let id = match code_line {
+                Line::Empty | Line::Path(_) | Line::Data(_) => continue,
+                Line::MainProgram(n) => ProgramId::Main(format!("MPF{n}")),
+                Line::SubProgram(n) => ProgramId::Sub(format!("L{n}")),
+                Line::Section(name, SectionType::Main) => ProgramId::Main(name),
+                Line::Section(name, SectionType::Sub) => ProgramId::Sub(name),
+                Line::Section(_, SectionType::Other(_)) => {
+                    program = Prog::Other;
                     continue;
                 }
-                Line::SubProgram(n) => {
-                    def_line = file_line;
-                    program = Prog::Sub(n);
+                Line::Code(words) => {
+                    if let (Prog::Unknown, Some(id)) = (&program, &implicit) {
+                        self.define(id, index, file_line)?;
+                        defined.push(id.clone());
+                        program = match id.clone() {
+                            ProgramId::Main(n) => Prog::Main(n),
+                            ProgramId::Sub(n) => Prog::Sub(n),
+                        };
+                    }
+                    let code = CodeLine {
+                        file_line,
+                        words,
+                        partner: None,
+                    };
+                    let block = match &program {
+                        Prog::Unknown => {
+                            return Err(SimpleError(tr!("MS0002-no-program")).at_line(file_line))
+                        }
+                        Prog::Other => continue,
+                        Prog::Main(n) => self.main_programs.get_mut(n),
+                        Prog::Sub(n) => self.sub_programs.get_mut(n),
+                    };
+                    block.expect("Bug: program not created").code.push(code);
                     continue;
                 }
-                Line::Code(words) => CodeLine { file_line, words },
             };
 
-            let entry = match program {
-                Prog::Unknown => {
-                    return Err(SimpleError("Code line with no program".into()).at_line(file_line))
+            self.define(&id, index, file_line)?;
+            defined.push(id.clone());
+            program = match id {
+                ProgramId::Main(n) => Prog::Main(n),
+                ProgramId::Sub(n) => Prog::Sub(n),
+            };
+        }
+
+        for id in defined {
+            let (code, ty) = match &id {
+                ProgramId::Main(n) => (self.main_programs.get_mut(n), ProgramType::Main),
+                ProgramId::Sub(n) => (self.sub_programs.get_mut(n), ProgramType::Sub),
+            };
+            let code = code.expect("Bug: program not created");
+            check_end(id.name(), code, &ty)?;
+            check_jump_targets(code)?;
+            check_structure(code)?;
+            let proc = find_proc(code)?;
+            match (ty, proc) {
+                (ProgramType::Main, Some(p)) => {
+                    return Err(SimpleError(tr!("MS0002-proc-in-main")).at_line(p.file_line))
                 }
-                Prog::Main(n) => main_programs.entry(n),
-                Prog::Sub(n) => sub_programs.entry(n),
+                (_, proc) => code.proc = proc,
+            }
+        }
+        Ok(())
+    }
+
+    /// Create an empty program, it must not be defined yet
+    fn define(&mut self, id: &ProgramId, file: usize, file_line: u64) -> Result<(), LineError> {
+        let (programs, name) = match id {
+            ProgramId::Main(n) => (&mut self.main_programs, n),
+            ProgramId::Sub(n) => (&mut self.sub_programs, n),
+        };
+        if let Some(other) = programs.get(name) {
+            let message = if other.file == file {
+                tr!("MS0002-defined", id = id, line = other.file_line)
+            } else {
+                tr!(
+                    "MS0002-defined-in",
+                    id = id,
+                    line = other.file_line,
+                    file = self.files[other.file].display()
+                )
             };
+            return Err(SimpleError(message).at_line(file_line));
+        }
+        programs.insert(
+            name.clone(),
+            CodeBlock {
+                file,
+                file_line,
+                code: Vec::new(),
+                proc: None,
+            },
+        );
+        Ok(())
+    }
 
-            entry
-                .or_insert(CodeBlock {
-                    file_line: def_line,
-                    code: Vec::new(),
-                })
-                .code
-                .push(code);
+    /// Register PROC names differing from the program names
+    fn resolve_procs(&mut self) -> Result<(), LineError> {
+        for (name, code) in &self.sub_programs {
+            let Some(p) = code.proc.as_ref().filter(|p| &p.name != name) else {
+                continue;
+            };
+            let other = match self.sub_programs.get(&p.name) {
+                Some(_) => Some(ProgramId::Sub(p.name.clone())),
+                None => self
+                    .procs
+                    .insert(p.name.clone(), name.clone())
+                    .map(ProgramId::Sub),
+            };
+            if let Some(other) = other {
+                return Err(
+                    SimpleError(tr!("MS0002-proc-defined", name = p.name, other = other))
+                        .at_line(p.file_line)
+                        .in_file(&self.files[code.file]),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of all subprograms called anywhere
+    fn called(&self) -> impl Iterator<Item = String> + '_ {
+        self.main_programs
+            .values()
+            .chain(self.sub_programs.values())
+            .flat_map(|p| &p.code)
+            .flat_map(|line| &line.words.0)
+            .filter_map(|w| match w {
+                Word::L(n) if !is_builtin(*n) => Some(format!("L{n}")),
+                Word::Call(name, _) => Some(name.clone()),
+                _ => None,
+            })
+    }
+
+    /// Check if subprogram is defined under the name or PROC name
+    fn is_defined(&self, name: &str) -> bool {
+        self.sub_programs.contains_key(name)
+            || self
+                .sub_programs
+                .values()
+                .any(|p| p.proc.as_ref().is_some_and(|p| p.name == name))
+    }
+
+    /// File and line where subprogram `name` is defined
+    pub fn definition(&self, name: &str) -> Option<(&Path, u64)> {
+        self.sub_program(name)
+            .map(|(_, code)| (self.file_of(code), code.file_line))
+    }
+
+    /// Severity of a rule violated in `file`, `None` if the rule is off
+    ///
+    /// Pragmas of the file override the configuration.
+    pub fn severity(&self, config: &RuleSet, rule: Rule, file: &Path) -> Option<Severity> {
+        let pragmas = self
+            .files
+            .iter()
+            .position(|f| f == file)
+            .and_then(|idx| self.pragmas[idx].level(rule));
+        match pragmas.or(config.level(rule)) {
+            Some(level) => level.severity(),
+            None => Some(rule.default_severity()),
         }
+    }
 
-        check_last_executable(&main_programs, ProgramType::Main)?;
-        check_last_executable(&sub_programs, ProgramType::Sub)?;
+    /// Source files the programs come from
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
 
-        Ok(Program {
-            main_programs,
-            sub_programs,
+    /// Source text of one of the `files()` exactly as loaded
+    pub fn text(&self, path: &Path) -> Option<&str> {
+        let idx = self.files.iter().position(|p| p == path)?;
+        Some(&self.texts[idx])
+    }
+
+    /// File lines of code blocks in every program with its file
+    pub fn code_lines(&self) -> impl Iterator<Item = (ProgramId, &Path, Vec<u64>)> {
+        self.programs().map(|(id, p)| {
+            let lines = p.code.iter().map(|l| l.file_line).collect();
+            (id, self.file_of(p), lines)
         })
     }
 
-    pub fn execute(&self, idx: Option<u8>) -> Result<Executor, SimpleError> {
-        (if let Some(idx) = idx {
+    /// Number of code blocks in all programs
+    pub fn block_count(&self) -> usize {
+        self.main_programs
+            .values()
+            .chain(self.sub_programs.values())
+            .map(|p| p.code.len())
+            .sum()
+    }
+
+    /// Start main program `name`, the first one in the file by default
+    pub fn execute(&self, name: Option<&str>) -> Result<Executor<'_>, SimpleError> {
+        (if let Some(name) = name {
             self.main_programs
-                .get(&idx)
-                .ok_or_else(|| SimpleError(format!("Program %{idx} not found")))
+                .get_key_value(name)
+                .ok_or_else(|| SimpleError(tr!("MS0002-not-found", name = name)))
         } else {
             self.main_programs
-                .first_key_value()
-                .ok_or_else(|| SimpleError("No main programs found".into()))
-                .map(|(_k, v)| v)
+                .iter()
+                .min_by_key(|(_, p)| (p.file, p.file_line))
+                .ok_or_else(|| SimpleError(tr!("MS0002-no-main")))
         })
-        .map(|p| &p.code[..])
-        .map(|p| Executor::start(&self.sub_programs, p))
+        .map(|(k, p)| Executor::start(self, ProgramId::Main(k.clone()), p))
+    }
+
+    /// All programs, main programs first
+    pub(super) fn programs(&self) -> impl Iterator<Item = (ProgramId, &CodeBlock)> {
+        let main = self.main_programs.iter();
+        let sub = self.sub_programs.iter();
+        main.map(|(n, p)| (ProgramId::Main(n.clone()), p))
+            .chain(sub.map(|(n, p)| (ProgramId::Sub(n.clone()), p)))
+    }
+
+    /// File the program comes from
+    pub(super) fn file_of(&self, code: &CodeBlock) -> &Path {
+        &self.files[code.file]
+    }
+
+    /// Subprogram by name or PROC name
+    pub(super) fn sub_program(&self, name: &str) -> Option<(&str, &CodeBlock)> {
+        let name = self.procs.get(name).map_or(name, String::as_str);
+        self.sub_programs
+            .get_key_value(name)
+            .map(|(n, p)| (n.as_str(), p))
     }
 }
 
+/// Program defined by a headerless `.MPF` or `.SPF` file
+fn implicit_program(path: &Path) -> Option<ProgramId> {
+    let name = path.file_stem()?.to_str()?.to_ascii_uppercase();
+    let ext = path.extension()?.to_str()?.to_ascii_uppercase();
+    match ext.as_str() {
+        "MPF" => Some(ProgramId::Main(name)),
+        "SPF" => Some(ProgramId::Sub(name)),
+        _ => None,
+    }
+}
+
+/// Subprogram file for `name` in the search path
+fn find_file(search_path: &[PathBuf], name: &str) -> Option<PathBuf> {
+    let lower = name.to_ascii_lowercase();
+    search_path
+        .iter()
+        .flat_map(|dir| {
+            [
+                dir.join(format!("{name}.SPF")),
+                dir.join(format!("{name}.spf")),
+                dir.join(format!("{lower}.spf")),
+            ]
+        })
+        .find(|p| p.is_file())
+}
+
 #[derive(Debug)]
 struct StackItem<'t> {
+    id: ProgramId,
+    call_line: Option<u64>,
+    /// Repeats left after the current one
     repeats: u16,
+    /// Number of runs requested by the call
+    runs: u32,
     code: &'t [CodeLine],
-    full_code: &'t [CodeLine],
+    /// Index in `Program::files`
+    file: usize,
+    /// Index of the next line in `code`
+    pc: usize,
+    /// Control structures entered, innermost last
+    nesting: Vec<Nest>,
+    /// Local variables and parameters
+    locals: BTreeMap<String, Local>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Local {
+    ty: VarType,
+    value: f64,
+}
+
+/// Values visible from a stack frame
+struct Env<'a> {
+    params: &'a BTreeMap<u8, f64>,
+    locals: &'a BTreeMap<String, Local>,
+}
+
+impl Scope for Env<'_> {
+    fn param(&self, n: u8) -> f64 {
+        self.params.get(&n).copied().unwrap_or(0.0)
+    }
+
+    fn var(&self, name: &str) -> Result<f64, SimpleError> {
+        self.locals
+            .get(name)
+            .map(|l| l.value)
+            .ok_or_else(|| SimpleError(tr!("MS0003-undefined", name = name)))
+    }
+}
+
+/// Block with expressions evaluated
+struct Evaluated<'t> {
+    words: Vec<Word>,
+    /// Jump to be taken
+    jump: Option<&'t Jump>,
+    /// Subprogram name and arguments
+    call: Option<(&'t str, Vec<f64>)>,
+}
+
+/// Control structure being executed, spanning lines `start..=end`
+#[derive(Debug)]
+enum Nest {
+    If {
+        start: usize,
+        end: usize,
+    },
+    While {
+        start: usize,
+        end: usize,
+    },
+    For {
+        start: usize,
+        end: usize,
+        var: u8,
+        last: f64,
+    },
+    /// `start` is the label line, `end` is the REPEAT line
+    Repeat {
+        start: usize,
+        end: usize,
+        left: u16,
+    },
+}
+
+impl Nest {
+    /// Check if execution at line `idx` stays inside the structure
+    fn contains(&self, idx: usize) -> bool {
+        match *self {
+            Nest::If { start, end } | Nest::While { start, end } | Nest::For { start, end, .. } => {
+                start < idx && idx <= end
+            }
+            Nest::Repeat { start, end, .. } => start <= idx && idx <= end,
+        }
+    }
 }
 
 impl<'t> StackItem<'t> {
-    fn new(code: &'t [CodeLine], repeats: u16) -> Self {
+    fn new(id: ProgramId, call_line: Option<u64>, block: &'t CodeBlock, repeats: u16) -> Self {
         Self {
-            code,
+            id,
+            call_line,
             repeats,
-            full_code: code,
+            runs: repeats as u32 + 1,
+            code: &block.code,
+            file: block.file,
+            pc: 0,
+            nesting: Vec::new(),
+            locals: BTreeMap::new(),
+        }
+    }
+
+    fn frame(&self) -> Frame {
+        Frame {
+            program: self.id.clone(),
+            call_line: self.call_line,
+            repeats_left: self.repeats,
+            runs: self.runs,
+        }
+    }
+
+    /// Leave the innermost control structure closed by `ctl`
+    fn leave(&mut self, ctl: &Control) -> Result<Nest, SimpleError> {
+        let ok = matches!(
+            (self.nesting.last(), ctl),
+            (Some(Nest::If { .. }), Control::Else | Control::EndIf)
+                | (Some(Nest::While { .. }), Control::EndWhile)
+                | (Some(Nest::For { .. }), Control::EndFor)
+        );
+        if ok {
+            Ok(self.nesting.pop().expect("Bug: nesting is empty"))
+        } else {
+            Err(SimpleError(tr!("MS0003-outside", control = ctl)))
+        }
+    }
+
+    /// Jump to line `idx`, leaving control structures not containing it
+    fn goto(&mut self, idx: usize) {
+        self.nesting.retain(|n| n.contains(idx));
+        self.pc = idx;
+    }
+
+    /// Check if the last taken line is the last one of the program
+    fn at_end(&self) -> bool {
+        self.pc >= self.code.len()
+    }
+
+    /// Index of the line the jump leads to, `from` is the index of the jump line
+    fn find(&self, jump: &Jump, from: usize) -> Result<usize, SimpleError> {
+        let found = |i: &usize| {
+            self.code[*i]
+                .words
+                .0
+                .iter()
+                .any(|w| match (w, &jump.target) {
+                    (Word::Label(l), Target::Label(t)) => l == t,
+                    (Word::N(n), Target::Number(t)) => n == t,
+                    _ => false,
+                })
+        };
+        let mut forward = from + 1..self.code.len();
+        let mut backward = (0..=from).rev();
+        match jump.direction {
+            Direction::Forward => forward.find(found),
+            Direction::Backward => backward.find(found),
+            Direction::Any => forward.find(found).or_else(|| backward.find(found)),
+        }
+        .ok_or_else(|| {
+            let key = match jump.direction {
+                Direction::Forward => "MS0003-target-forward",
+                Direction::Backward => "MS0003-target-backward",
+                Direction::Any => "MS0003-target-any",
+            };
+            SimpleError(tr!(key, target = jump.target, program = self.id))
+        })
+    }
+}
+
+/// Entry of the subprogram call stack
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Frame {
+    /// Running program
+    pub program: ProgramId,
+    /// File line of the call, `None` for the main program
+    pub call_line: Option<u64>,
+    /// Repeats left after the current one
+    pub repeats_left: u16,
+    /// Number of runs requested by the call
+    pub runs: u32,
+}
+
+impl Frame {
+    /// Number of the current run, starting at 1
+    pub fn run(&self) -> u32 {
+        self.runs - self.repeats_left as u32
+    }
+
+    /// Backtrace line like "in L3 (repeat 2 of 4) called from line 17 of %MPF1",
+    /// `None` for the main program
+    pub fn called_from(&self, caller: &Frame) -> Option<String> {
+        let line = self.call_line?;
+        let program = self.program.name();
+        Some(if self.runs > 1 {
+            tr!(
+                "frame-repeat",
+                program = program,
+                run = self.run(),
+                runs = self.runs,
+                line = line,
+                caller = caller.program
+            )
+        } else {
+            tr!(
+                "frame",
+                program = program,
+                line = line,
+                caller = caller.program
+            )
+        })
+    }
+}
+
+/// Backtrace lines of a call stack, innermost call first
+pub fn backtrace(stack: &[Frame]) -> impl Iterator<Item = String> + '_ {
+    stack
+        .windows(2)
+        .rev()
+        .filter_map(|w| w[1].called_from(&w[0]))
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.call_line {
+            Some(l) => tr!(
+                "frame-repeats-left",
+                program = self.program,
+                line = l,
+                repeats = self.repeats_left
+            )
+            .fmt(f),
+            None => self.program.fmt(f),
         }
     }
 }
@@ -125,70 +726,435 @@ impl<'t> StackItem<'t> {
 #[derive(Debug)]
 pub struct Executor<'t> {
     stack: Vec<StackItem<'t>>,
-    sub_programs: &'t BTreeMap<u8, CodeBlock>,
+    program: &'t Program,
+    params: BTreeMap<u8, f64>,
+    /// Maximal number of blocks to execute
+    budget: u64,
+    executed: u64,
+    /// File of the last taken block
+    file: usize,
+    /// Source of the last taken block
+    source: Option<&'t Words>,
+    /// Stack depth when the last block was taken
+    taken_depth: usize,
+    /// Frame of the subprogram the last taken block returned from
+    returned: Option<Frame>,
 }
 
 impl<'t> Executor<'t> {
-    fn start(sub_programs: &'t BTreeMap<u8, CodeBlock>, code: &'t [CodeLine]) -> Self {
+    /// Default block budget, enough for any sane program
+    pub const DEFAULT_BUDGET: u64 = 1_000_000;
+
+    fn start(program: &'t Program, id: ProgramId, code: &'t CodeBlock) -> Self {
+        Self {
+            stack: vec![StackItem::new(id, None, code, 0)],
+            program,
+            params: BTreeMap::new(),
+            budget: Self::DEFAULT_BUDGET,
+            executed: 0,
+            file: code.file,
+            source: None,
+            taken_depth: 1,
+            returned: None,
+        }
+    }
+
+    /// Stop with an error after executing `blocks` blocks
+    pub fn with_budget(self, blocks: u64) -> Self {
         Self {
-            stack: vec![StackItem::new(code, 0)],
-            sub_programs,
+            budget: blocks,
+            ..self
+        }
+    }
+
+    /// Next block to be executed as file line and code
+    pub fn peek(&self) -> Option<(u64, &'t Words)> {
+        let top = self.stack.last()?;
+        let line = top.code.get(top.pc)?;
+        Some((line.file_line, &line.words))
+    }
+
+    /// Program being executed
+    pub fn program(&self) -> &'t Program {
+        self.program
+    }
+
+    /// File of the last taken block
+    pub fn file(&self) -> &'t Path {
+        &self.program.files[self.file]
+    }
+
+    /// Source of the last taken block, expressions not evaluated
+    pub fn source(&self) -> Option<&'t Words> {
+        self.source
+    }
+
+    /// Values of R parameters assigned so far
+    pub fn parameters(&self) -> &BTreeMap<u8, f64> {
+        &self.params
+    }
+
+    /// Local variables of the running subprogram
+    pub fn locals(&self) -> impl Iterator<Item = (&str, VarType, f64)> {
+        self.stack
+            .last()
+            .into_iter()
+            .flat_map(|s| &s.locals)
+            .map(|(name, l)| (name.as_str(), l.ty, l.value))
+    }
+
+    /// Current subprogram call stack, main program first
+    pub fn call_stack(&self) -> Vec<Frame> {
+        self.stack.iter().map(StackItem::frame).collect()
+    }
+
+    /// Call stack the last taken block ran in, main program first
+    ///
+    /// Unlike `call_stack()` it has no frame of a subprogram the block
+    /// called and keeps the one it returned from.
+    pub fn block_stack(&self) -> Vec<Frame> {
+        let mut stack = self.call_stack();
+        match &self.returned {
+            Some(frame) => {
+                stack.truncate(self.taken_depth - 1);
+                stack.push(frame.clone());
+            }
+            None => stack.truncate(self.taken_depth),
+        }
+        stack
+    }
+
+    /// Current subprogram nesting depth, 1 in the main program
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn scope(&self) -> Env<'_> {
+        Env {
+            params: &self.params,
+            locals: &self.stack.last().expect("Bug: stack is empty").locals,
+        }
+    }
+
+    fn local(&mut self, name: &str) -> Result<&mut Local, SimpleError> {
+        self.stack
+            .last_mut()
+            .expect("Bug: stack is empty")
+            .locals
+            .get_mut(name)
+            .ok_or_else(|| SimpleError(tr!("MS0003-undefined", name = name)))
+    }
+
+    /// Evaluate expressions into literal words, collect the jump if taken
+    fn evaluate(&mut self, line: &'t CodeLine) -> Result<Evaluated<'t>, SimpleError> {
+        let mut words = Vec::with_capacity(line.words.0.len());
+        let mut jump = None;
+        let mut call = None;
+        for word in &line.words.0 {
+            match word {
+                Word::R(n, e) => {
+                    let value = e.eval(&self.scope())?;
+                    self.params.insert(*n, value);
+                    words.push(Word::R(*n, Expr::Num(value)));
+                }
+                Word::Computed(a, e) => words.push(Word::with_value(*a, e.eval(&self.scope())?)?),
+                Word::Assign(name, e) => {
+                    let value = e.eval(&self.scope())?;
+                    let local = self.local(name)?;
+                    local.value = local.ty.check(value).map_err(|e| {
+                        SimpleError(tr!("MS0003-value-for", error = e.0, name = name))
+                    })?;
+                    words.push(Word::Assign(name.clone(), Expr::Num(value)));
+                }
+                Word::Def(ty, vars) => {
+                    let mut init = Vec::with_capacity(vars.len());
+                    for (name, e) in vars {
+                        let value = match e {
+                            Some(e) => e.eval(&self.scope())?,
+                            None => 0.0,
+                        };
+                        let value = ty.check(value).map_err(|e| {
+                            SimpleError(tr!("MS0003-value-for", error = e.0, name = name))
+                        })?;
+                        self.stack
+                            .last_mut()
+                            .expect("Bug: stack is empty")
+                            .locals
+                            .insert(name.clone(), Local { ty: *ty, value });
+                        init.push((name.clone(), Some(Expr::Num(value))));
+                    }
+                    words.push(Word::Def(*ty, init));
+                }
+                Word::Call(name, args) => {
+                    if call.is_some() {
+                        return Err(SimpleError(tr!("MS0003-calls")));
+                    }
+                    let values = args
+                        .iter()
+                        .flatten()
+                        .map(|a| a.eval(&self.scope()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    words.push(Word::Call(
+                        name.clone(),
+                        args.as_ref()
+                            .map(|_| values.iter().map(|v| Expr::Num(*v)).collect()),
+                    ));
+                    call = Some((name.as_str(), values));
+                }
+                Word::Goto(j) => {
+                    if jump.is_some() {
+                        return Err(SimpleError(tr!("MS0003-jumps")));
+                    }
+                    let taken = match &j.condition {
+                        Some(c) => c.eval(&self.scope())? != 0.0,
+                        None => true,
+                    };
+                    if taken {
+                        jump = Some(j);
+                    }
+                    words.push(word.clone());
+                }
+                w => words.push(w.clone()),
+            }
+        }
+        Ok(Evaluated { words, jump, call })
+    }
+
+    /// Enter subprogram `n` with `args` for its PROC parameters
+    fn enter(
+        &mut self,
+        name: &str,
+        call_line: u64,
+        args: &[f64],
+        repeats: u16,
+    ) -> Result<(), SimpleError> {
+        let (id, sub) = self
+            .program
+            .sub_program(name)
+            .ok_or_else(|| SimpleError(tr!("MS0003-sub-not-found", name = name)))?;
+        let id = ProgramId::Sub(id.to_owned());
+        let params = match &sub.proc {
+            Some(p) => &p.params[..],
+            None => &[][..],
+        };
+        let definition = || match &sub.proc {
+            Some(p) => tr!("MS0003-defined-as", definition = p),
+            None => tr!("MS0003-defined-at", id = id, line = sub.file_line),
+        };
+
+        if args.len() != params.len() {
+            return Err(SimpleError(format!(
+                "{}\n  {}",
+                tr!(
+                    "MS0003-arguments",
+                    name = name,
+                    expected = params.len(),
+                    got = args.len()
+                ),
+                definition()
+            )));
+        }
+        let mut locals = BTreeMap::new();
+        for ((ty, param), value) in params.iter().zip(args) {
+            let value = ty.check(*value).map_err(|e| {
+                SimpleError(format!(
+                    "{}\n  {}",
+                    tr!("MS0003-argument", error = e.0, param = param, name = name),
+                    definition()
+                ))
+            })?;
+            locals.insert(param.clone(), Local { ty: *ty, value });
+        }
+
+        self.stack.push(StackItem {
+            locals,
+            ..StackItem::new(id, Some(call_line), sub, repeats)
+        });
+        Ok(())
+    }
+
+    /// Execute structured control flow statement
+    fn control(&mut self, line: &CodeLine, ctl: &Control) -> Result<(), SimpleError> {
+        // Expressions are evaluated before the frame is changed
+        let value = |e: &Expr| e.eval(&self.scope());
+        let condition = match ctl {
+            Control::If(c) | Control::While(c) => value(c)? != 0.0,
+            _ => false,
+        };
+        let range = match ctl {
+            Control::For(_, from, to) => (value(from)?, value(to)?),
+            _ => (0.0, 0.0),
+        };
+        let params = &mut self.params;
+        let top = self.stack.last_mut().expect("Bug: stack is empty");
+        let idx = top.pc - 1;
+        let partner = line.partner.expect("Bug: unmatched control statement");
+
+        match ctl {
+            Control::If(_) => {
+                // Skip to ELSE or past ENDIF
+                let other = &top.code[partner];
+                let end = match other.control() {
+                    Some(Control::Else) => other.partner.expect("Bug: unmatched ELSE"),
+                    _ => partner,
+                };
+                if condition {
+                    top.nesting.push(Nest::If { start: idx, end });
+                } else {
+                    if partner != end {
+                        top.nesting.push(Nest::If { start: idx, end });
+                    }
+                    top.pc = partner + 1;
+                }
+            }
+            Control::Else => {
+                top.leave(ctl)?;
+                top.pc = partner + 1;
+            }
+            Control::EndIf => {
+                top.leave(ctl)?;
+            }
+            Control::While(_) => {
+                if condition {
+                    top.nesting.push(Nest::While {
+                        start: idx,
+                        end: partner,
+                    });
+                } else {
+                    top.pc = partner + 1;
+                }
+            }
+            Control::EndWhile => {
+                top.leave(ctl)?;
+                top.pc = partner;
+            }
+            Control::For(var, ..) => {
+                let (first, last) = range;
+                params.insert(*var, first);
+                if first <= last {
+                    top.nesting.push(Nest::For {
+                        start: idx,
+                        end: partner,
+                        var: *var,
+                        last,
+                    });
+                } else {
+                    top.pc = partner + 1;
+                }
+            }
+            Control::EndFor => {
+                let Some(&Nest::For {
+                    start, var, last, ..
+                }) = top.nesting.last()
+                else {
+                    return Err(top.leave(ctl).expect_err("Bug: FOR not entered"));
+                };
+                let next = params.get(&var).copied().unwrap_or(0.0) + 1.0;
+                params.insert(var, next);
+                if next <= last {
+                    top.pc = start + 1;
+                } else {
+                    top.leave(ctl)?;
+                }
+            }
+            Control::Repeat(_, count) => match top.nesting.last_mut() {
+                Some(Nest::Repeat { end, left, .. }) if *end == idx => {
+                    if *left > 0 {
+                        *left -= 1;
+                        top.pc = partner;
+                    } else {
+                        top.nesting.pop();
+                    }
+                }
+                _ => {
+                    let count = match count {
+                        Some(c) => c.eval(&Env {
+                            params,
+                            locals: &top.locals,
+                        })?,
+                        None => 1.0,
+                    };
+                    if !(0.0..=u16::MAX as f64).contains(&count) || count.fract() != 0.0 {
+                        return Err(SimpleError(tr!("MS0003-repeats", count = count)));
+                    }
+                    if count >= 1.0 {
+                        top.nesting.push(Nest::Repeat {
+                            start: partner,
+                            end: idx,
+                            left: count as u16 - 1,
+                        });
+                        top.pc = partner;
+                    }
+                }
+            },
         }
+        Ok(())
     }
 
-    fn exec(&mut self, line: &CodeLine) -> Result<Command, SimpleError> {
-        let cmd = Command::from_gcode(&line.words.0)?;
+    fn exec(&mut self, line: &'t CodeLine) -> Result<Command, SimpleError> {
+        let Evaluated { words, jump, call } = self.evaluate(line)?;
+        let cmd = Command::from_gcode(&words)?;
+
+        if let Some((name, _)) = &call {
+            if let Some(g) = &cmd.global {
+                return Err(SimpleError(tr!("MS0003-call-with", name = name, word = g)));
+            }
+            if let Some(jump) = jump {
+                return Err(SimpleError(tr!(
+                    "MS0003-call-with-jump",
+                    name = name,
+                    jump = jump
+                )));
+            }
+        }
+        if let Some(jump) = jump {
+            if let Some(g) = &cmd.global {
+                return Err(SimpleError(tr!("MS0003-jump-with", jump = jump, word = g)));
+            }
+            let top = self.stack.last_mut().expect("Bug: stack is empty");
+            let target = top.find(jump, top.pc - 1)?;
+            top.goto(target);
+        }
+        if let Some(ctl) = line.control() {
+            self.control(line, ctl)?;
+        }
+        if let Some((name, args)) = call {
+            self.enter(name, line.file_line, &args, cmd.p.unwrap_or(0))?;
+        }
 
         if let Some(g) = &cmd.global {
             match g {
                 Global::CallSub(n) => {
-                    let sub = self
-                        .sub_programs
-                        .get(n)
-                        .ok_or_else(|| SimpleError(format!("Subroutine L{n} not found")))?;
-                    let repeats = cmd.p.ok_or(SimpleError(format!(
-                        "Repeats count for subroutine L{n} not defined"
-                    )))?;
-                    self.stack.push(StackItem::new(&sub.code, repeats));
+                    let repeats = cmd
+                        .p
+                        .ok_or(SimpleError(tr!("MS0003-repeats-undefined", n = n)))?;
+                    self.enter(&format!("L{n}"), line.file_line, &[], repeats)?;
                     Ok(cmd)
                 }
                 Global::ReturnSub => {
                     if self.stack.len() <= 1 {
-                        Err(SimpleError(
-                            "Subroutine return (M17) without subroutine call".into(),
-                        ))
-                    } else if !self
-                        .stack
-                        .last()
-                        .expect("Bug: stack is empty")
-                        .code
-                        .is_empty()
-                    {
-                        Err(SimpleError(
-                            "Subroutine return (M17) is not the last statement".into(),
-                        ))
+                        Err(SimpleError(tr!("MS0003-return-without-call")))
+                    } else if !self.stack.last().expect("Bug: stack is empty").at_end() {
+                        Err(SimpleError(tr!("MS0003-return-not-last")))
                     } else {
                         let p = self.stack.pop().expect("Bug: popping from empty stack");
+                        self.returned = Some(p.frame());
                         if p.repeats > 0 {
-                            let repeats = p.repeats - 1;
-                            self.stack.push(StackItem::new(p.full_code, repeats));
+                            self.stack.push(StackItem {
+                                repeats: p.repeats - 1,
+                                pc: 0,
+                                nesting: Vec::new(),
+                                ..p
+                            });
                         }
                         Ok(cmd)
                     }
                 }
                 Global::EndProgram => {
                     if self.stack.len() > 1 {
-                        Err(SimpleError("Program end (M2) in a subroutine".into()))
-                    } else if !self
-                        .stack
-                        .last()
-                        .expect("Bug: stack is empty")
-                        .code
-                        .is_empty()
-                    {
-                        Err(SimpleError(
-                            "Program end (M2) is not the last statement".into(),
-                        ))
+                        Err(SimpleError(tr!("MS0003-end-in-sub")))
+                    } else if !self.stack.last().expect("Bug: stack is empty").at_end() {
+                        Err(SimpleError(tr!("MS0003-end-not-last")))
                     } else {
                         Ok(cmd)
                     }
@@ -198,47 +1164,68 @@ impl<'t> Executor<'t> {
             Ok(cmd)
         }
     }
+
+    /// Check that a jump didn't take execution past the end word of the
+    /// program, `cmd` is the block just executed
+    fn check_running(&self, cmd: &Command) -> Result<(), SimpleError> {
+        let top = self.stack.last().expect("Bug: stack is empty");
+        if !top.at_end() || matches!(cmd.global, Some(Global::EndProgram)) {
+            return Ok(());
+        }
+        let ty = match top.id {
+            ProgramId::Main(_) => ProgramType::Main,
+            ProgramId::Sub(_) => ProgramType::Sub,
+        };
+        Err(SimpleError(tr!(
+            "MS0003-past-end",
+            program = top.id,
+            word = ty.final_word()
+        )))
+    }
 }
 
 impl Iterator for Executor<'_> {
     type Item = Result<(u64, Command), LineError>;
 
-    #[allow(unstable_name_collisions)] // TODO for take_first() - remove as it gets stabilized
     fn next(&mut self) -> Option<Self::Item> {
-        let code = self
+        let top = self
             .stack
             .last_mut()
-            .expect("Bug: execution stack is empty")
-            .code
-            .take_first()?;
-        Some(
-            self.exec(code)
-                .map(|c| (code.file_line, c))
-                .map_err(|e| e.at_line(code.file_line)),
-        )
-    }
-}
-
-// TODO remove as slice::take_first() gets stabilized
-trait TakeFirst<T> {
-    fn take_first<'t>(self: &mut &'t Self) -> Option<&'t T>;
-}
-
-impl<T> TakeFirst<T> for [T] {
-    fn take_first<'t>(self: &mut &'t Self) -> Option<&'t T> {
-        let (first, rem) = self.split_first()?;
-        *self = rem;
-        Some(first)
+            .expect("Bug: execution stack is empty");
+        let code = top.code.get(top.pc)?;
+        self.file = top.file;
+        self.source = Some(&code.words);
+        let file = self.program.files[top.file].as_path();
+        if self.executed >= self.budget {
+            return Some(Err(SimpleError(tr!("MS0003-budget", budget = self.budget))
+                .at_line(code.file_line)
+                .in_file(file)
+                .with_rule(Rule::RuntimeError)
+                .with_stack(self.call_stack())));
+        }
+        top.pc += 1;
+        self.executed += 1;
+        self.taken_depth = self.stack.len();
+        self.returned = None;
+        let result = self
+            .exec(code)
+            .and_then(|cmd| self.check_running(&cmd).map(|()| cmd));
+        Some(result.map(|c| (code.file_line, c)).map_err(|e| {
+            e.at_line(code.file_line)
+                .in_file(file)
+                .with_rule(Rule::RuntimeError)
+                .with_stack(self.block_stack())
+        }))
     }
 }
 
-enum ProgramType {
+pub(super) enum ProgramType {
     Main,
     Sub,
 }
 
 impl ProgramType {
-    fn final_word(&self) -> Word {
+    pub(super) fn final_word(&self) -> Word {
         use ProgramType::*;
         Word::M(match self {
             Main => MWord::M2,
@@ -250,35 +1237,457 @@ impl ProgramType {
 impl fmt::Display for ProgramType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use ProgramType::*;
-        let s = match self {
-            Main => "Main program",
-            Sub => "Subprogram",
+        let key = match self {
+            Main => "main-program",
+            Sub => "subprogram",
         };
-        s.fmt(f)
+        text(key, &[]).fmt(f)
     }
 }
 
-fn check_last_executable(
-    programs: &BTreeMap<u8, CodeBlock>,
-    ty: ProgramType,
-) -> Result<(), LineError> {
-    for (p, code) in programs {
-        let c = code
-            .code
-            .iter()
-            .rev()
-            .map(|line| line.executable_code().collect())
-            .skip_while(Vec::is_empty)
-            .take(1)
-            .last();
+/// Check that the program end word stands alone in a block,
+/// code after it is left to the analysis
+fn check_end(p: &str, code: &CodeBlock, ty: &ProgramType) -> Result<(), LineError> {
+    let w = ty.final_word();
+    let c = code
+        .code
+        .iter()
+        .rev()
+        .map(|line| line.executable_code().collect::<Vec<_>>())
+        .find(|c| c.contains(&w));
+
+    if c != Some(vec![w.clone()]) {
+        return Err(
+            SimpleError(tr!("MS0002-end", kind = ty, program = p, word = w))
+                .at_line(code.file_line),
+        );
+    }
+
+    Ok(())
+}
 
-        let w = ty.final_word();
-        if c != Some(vec![w.clone()]) {
-            return Err(
-                SimpleError(format!("{ty} #{p} does not end with {w}")).at_line(code.file_line)
-            );
+fn check_jump_targets(code: &CodeBlock) -> Result<(), LineError> {
+    let mut labels = BTreeMap::new();
+    for line in &code.code {
+        for word in &line.words.0 {
+            if let Word::Label(l) = word {
+                if let Some(first) = labels.insert(l, line.file_line) {
+                    return Err(
+                        SimpleError(tr!("MS0002-label-defined", label = l, line = first))
+                            .at_line(line.file_line),
+                    );
+                }
+            }
+        }
+    }
+
+    for line in &code.code {
+        for word in &line.words.0 {
+            let defined = match word {
+                Word::Goto(Jump {
+                    target: Target::Label(l),
+                    ..
+                }) => labels.contains_key(l),
+                Word::Goto(Jump {
+                    target: Target::Number(n),
+                    ..
+                }) => code.code.iter().any(|c| c.words.0.contains(&Word::N(*n))),
+                _ => true,
+            };
+            if !defined {
+                return Err(SimpleError(tr!("MS0002-target-undefined", word = word))
+                    .at_line(line.file_line));
+            }
         }
     }
 
     Ok(())
 }
+
+/// Check if `close` ends the structure opened with `open`
+fn closes(open: &Control, close: &Control) -> bool {
+    use Control::*;
+    matches!(
+        (open, close),
+        (If(_), Else | EndIf) | (Else, EndIf) | (While(_), EndWhile) | (For(..), EndFor)
+    )
+}
+
+/// Match control structure statements and remember the partner lines
+fn check_structure(code: &mut CodeBlock) -> Result<(), LineError> {
+    // Opened structures as line index and statement
+    let mut open: Vec<(usize, Control)> = Vec::new();
+    let mut partners = Vec::new();
+    for (idx, line) in code.code.iter().enumerate() {
+        let Some(ctl) = line.control() else {
+            continue;
+        };
+        let err = |msg: String| Err(SimpleError(msg).at_line(line.file_line));
+        if line.executable_code().count() > 1 {
+            return err(tr!("MS0002-control-alone", control = ctl));
+        }
+
+        use Control::*;
+        match ctl {
+            If(_) | While(_) | For(..) => open.push((idx, ctl.clone())),
+            Else | EndIf | EndWhile | EndFor => match open.pop() {
+                Some((start, o)) if closes(&o, ctl) => {
+                    partners.push((start, idx));
+                    match ctl {
+                        // ELSE is an end for IF and a start for ENDIF
+                        Else => open.push((idx, Else)),
+                        EndWhile | EndFor | EndIf => partners.push((idx, start)),
+                        _ => (),
+                    }
+                }
+                Some((start, o)) => {
+                    return err(tr!(
+                        "MS0002-control-mismatch",
+                        control = ctl,
+                        open = o,
+                        line = code.code[start].file_line
+                    ))
+                }
+                None => return err(tr!("MS0002-control-unopened", control = ctl)),
+            },
+            Repeat(label, _) => {
+                let start = code.code[..idx].iter().rposition(|l| {
+                    l.words
+                        .0
+                        .iter()
+                        .any(|w| matches!(w, Word::Label(l) if l == label))
+                });
+                match start {
+                    Some(start) => partners.push((idx, start)),
+                    None => return err(tr!("MS0002-repeat-label", label = label, control = ctl)),
+                }
+            }
+        }
+    }
+
+    if let Some((idx, ctl)) = open.pop() {
+        return Err(SimpleError(tr!("MS0002-control-unclosed", control = ctl))
+            .at_line(code.code[idx].file_line));
+    }
+    for (idx, partner) in partners {
+        code.code[idx].partner = Some(partner);
+    }
+
+    Ok(())
+}
+
+/// PROC definition, it must be the first statement of the program
+fn find_proc(code: &CodeBlock) -> Result<Option<Proc>, LineError> {
+    let mut proc = None;
+    let mut first = true;
+    for line in &code.code {
+        for word in &line.words.0 {
+            match word {
+                Word::Proc(name, params) if first && proc.is_none() => {
+                    proc = Some(Proc {
+                        file_line: line.file_line,
+                        name: name.clone(),
+                        params: params.clone(),
+                    });
+                }
+                Word::Proc(..) => {
+                    return Err(SimpleError(tr!("MS0002-proc-first")).at_line(line.file_line))
+                }
+                Word::N(_) | Word::Comment(_) => (),
+                _ => first = false,
+            }
+        }
+        if proc.is_some() {
+            first = false;
+        }
+    }
+    Ok(proc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Program;
+    use crate::{
+        errors::LineError,
+        gcode::{words::Word, GCodeFile},
+        types::Micrometer,
+    };
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    fn program(text: &str) -> Program {
+        Program::from_file(GCodeFile::parse("test.mpf", text).unwrap()).unwrap()
+    }
+
+    /// File lines executed up to the first error
+    fn run(text: &str) -> (Vec<u64>, Option<LineError>) {
+        let program = program(text);
+        let mut lines = Vec::new();
+        for block in program.execute(None).unwrap().with_budget(1000) {
+            match block {
+                Ok((line, _)) => lines.push(line),
+                Err(e) => return (lines, Some(e)),
+            }
+        }
+        (lines, None)
+    }
+
+    #[test]
+    fn computed_out_of_range() {
+        for block in ["G0 X=1E20 Y0", "R1=1E200 G0 Y=R1*R1", "G2 X0 I=-1E13"] {
+            let (lines, e) = run(&format!("%MPF1\nG0 X0 Y0\n{block}\nM2\n"));
+            assert_eq!(lines, [2], "{block}");
+            let e = e.unwrap();
+            assert_eq!(e.line(), Some(3));
+            assert!(e.message().contains("out of range"), "{block}: {e}");
+        }
+        assert_eq!(run("%MPF1\nG0 X=1E6\nM2\n").0, [2, 3]);
+    }
+
+    #[test]
+    fn jumps() {
+        let text = "\
+%MPF1
+R1=0
+GOTOF SKIP
+G0 X1
+SKIP:
+R1=R1+1
+IF R1<3 GOTOB SKIP
+N100 G0 X2
+M2
+";
+        let (lines, e) = run(text);
+        assert!(e.is_none());
+        assert_eq!(lines, [2, 3, 5, 6, 7, 5, 6, 7, 5, 6, 7, 8, 9]);
+
+        // The label exists, but only behind the jump
+        let (lines, e) = run("%MPF1\nBACK:\nG0 X0\nGOTOF BACK\nM2\n");
+        assert_eq!(lines, [2, 3]);
+        let e = e.unwrap();
+        assert_eq!(e.line(), Some(4));
+        assert!(e.message().contains("searching forward"), "{e}");
+    }
+
+    #[test]
+    fn jump_past_end() {
+        let text = "\
+%MPF1
+PART
+M5 M9
+M2
+%_N_PART_SPF
+PROC PART
+GOTOF SKIP
+M17
+SKIP:
+G0 X5
+";
+        let (lines, e) = run(text);
+        assert_eq!(lines, [2, 6, 7, 9]);
+        let e = e.unwrap();
+        assert_eq!(e.line(), Some(10));
+        assert!(
+            e.message().contains("past the end of %_N_PART_SPF, M17"),
+            "{e}"
+        );
+
+        let (lines, e) = run("%MPF1\nGOTOF DONE\nM2\nDONE:\n");
+        assert_eq!(lines, [2]);
+        let e = e.unwrap();
+        assert_eq!(e.line(), Some(4));
+        assert!(e.message().contains("M2 was skipped"), "{e}");
+    }
+
+    #[test]
+    fn nested_loops() {
+        let text = "\
+%MPF1
+R2=0
+FOR R1=1 TO 2
+  WHILE R2<R1
+    R2=R2+1
+  ENDWHILE
+  IF R1==1
+    G0 X1
+  ELSE
+    G0 X2
+  ENDIF
+ENDFOR
+M2
+";
+        let (lines, e) = run(text);
+        assert!(e.is_none());
+        #[rustfmt::skip]
+        let expected = [
+            2, 3,
+            4, 5, 6, 4, 7, 8, 9, 12,
+            4, 5, 6, 4, 7, 10, 11, 12,
+            13,
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn repeats() {
+        let text = "\
+%MPF1
+START:
+G0 X1
+REPEAT START P=2
+L10 P3
+M2
+%_N_L10_SPF
+G0 X2
+M17
+";
+        let (lines, e) = run(text);
+        assert!(e.is_none());
+        // P counts the repeats after the first run
+        #[rustfmt::skip]
+        let expected = [
+            2, 3, 4, 2, 3, 4, 2, 3, 4,
+            5, 8, 9, 8, 9, 8, 9, 8, 9,
+            6,
+        ];
+        assert_eq!(lines, expected);
+
+        let (lines, e) = run("%MPF1\nSTART:\nREPEAT START P=-1\nM2\n");
+        assert_eq!(lines, [2]);
+        assert_eq!(e.unwrap().line(), Some(3));
+    }
+
+    #[test]
+    fn budget() {
+        let text = "%MPF1\nLOOP:\nG0 X1\nGOTOB LOOP\nM2\n";
+        let program = program(text);
+        let exec = program.execute(None).unwrap().with_budget(5);
+        let blocks: Vec<_> = exec.take(6).collect();
+        let lines: Vec<_> = blocks[..5].iter().map(|b| b.as_ref().unwrap().0).collect();
+        assert_eq!(lines, [2, 3, 4, 2, 3]);
+        let e = blocks[5].as_ref().unwrap_err();
+        assert!(e.message().contains("budget of 5"), "{e}");
+    }
+
+    #[test]
+    fn procedures() {
+        let sub = "\
+%_N_POCKET_SPF
+PROC POCKET(REAL DEPTH, INT COUNT)
+DEF REAL HALF=DEPTH/2, LEFT
+LEFT=COUNT
+WHILE LEFT>0
+  G1 Z=-HALF F100
+  LEFT=LEFT-1
+ENDWHILE
+M17
+";
+        let (lines, e) = run(&format!("%MPF1\nPOCKET(4, 2)\nM2\n{sub}"));
+        assert!(e.is_none());
+        assert_eq!(lines, [2, 5, 6, 7, 8, 9, 10, 11, 8, 9, 10, 11, 8, 12, 3]);
+        // The local initialized from the parameter sets the depth
+        let program = program(&format!("%MPF1\nPOCKET(4, 2)\nM2\n{sub}"));
+        let depth = program
+            .execute(None)
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|(line, _)| *line == 9)
+            .map(|(_, cmd)| cmd.raw.0);
+        assert_eq!(depth.unwrap()[1], Word::Z(Micrometer(-2000)));
+
+        let (lines, e) = run(&format!("%MPF1\nPOCKET(4)\nM2\n{sub}"));
+        assert!(lines.is_empty());
+        let e = e.unwrap();
+        assert_eq!(e.line(), Some(2));
+        assert!(e.message().contains("expects 2 arguments, got 1"), "{e}");
+
+        let (_, e) = run(&format!("%MPF1\nPOCKET(4, 1.5)\nM2\n{sub}"));
+        let e = e.unwrap();
+        assert_eq!(e.line(), Some(2));
+        assert!(e.message().contains("parameter COUNT"), "{e}");
+    }
+
+    /// Empty directory for the files of a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("millsim-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, text: &str) -> PathBuf {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+        path.to_owned()
+    }
+
+    #[test]
+    fn find_file() {
+        let dir = temp_dir("find");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        let upper = write(&first.join("L5.SPF"), "");
+        let lower = write(&second.join("l5.spf"), "");
+        let named = write(&second.join("pocket.spf"), "");
+        let search = [first.clone(), second.clone()];
+        assert_eq!(super::find_file(&search, "L5"), Some(upper));
+        assert_eq!(super::find_file(&search[1..], "L5"), Some(lower));
+        assert_eq!(super::find_file(&search, "POCKET"), Some(named));
+        assert_eq!(super::find_file(&search, "L6"), None);
+        assert_eq!(super::find_file(&[], "L5"), None);
+    }
+
+    #[test]
+    fn search_path() {
+        let dir = temp_dir("search");
+        let main = write(&dir.join("main.mpf"), "%MPF1\nL5 P0\nL6 P0\nM2\n");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        let l5 = write(&first.join("L5.SPF"), "%SPF5\nL8 P0\nM17\n");
+        let other_l5 = write(&second.join("L5.SPF"), "%SPF5\nG0 X2\nM17\n");
+        // Subprograms found are searched for the subprograms they call
+        let l8 = write(&second.join("L8.SPF"), "G0 X8\nM17\n");
+        let l6 = write(&second.join("L6.SPF"), "G0 X6\nM17\n");
+        let beside = write(&dir.join("L6.SPF"), "G0 X6\nM17\n");
+
+        let load = |search: &[PathBuf]| {
+            let program =
+                Program::with_libraries(GCodeFile::load(&main).unwrap(), &[], search).unwrap();
+            let found = |name| program.definition(name).map(|(p, _)| p.to_owned());
+            (found("L5"), found("L6"), found("L8"))
+        };
+        // The first directory defining a subprogram wins, the one of the
+        // program comes last
+        let search = [first.clone(), second.clone()];
+        assert_eq!(load(&search), (Some(l5), Some(l6.clone()), Some(l8)));
+        let search = [second.clone(), first.clone()];
+        assert_eq!(load(&search), (Some(other_l5), Some(l6), None));
+        assert_eq!(load(&[]), (None, Some(beside), None));
+    }
+
+    #[test]
+    fn libraries() {
+        let dir = temp_dir("libraries");
+        let main = write(&dir.join("main.mpf"), "%MPF1\nL5 P0\nM2\n");
+        let text = "%SPF5\nG0 X1\nM17\n%SPF7\nM17\n";
+        let libraries = [write(&dir.join("lib").join("parts.spf"), text)];
+        let lib = libraries[0].as_path();
+        let search = [dir.join("include")];
+        write(&search[0].join("L5.SPF"), "%SPF5\nG0 X2\nM17\n");
+
+        // Libraries are loaded first, the search path only fills the gaps
+        let file = GCodeFile::load(&main).unwrap();
+        let program = Program::with_libraries(file, &libraries, &search).unwrap();
+        assert_eq!(program.definition("L5").unwrap(), (lib, 1));
+        assert_eq!(program.definition("L7").unwrap(), (lib, 4));
+        assert_eq!(program.files().len(), 2);
+
+        // Subprograms defined twice in loaded files are an error
+        let main = write(&dir.join("twice.mpf"), "%MPF1\nL5 P0\nM2\n%SPF5\nM17\n");
+        let file = GCodeFile::load(&main).unwrap();
+        let e = Program::with_libraries(file, &libraries, &search).unwrap_err();
+        assert_eq!(e.file(), Some(lib));
+        assert_eq!(e.line(), Some(1));
+        assert!(e.message().contains("already defined at line 4"), "{e}");
+    }
+}
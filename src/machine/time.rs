@@ -0,0 +1,91 @@
+//! Machining time model
+
+/// Machine dynamics used to estimate machining time
+#[derive(Debug, Clone)]
+pub struct TimeModel {
+    /// Rapid traverse rate of X, Y and Z axes in mm/min
+    pub rapid: [f64; 3],
+    /// Axis acceleration in mm/s²
+    pub acceleration: f64,
+    /// Duration of a tool change in seconds
+    pub tool_change: f64,
+}
+
+impl Default for TimeModel {
+    fn default() -> Self {
+        Self {
+            rapid: [5000.0, 5000.0, 2000.0],
+            acceleration: 500.0,
+            tool_change: 10.0,
+        }
+    }
+}
+
+impl TimeModel {
+    /// Time in seconds to travel `length` mm with `feed` mm/min
+    pub fn feed_move(&self, length: f64, feed: u16) -> f64 {
+        trapezoid(length, feed as f64 / 60.0, self.acceleration)
+    }
+
+    /// Time in seconds of a rapid move, axes moving independently
+    pub fn rapid_move(&self, delta: [f64; 3]) -> f64 {
+        delta
+            .iter()
+            .zip(self.rapid)
+            .map(|(d, rate)| trapezoid(d.abs(), rate / 60.0, self.acceleration))
+            .fold(0.0, f64::max)
+    }
+}
+
+/// Time of a move that accelerates to `speed`, cruises and stops again
+fn trapezoid(length: f64, speed: f64, acceleration: f64) -> f64 {
+    if length <= 0.0 || speed <= 0.0 {
+        return 0.0;
+    }
+    if acceleration <= 0.0 {
+        return length / speed;
+    }
+
+    let ramps = speed * speed / acceleration;
+    if length >= ramps {
+        length / speed + speed / acceleration
+    } else {
+        // Triangular profile, never reaching full speed
+        2.0 * (length / acceleration).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimeModel;
+
+    #[test]
+    fn feed_time() {
+        let tm = TimeModel {
+            acceleration: 0.0,
+            ..TimeModel::default()
+        };
+        assert_eq!(tm.feed_move(100.0, 600), 10.0);
+        assert_eq!(tm.feed_move(0.0, 600), 0.0);
+
+        let tm = TimeModel {
+            acceleration: 10.0,
+            ..TimeModel::default()
+        };
+        // 10 mm/s, 1 s to accelerate and 1 s to stop
+        assert_eq!(tm.feed_move(100.0, 600), 11.0);
+        // Never reaches full speed
+        assert_eq!(tm.feed_move(2.5, 600), 1.0);
+    }
+
+    #[test]
+    fn rapid_time() {
+        let tm = TimeModel {
+            rapid: [600.0, 600.0, 60.0],
+            acceleration: 0.0,
+            tool_change: 0.0,
+        };
+        assert_eq!(tm.rapid_move([10.0, -5.0, 0.0]), 1.0);
+        assert_eq!(tm.rapid_move([10.0, -5.0, 2.0]), 2.0);
+    }
+}
@@ -1,54 +1,379 @@
-mod errors;
-mod gcode;
-mod machine;
-mod render;
-mod types;
-
-use errors::{LineError, SimpleError};
-use gcode::GCodeFile;
-use machine::{Machine, Program};
-use render::{svg::Svg, Render};
-use std::{io::Write, path::Path};
+mod commands;
+mod debug;
+mod flatten;
+mod lsp;
+mod report;
+mod search;
+
+use crate::debug::Breakpoint;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args, Parser, Subcommand, ValueEnum,
+};
+use millsim::{
+    errors::{LineError, SimpleError},
+    gcode::FormatOptions,
+    machine::{Analysis, Executor, MachineConfig, TimeModel},
+    render::heightmap::{HeightMap, Stock},
+    types::Micrometer,
+    Lang,
+};
+use std::{io::Write, path::PathBuf, process::ExitCode};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-fn run(path: impl AsRef<Path>, out_path: Option<impl AsRef<Path>>) -> Result<(), LineError> {
-    let file = GCodeFile::load(path)?;
+/// Milling machine G-code simulator
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    options: Options,
+}
+
+/// Options of program loading and execution
+#[derive(Debug, Args)]
+struct Options {
+    /// Stop after executing this many blocks to catch endless loops
+    #[arg(long, global = true, default_value_t = Executor::DEFAULT_BUDGET)]
+    max_blocks: u64,
+    /// Search subprograms not defined in the loaded files in this directory
+    /// before the directory of the G-code file
+    #[arg(short = 'I', long = "include", global = true, value_name = "DIR")]
+    search_path: Vec<PathBuf>,
+    /// Load subprograms from this file too
+    #[arg(long = "lib", global = true, value_name = "FILE")]
+    libraries: Vec<PathBuf>,
+    /// Machine configuration in JSON with limits and rule levels
+    #[arg(long, global = true, value_name = "FILE")]
+    machine: Option<PathBuf>,
+    /// Language of the messages, taken from LANG by default
+    #[arg(long, global = true, value_parser = parse_lang())]
+    lang: Option<Lang>,
+}
+
+impl Options {
+    fn machine(&self) -> Result<MachineConfig, LineError> {
+        match &self.machine {
+            Some(p) => MachineConfig::load(p),
+            None => Ok(MachineConfig::default()),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Simulate a program and render the tool path
+    Run {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// SVG output file, "-" for standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Save height map of the machined part, "-" for standard output
+        #[arg(long)]
+        heightmap: Option<PathBuf>,
+        /// Write machine state after every block to JSON Lines file, "-" for standard output
+        #[arg(long)]
+        trace: Option<PathBuf>,
+        #[command(flatten)]
+        part: PartArgs,
+    },
+    /// Compare the machined part against a reference program or height map
+    Compare {
+        /// G-code file to grade, "-" for standard input
+        file: PathBuf,
+        /// Reference G-code file or height map (.hmap)
+        reference: PathBuf,
+        /// Allowed deviation in millimeters
+        #[arg(long, default_value = "0.05", value_parser = parse_mm)]
+        tolerance: Micrometer,
+        /// Write difference heat map to SVG file, "-" for standard output
+        #[arg(long)]
+        diff: Option<PathBuf>,
+        #[command(flatten)]
+        part: PartArgs,
+    },
+    /// Estimate machining time per block, per subprogram call and in total
+    Time {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        #[command(flatten)]
+        time: TimeArgs,
+    },
+    /// Count executions of every source line
+    Coverage {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// Write annotated sources to HTML file, "-" for standard output
+        #[arg(long)]
+        html: Option<PathBuf>,
+    },
+    /// Show machining time per source line and subprogram, most expensive first
+    Profile {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// Show only that many most expensive lines
+        #[arg(long)]
+        top: Option<usize>,
+        #[command(flatten)]
+        time: TimeArgs,
+    },
+    /// Write a single main program with all subprogram calls unrolled
+    Flatten {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// Output file, standard output by default or for "-"
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Replace R parameters and expressions with their values
+        #[arg(long)]
+        evaluate: bool,
+    },
+    /// Rewrite G-code files in canonical form
+    Fmt {
+        /// G-code files, "-" formats standard input to standard output
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Only report files which are not formatted
+        #[arg(long)]
+        check: bool,
+        /// Number the blocks of every program in steps of this
+        #[arg(long, value_name = "STEP", value_parser = clap::value_parser!(u32).range(1..))]
+        renumber: Option<u32>,
+        /// Remove G, F and S words repeating the modal state
+        #[arg(long)]
+        remove_modal: bool,
+    },
+    /// Serve editors with the Language Server Protocol on standard input and output
+    Lsp,
+    /// Step through a program interactively
+    Debug {
+        /// G-code file
+        file: PathBuf,
+    },
+    /// Find the machine state for restarting the program at a block
+    Search {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// Target block as file line, N<number> or L<subprogram> entry
+        target: Breakpoint,
+        /// Stop at the given occurrence of the target block
+        #[arg(long, default_value_t = 1)]
+        occurrence: usize,
+    },
+    /// Check program structure and simulate it, reporting all diagnostics
+    Check {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// Program levels the control allows, the main program included
+        #[arg(long, default_value_t = Analysis::DEFAULT_DEPTH_LIMIT)]
+        depth_limit: usize,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = CheckFormat::Text)]
+        format: CheckFormat,
+        /// Apply suggested fixes of simulation errors to the source files first
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Print program statistics
+    Stats {
+        /// G-code file, "-" for standard input
+        file: PathBuf,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+        #[command(flatten)]
+        time: TimeArgs,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Output format of diagnostics
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CheckFormat {
+    Text,
+    Json,
+    /// SARIF 2.1 log for code hosting annotations
+    Sarif,
+    /// JUnit XML with a test case per program file
+    Junit,
+}
 
-    let program = Program::from_file(file)?;
-    let render = out_path.map(|p| Box::new(Svg::new(p)) as Box<dyn Render>);
+#[derive(Debug, Args)]
+struct PartArgs {
+    /// Stock as "X0,Y0,X1,Y1,TOP" in millimeters
+    #[arg(long, value_parser = parse_stock)]
+    stock: Option<Stock>,
+    /// Height map cell size in millimeters
+    #[arg(long, default_value = "0.5", value_parser = parse_mm)]
+    resolution: Micrometer,
+}
 
-    let mut machine = Machine::with_render(render);
-    for cmd in program.execute(None).map_err(SimpleError::no_line)? {
-        let (line, cmd) = cmd?;
-        println!("{}", cmd.raw);
-        machine.execute_command(cmd).map_err(|e| e.at_line(line))?;
+impl PartArgs {
+    fn stock(&self) -> Stock {
+        self.stock.unwrap_or_default()
     }
 
-    if let Some(render) = machine.finalize() {
-        render
-            .finalize()
-            .map_err(|e| SimpleError(format!("Can't write output file: {e}")).no_line())?;
+    fn height_map(&self) -> HeightMap {
+        HeightMap::new(&self.stock(), self.resolution)
     }
+}
+
+#[derive(Debug, Args)]
+struct TimeArgs {
+    /// Rapid traverse rates of X, Y and Z axes as "X,Y,Z" in mm/min
+    #[arg(long, value_parser = parse_rates)]
+    rapid: Option<[f64; 3]>,
+    /// Axis acceleration in mm/s²
+    #[arg(long, default_value_t = TimeModel::default().acceleration)]
+    acceleration: f64,
+    /// Tool change duration in seconds
+    #[arg(long, default_value_t = TimeModel::default().tool_change)]
+    tool_change: f64,
+}
+
+impl TimeArgs {
+    fn config(&self, opts: &Options) -> Result<MachineConfig, LineError> {
+        Ok(opts.machine()?.with_time_model(TimeModel {
+            rapid: self.rapid.unwrap_or(TimeModel::default().rapid),
+            acceleration: self.acceleration,
+            tool_change: self.tool_change,
+        }))
+    }
+}
+
+fn parse_mm(s: &str) -> Result<Micrometer, String> {
+    match Micrometer::parse(s) {
+        Ok(("", n)) => Ok(n),
+        _ => Err(format!("invalid number '{s}'")),
+    }
+}
+
+fn parse_rates(s: &str) -> Result<[f64; 3], String> {
+    let v = s
+        .split(',')
+        .map(|n| n.trim().parse::<f64>().map_err(|e| format!("'{n}': {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    v.try_into().map_err(|_| "expected three rates X,Y,Z".into())
+}
+
+fn parse_lang() -> impl TypedValueParser<Value = Lang> {
+    PossibleValuesParser::new(["en", "de"]).map(|s| s.parse().expect("Bug: unknown language"))
+}
+
+fn parse_stock(s: &str) -> Result<Stock, String> {
+    s.parse().map_err(|e: SimpleError| e.0)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let opts = &cli.options;
+    millsim::set_lang(opts.lang.or_else(Lang::from_env).unwrap_or_default());
+
+    let result = match &cli.command {
+        Command::Run {
+            file,
+            output,
+            heightmap,
+            trace,
+            part,
+        } => commands::run(
+            file,
+            output.as_deref(),
+            heightmap.as_deref(),
+            trace.as_deref(),
+            part,
+            opts,
+        )
+        .map_err(|e| e.in_file(file))
+        .map(|()| true),
+        Command::Compare {
+            file,
+            reference,
+            tolerance,
+            diff,
+            part,
+        } => commands::compare(file, reference, *tolerance, diff.as_deref(), part, opts),
+        Command::Time { file, time: t } => commands::time(file, t, opts)
+            .map_err(|e| e.in_file(file))
+            .map(|()| true),
+        Command::Coverage { file, html } => commands::coverage(file, html.as_deref(), opts)
+            .map_err(|e| e.in_file(file))
+            .map(|()| true),
+        Command::Profile { file, top, time: t } => commands::profile(file, *top, t, opts)
+            .map_err(|e| e.in_file(file))
+            .map(|()| true),
+        Command::Flatten {
+            file,
+            output,
+            evaluate,
+        } => commands::flatten(file, output.as_deref(), *evaluate, opts)
+            .map_err(|e| e.in_file(file))
+            .map(|()| true),
+        Command::Fmt {
+            files,
+            check,
+            renumber,
+            remove_modal,
+        } => commands::format_files(
+            files,
+            *check,
+            &FormatOptions {
+                renumber: *renumber,
+                remove_modal: *remove_modal,
+            },
+        ),
+        Command::Lsp => opts.machine().and_then(|machine| {
+            lsp::run(lsp::ServerConfig {
+                search_path: opts.search_path.clone(),
+                libraries: opts.libraries.clone(),
+                max_blocks: opts.max_blocks,
+                machine,
+            })
+            .map_err(|e| SimpleError(format!("Language server failed: {e}")).no_line())
+            .map(|()| true)
+        }),
+        Command::Debug { file } => commands::debug(file, opts)
+            .map_err(|e| e.in_file(file))
+            .map(|()| true),
+        Command::Search {
+            file,
+            target,
+            occurrence,
+        } => commands::search(file, target.clone(), *occurrence, opts).map_err(|e| e.in_file(file)),
+        Command::Check {
+            file,
+            depth_limit,
+            format,
+            fix,
+        } => commands::check(file, *depth_limit, *format, *fix, opts).map_err(|e| e.in_file(file)),
+        Command::Stats { file, format, time } => commands::stats(file, *format, time, opts)
+            .map_err(|e| e.in_file(file))
+            .map(|()| true),
+    };
 
-    Ok(())
-}
-
-fn main() {
-    let file = "haken.ngc";
-    let out = "haken.svg";
-
-    if let Err(e) = run(file, Some(out)) {
-        let mut stderr = StandardStream::stderr(ColorChoice::Auto);
-        stderr
-            .set_color(
-                ColorSpec::new()
-                    .set_fg(Some(Color::Red))
-                    .set_bold(true)
-                    .set_intense(true),
-            )
-            .ok();
-        writeln!(stderr, "While parsing '{file}':").ok();
-        writeln!(stderr, "{e}").ok();
-        stderr.reset().ok();
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            let mut stderr = StandardStream::stderr(ColorChoice::Auto);
+            stderr
+                .set_color(
+                    ColorSpec::new()
+                        .set_fg(Some(Color::Red))
+                        .set_bold(true)
+                        .set_intense(true),
+                )
+                .ok();
+            writeln!(stderr, "{error}").ok();
+            stderr.reset().ok();
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,411 @@
+//! Height map render simulating material removal
+
+use super::traits::{Circle, Line, Micrometer, Render};
+use crate::errors::{LineError, SimpleError};
+use std::{
+    f64::consts::TAU,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Error, Write},
+    path::Path,
+    str::FromStr,
+};
+
+/// Raw material block placed on the machine table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stock {
+    /// Lower left corner (X, Y)
+    pub min: (Micrometer, Micrometer),
+    /// Upper right corner (X, Y)
+    pub max: (Micrometer, Micrometer),
+    /// Z height of the top surface
+    pub top: Micrometer,
+}
+
+impl Default for Stock {
+    fn default() -> Self {
+        Self {
+            min: (Micrometer(-300_000), Micrometer(0)),
+            max: (Micrometer(0), Micrometer(60_300)),
+            top: Micrometer(0),
+        }
+    }
+}
+
+impl FromStr for Stock {
+    type Err = SimpleError;
+
+    /// Parse stock from "X0,Y0,X1,Y1,TOP" in millimeters
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v: Vec<_> = s
+            .split(',')
+            .map(|n| match Micrometer::parse(n.trim()) {
+                Ok(("", n)) => Ok(n),
+                _ => Err(SimpleError(format!("Invalid number '{n}' in stock"))),
+            })
+            .collect::<Result<_, _>>()?;
+        match v[..] {
+            [x0, y0, x1, y1, top] if x0 < x1 && y0 < y1 => Ok(Self {
+                min: (x0, y0),
+                max: (x1, y1),
+                top,
+            }),
+            [_, _, _, _, _] => Err(SimpleError("Stock has no area".into())),
+            _ => Err(SimpleError("Stock must be given as X0,Y0,X1,Y1,TOP".into())),
+        }
+    }
+}
+
+/// A render that keeps the remaining material height on a regular grid
+///
+/// Every movement, including fast ones, removes material using a flat end mill
+/// of the tool diameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeightMap {
+    origin: (Micrometer, Micrometer),
+    step: Micrometer,
+    cols: usize,
+    rows: usize,
+    heights: Vec<Micrometer>,
+    position: Option<(Micrometer, Micrometer, Micrometer)>,
+}
+
+impl HeightMap {
+    /// Create unmachined height map of the stock with cells of `step` size
+    pub fn new(stock: &Stock, step: Micrometer) -> Self {
+        let step = Micrometer(step.0.max(1));
+        let cells = |a: Micrometer, b: Micrometer| ((b - a).0 + step.0 - 1) as usize / step.0 as usize;
+        let cols = cells(stock.min.0, stock.max.0);
+        let rows = cells(stock.min.1, stock.max.1);
+        Self {
+            origin: stock.min,
+            step,
+            cols,
+            rows,
+            heights: vec![stock.top; cols * rows],
+            position: None,
+        }
+    }
+
+    /// Load height map saved with `save()`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LineError> {
+        let fd =
+            File::open(path).map_err(|e| SimpleError(format!("Can't open file: {e}")).no_line())?;
+        Self::read(BufReader::new(fd))
+    }
+
+    /// Read height map in the text form of `write()`
+    pub fn read(fd: impl BufRead) -> Result<Self, LineError> {
+        let mut lines = fd.lines().enumerate().map(|(no, line)| {
+            let no = no as u64 + 1;
+            line.map(|l| (no, l))
+                .map_err(|e| SimpleError(format!("I/O error {e}")).at_line(no))
+        });
+        let mut header = |key: &str, count: usize| -> Result<(u64, Vec<String>), LineError> {
+            let (no, line) = lines
+                .next()
+                .unwrap_or_else(|| Err(SimpleError("Unexpected end of file".into()).no_line()))?;
+            let mut words = line.split_whitespace();
+            if words.next() != Some(key) {
+                return Err(SimpleError(format!("Expected '{key}'")).at_line(no));
+            }
+            let v: Vec<_> = words.map(String::from).collect();
+            if v.len() != count {
+                return Err(SimpleError(format!("Expected {count} values")).at_line(no));
+            }
+            Ok((no, v))
+        };
+
+        header(MAGIC, 0)?;
+        let (no, step) = header("step", 1)?;
+        let step = numbers(step.iter().map(String::as_str)).map_err(|e| e.at_line(no))?[0];
+        if step.0 <= 0 {
+            return Err(SimpleError("Step must be positive".into()).at_line(no));
+        }
+        let (no, origin) = header("origin", 2)?;
+        let origin = numbers(origin.iter().map(String::as_str)).map_err(|e| e.at_line(no))?;
+        let (no, size) = header("size", 2)?;
+        let size = size
+            .iter()
+            .map(|s| s.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SimpleError(format!("Invalid size: {e}")).at_line(no))?;
+        let (cols, rows) = (size[0], size[1]);
+        let cells = cols
+            .checked_mul(rows)
+            .filter(|&n| n <= MAX_CELLS)
+            .ok_or_else(|| SimpleError(format!("Size {cols} x {rows} is too large")).at_line(no))?;
+
+        let mut heights = Vec::with_capacity(cells);
+        for line in lines {
+            let (no, line) = line?;
+            let row = numbers(line.split_whitespace()).map_err(|e| e.at_line(no))?;
+            if row.len() != cols {
+                return Err(SimpleError(format!("Expected {cols} values")).at_line(no));
+            }
+            heights.extend(row);
+        }
+        if heights.len() != cells {
+            return Err(SimpleError(format!("Expected {rows} rows")).no_line());
+        }
+
+        Ok(Self {
+            origin: (origin[0], origin[1]),
+            step,
+            cols,
+            rows,
+            heights,
+            position: None,
+        })
+    }
+
+    /// Save height map in text form
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    /// Write height map in the text form of `save()`
+    pub fn write(&self, mut fd: impl Write) -> Result<(), Error> {
+        writeln!(fd, "{MAGIC}")?;
+        writeln!(fd, "step {}", self.step)?;
+        writeln!(fd, "origin {} {}", self.origin.0, self.origin.1)?;
+        writeln!(fd, "size {} {}", self.cols, self.rows)?;
+        for row in self.heights.chunks(self.cols.max(1)) {
+            for (i, h) in row.iter().enumerate() {
+                let c = if i == 0 { "" } else { " " };
+                write!(fd, "{c}{h}")?;
+            }
+            writeln!(fd)?;
+        }
+        fd.flush()
+    }
+
+    /// Cell size
+    pub fn step(&self) -> Micrometer {
+        self.step
+    }
+
+    /// Grid size as (columns, rows)
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Lower left corner of the grid
+    pub fn origin(&self) -> (Micrometer, Micrometer) {
+        self.origin
+    }
+
+    /// Check if both maps cover the same area with the same cells
+    pub fn same_grid(&self, other: &Self) -> bool {
+        (self.origin, self.step, self.cols, self.rows)
+            == (other.origin, other.step, other.cols, other.rows)
+    }
+
+    /// Material height at cell (column, row)
+    pub fn height(&self, col: usize, row: usize) -> Micrometer {
+        self.heights[row * self.cols + col]
+    }
+
+    /// Center of cell (column, row) in millimeters
+    pub fn cell_center(&self, col: usize, row: usize) -> (f64, f64) {
+        let step = self.step.to_mm();
+        (
+            self.origin.0.to_mm() + (col as f64 + 0.5) * step,
+            self.origin.1.to_mm() + (row as f64 + 0.5) * step,
+        )
+    }
+
+    /// Lower cells within the given rectangle (in millimeters) to `depth(x, y)`
+    fn cut(
+        &mut self,
+        (x0, y0): (f64, f64),
+        (x1, y1): (f64, f64),
+        depth: impl Fn(f64, f64) -> Option<f64>,
+    ) {
+        let step = self.step.to_mm();
+        let (ox, oy) = (self.origin.0.to_mm(), self.origin.1.to_mm());
+        let range = |a: f64, b: f64, n: usize| {
+            let a = (a / step).floor().max(0.0) as usize;
+            let b = ((b / step).ceil().max(0.0) as usize).min(n);
+            a..b
+        };
+        let cols = range(x0 - ox, x1 - ox, self.cols);
+        let rows = range(y0 - oy, y1 - oy, self.rows);
+        for row in rows {
+            for col in cols.clone() {
+                let (x, y) = self.cell_center(col, row);
+                if let Some(z) = depth(x, y) {
+                    let h = &mut self.heights[row * self.cols + col];
+                    *h = (*h).min(Micrometer::from_mm(z));
+                }
+            }
+        }
+    }
+}
+
+const MAGIC: &str = "millsim-heightmap";
+
+/// Most cells of a loaded map, 1 m square with 0.1 mm steps
+const MAX_CELLS: usize = 100_000_000;
+
+fn numbers<'t>(words: impl Iterator<Item = &'t str>) -> Result<Vec<Micrometer>, SimpleError> {
+    words
+        .map(|w| match Micrometer::parse(w) {
+            Ok(("", n)) => Ok(n),
+            _ => Err(SimpleError(format!("Invalid number '{w}'"))),
+        })
+        .collect()
+}
+
+impl Render for HeightMap {
+    fn line_to(
+        &mut self,
+        tool: Micrometer,
+        _ty: Line,
+        (x, y): (Micrometer, Micrometer),
+        height: Micrometer,
+    ) {
+        let end = (x, y, height);
+        let Some(start) = self.position.replace(end) else {
+            return;
+        };
+
+        let r = tool.to_mm() / 2.0;
+        let (sx, sy, sz) = (start.0.to_mm(), start.1.to_mm(), start.2.to_mm());
+        let (ex, ey, ez) = (x.to_mm(), y.to_mm(), height.to_mm());
+        let (dx, dy) = (ex - sx, ey - sy);
+        let a = dx * dx + dy * dy;
+
+        self.cut(
+            (sx.min(ex) - r, sy.min(ey) - r),
+            (sx.max(ex) + r, sy.max(ey) + r),
+            |x, y| {
+                // Interval of the path where the tool covers the point
+                let (fx, fy) = (sx - x, sy - y);
+                let c = fx * fx + fy * fy - r * r;
+                let (t1, t2) = if a == 0.0 {
+                    if c > 0.0 {
+                        return None;
+                    }
+                    (0.0, 1.0)
+                } else {
+                    let b = 2.0 * (fx * dx + fy * dy);
+                    let disc = b * b - 4.0 * a * c;
+                    if disc < 0.0 {
+                        return None;
+                    }
+                    let q = disc.sqrt();
+                    ((-b - q) / (2.0 * a), (-b + q) / (2.0 * a))
+                };
+                if t1 > 1.0 || t2 < 0.0 {
+                    return None;
+                }
+                let z = |t: f64| sz + (ez - sz) * t.clamp(0.0, 1.0);
+                Some(z(t1).min(z(t2)))
+            },
+        );
+    }
+
+    fn arc_to(
+        &mut self,
+        tool: Micrometer,
+        ty: Circle,
+        center: (Micrometer, Micrometer),
+        end: (Micrometer, Micrometer),
+    ) {
+        let (sx, sy, z) = self.position.expect("Bug: circle with no start");
+        self.position = Some((end.0, end.1, z));
+
+        let r = tool.to_mm() / 2.0;
+        let z = z.to_mm();
+        let (sx, sy) = (sx.to_mm(), sy.to_mm());
+        let (cx, cy) = (center.0.to_mm(), center.1.to_mm());
+        let (ex, ey) = (end.0.to_mm(), end.1.to_mm());
+        let radius = (sx - cx).hypot(sy - cy);
+
+        let (from, sweep) = ty.sweep((sx, sy), (cx, cy), (ex, ey));
+
+        let reach = radius + r;
+        self.cut((cx - reach, cy - reach), (cx + reach, cy + reach), |x, y| {
+            let a = (y - cy).atan2(x - cx);
+            let d = if (a - from).rem_euclid(TAU) <= sweep {
+                ((x - cx).hypot(y - cy) - radius).abs()
+            } else {
+                (x - sx).hypot(y - sy).min((x - ex).hypot(y - ey))
+            };
+            (d <= r).then_some(z)
+        });
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        // Height map is kept in memory and saved explicitly
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeightMap, Stock};
+    use crate::render::{Circle, Line, Render};
+    use crate::types::Micrometer;
+
+    fn mm(x: f64) -> Micrometer {
+        Micrometer::from_mm(x)
+    }
+
+    fn stock() -> Stock {
+        "0,0,20,20,0".parse().unwrap()
+    }
+
+    #[test]
+    fn parse_stock() {
+        let s = stock();
+        assert_eq!(s.min, (Micrometer(0), Micrometer(0)));
+        assert_eq!(s.max, (Micrometer(20_000), Micrometer(20_000)));
+        assert!("0,0,20,20".parse::<Stock>().is_err());
+        assert!("0,0,0,20,0".parse::<Stock>().is_err());
+    }
+
+    #[test]
+    fn line_cut() {
+        let mut map = HeightMap::new(&stock(), mm(1.0));
+        map.line_to(mm(4.0), Line::Fast, (mm(5.0), mm(10.0)), mm(10.0));
+        map.line_to(mm(4.0), Line::Cut, (mm(5.0), mm(10.0)), mm(-1.0));
+        map.line_to(mm(4.0), Line::Cut, (mm(15.0), mm(10.0)), mm(-1.0));
+        assert_eq!(map.size(), (20, 20));
+        assert_eq!(map.height(10, 10), mm(-1.0));
+        assert_eq!(map.height(10, 11), mm(-1.0));
+        assert_eq!(map.height(10, 13), mm(0.0));
+        assert_eq!(map.height(18, 10), mm(0.0));
+    }
+
+    #[test]
+    fn read() {
+        let mut map = HeightMap::new(&stock(), mm(1.0));
+        map.line_to(mm(4.0), Line::Fast, (mm(5.0), mm(10.0)), mm(10.0));
+        map.line_to(mm(4.0), Line::Cut, (mm(5.0), mm(10.0)), mm(-1.0));
+        let mut text = Vec::new();
+        map.write(&mut text).unwrap();
+        let read = HeightMap::read(&text[..]).unwrap();
+        assert_eq!(read.size(), (20, 20));
+        assert_eq!(read.height(5, 10), mm(-1.0));
+
+        let text = String::from_utf8(text).unwrap();
+        for size in ["size 18446744073709551615 2", "size 100000 100000"] {
+            let huge = text.replace("size 20 20", size);
+            let e = HeightMap::read(huge.as_bytes()).unwrap_err();
+            assert_eq!(e.line(), Some(4));
+            assert!(e.message().contains("too large"), "{e}");
+        }
+    }
+
+    #[test]
+    fn arc_cut() {
+        let mut map = HeightMap::new(&stock(), mm(1.0));
+        map.line_to(mm(2.0), Line::Cut, (mm(15.0), mm(10.0)), mm(-2.0));
+        map.arc_to(mm(2.0), Circle::Ccw, (mm(10.0), mm(10.0)), (mm(5.0), mm(10.0)));
+        // Upper half is cut, lower half is not
+        assert_eq!(map.height(10, 14), mm(-2.0));
+        assert_eq!(map.height(10, 5), mm(0.0));
+        assert_eq!(map.height(10, 10), mm(0.0));
+    }
+}
@@ -1,6 +1,16 @@
 //! Rendering engine
 
+pub mod heightmap;
 pub mod svg;
 mod traits;
 
 pub use traits::{Circle, Line, Render};
+use std::any::Any;
+
+/// Remove a render of the given type from the list of finalized renders
+pub fn take<T: Render>(renders: &mut Vec<Box<dyn Render>>) -> Option<Box<T>> {
+    let idx = renders
+        .iter()
+        .position(|r| (r.as_ref() as &dyn Any).is::<T>())?;
+    (renders.remove(idx) as Box<dyn Any>).downcast().ok()
+}
@@ -1,26 +1,30 @@
 //! SVG render
 
-use super::traits::{Circle, Line, Micrometer, Render};
+use super::{
+    heightmap::Stock,
+    traits::{Circle, Line, Micrometer, Render},
+};
 use std::{
     io::{Write, Error},
-    path::{Path, PathBuf},
-    fs::File,
     fmt,
 };
 
-/// A SVG render using `piet_svg` library
+/// A SVG render writing the tool path to `W` at the end
 #[derive(Debug)]
-pub struct Svg {
-    svg_file: PathBuf,
+pub struct Svg<W> {
+    out: W,
+    stock: Stock,
     items: Vec<DrawingItem>,
     current: Option<DrawingItem>,
     position: Option<(Micrometer, Micrometer)>,
 }
 
-impl Svg {
-    pub fn new(path: impl AsRef<Path>) -> Self {
+impl<W: Write> Svg<W> {
+    /// Render drawing the stock and the tool path on it to `out`
+    pub fn new(out: W, stock: Stock) -> Self {
         Self {
-            svg_file: path.as_ref().to_owned(),
+            out,
+            stock,
             items: Vec::new(),
             current: None,
             position: None,
@@ -45,9 +49,20 @@ impl Svg {
 
         self.current.as_mut().unwrap()
     }
+
+    /// Write the picture and give back the output
+    pub fn finish(mut self) -> Result<W, Error> {
+        if let Some(cur) = self.current.take() {
+            self.items.push(cur);
+        }
+
+        write_svg(&mut self.out, &self.stock, self.items)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
 }
 
-impl Render for Svg {
+impl<W: Write + fmt::Debug + 'static> Render for Svg<W> {
     fn line_to(
         &mut self,
         tool: Micrometer,
@@ -125,13 +140,8 @@ impl Render for Svg {
         self.position = Some(end);
     }
 
-    fn finalize(mut self: Box<Self>) -> Result<(), Error> {
-        if let Some(cur) = self.current.take() {
-            self.items.push(cur);
-        }
-
-        let fd = File::create(self.svg_file)?;
-        write_svg(fd, self.items)
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        self.finish().map(drop)
     }
 }
 
@@ -186,18 +196,19 @@ struct DrawingItem {
     path: Vec<PathEl>,
 }
 
-fn write_svg(mut fd: impl Write, items: impl IntoIterator<Item = DrawingItem>) -> Result<(), Error> {
-    let (width, height) = (400.0, 200.0);
-    let (left, bottom) = (-width/2.0, -height/2.0);
-    writeln!(fd, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}mm\" height=\"{height}mm\" viewBox=\"{left} {bottom} {width} {height}\">")?;
+/// Space around the stock in millimeters for moves next to it
+const MARGIN: f64 = 10.0;
 
-    let material = Some(((300.0, 60.3), (300.0, 0.0)));
+fn write_svg(mut fd: impl Write, stock: &Stock, items: impl IntoIterator<Item = DrawingItem>) -> Result<(), Error> {
+    let ((x0, y0), (x1, y1)) = (stock.min, stock.max);
+    let (w, h) = ((x1 - x0).to_mm(), (y1 - y0).to_mm());
+    let (x, y) = (x0.to_mm(), -y1.to_mm());
 
-    if let Some(((w, h), (cx, cy))) = material {
-        let x = -cx;
-        let y = cy - h;
-        write!(fd, "<rect x=\"{x}\" y=\"{y}\" width=\"{w}\" height=\"{h}\" stroke=\"none\" fill=\"grey\" />")?;
-    }
+    // Y grows downwards in SVG, the picture is flipped
+    let (width, height) = (w + 2.0 * MARGIN, h + 2.0 * MARGIN);
+    let (left, top) = (x - MARGIN, y - MARGIN);
+    writeln!(fd, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}mm\" height=\"{height}mm\" viewBox=\"{left} {top} {width} {height}\">")?;
+    write!(fd, "<rect x=\"{x}\" y=\"{y}\" width=\"{w}\" height=\"{h}\" stroke=\"none\" fill=\"grey\" />")?;
 
     for item in items {
         let width = item.width;
@@ -214,3 +225,21 @@ fn write_svg(mut fd: impl Write, items: impl IntoIterator<Item = DrawingItem>) -
 
     writeln!(fd, "</svg>")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Svg;
+    use crate::render::{heightmap::Stock, Line, Render};
+    use crate::types::Micrometer;
+
+    #[test]
+    fn size_of_stock() {
+        let stock: Stock = "-50,10,30,40,0".parse().unwrap();
+        let mut svg = Svg::new(Vec::new(), stock);
+        svg.line_to(Micrometer::from_mm(6.0), Line::Cut, (Micrometer(0), Micrometer(20_000)), Micrometer(0));
+        let text = String::from_utf8(svg.finish().unwrap()).unwrap();
+        let header = text.lines().next().unwrap();
+        assert!(header.contains(r#"width="100mm" height="50mm" viewBox="-60 -50 100 50""#), "{header}");
+        assert!(text.contains(r#"<rect x="-50" y="-40" width="80" height="30""#));
+    }
+}
@@ -1,21 +1,51 @@
 //! Rendering traits
 
 pub use crate::types::Micrometer;
-use std::{fmt::Debug, io::Error};
+use serde::Serialize;
+use std::{any::Any, f64::consts::TAU, fmt::Debug, io::Error};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Direction of an arc, clockwise or counterclockwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Circle {
+    /// Clockwise with G2
     Cw,
+    /// Counterclockwise with G3
     Ccw,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl Circle {
+    /// Arc from `start` around `center` to `end` as counterclockwise sweep
+    /// of the returned angle from the returned start angle, in radians
+    ///
+    /// An arc ending at its start is a full circle.
+    pub fn sweep(self, start: (f64, f64), center: (f64, f64), end: (f64, f64)) -> (f64, f64) {
+        let a1 = (start.1 - center.1).atan2(start.0 - center.0);
+        let a2 = (end.1 - center.1).atan2(end.0 - center.0);
+        let (from, sweep) = match self {
+            Circle::Ccw => (a1, (a2 - a1).rem_euclid(TAU)),
+            Circle::Cw => (a2, (a1 - a2).rem_euclid(TAU)),
+        };
+        (from, if sweep == 0.0 { TAU } else { sweep })
+    }
+}
+
+/// Straight movement, fast with G0 or cutting with G1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Line {
+    /// Rapid traverse with G0
     Fast,
+    /// Cut at the feed with G1
     Cut,
 }
 
-pub trait Render: Debug {
+/// Receiver of the tool movements and machine events
+///
+/// Renders are attached to a machine and get every movement from the current
+/// tool position with the diameter of the selected tool.
+pub trait Render: Debug + Any {
+    /// Straight movement to `point` in the XY plane at Z `height`
     fn line_to(
         &mut self,
         tool: Micrometer,
@@ -24,6 +54,7 @@ pub trait Render: Debug {
         height: Micrometer,
     );
 
+    /// Arc in the XY plane around `center` to `end`, Z does not change
     fn arc_to(
         &mut self,
         tool: Micrometer,
@@ -32,5 +63,18 @@ pub trait Render: Debug {
         end: (Micrometer, Micrometer),
     );
 
+    /// Tool with the given number is selected
+    fn tool_change(&mut self, _tool: u8) {}
+
+    /// Spindle is turned on or off
+    fn spindle(&mut self, _on: bool) {}
+
+    /// Coolant is turned on or off
+    fn coolant(&mut self, _on: bool) {}
+
+    /// Machining time passes
+    fn elapse(&mut self, _seconds: f64) {}
+
+    /// Write the output after the program ended
     fn finalize(self: Box<Self>) -> Result<(), Error>;
 }
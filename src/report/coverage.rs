@@ -0,0 +1,283 @@
+//! Execution coverage of source lines
+
+use super::Html;
+use millsim::machine::{Program, ProgramId};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{Error, Write},
+    path::{Path, PathBuf},
+};
+
+/// Executions of every source line
+#[derive(Debug, Default)]
+pub struct Coverage {
+    hits: BTreeMap<PathBuf, BTreeMap<u64, u64>>,
+}
+
+impl Coverage {
+    /// Record executed block
+    pub fn record(&mut self, file: &Path, line: u64) {
+        let lines = match self.hits.get_mut(file) {
+            Some(lines) => lines,
+            None => self.hits.entry(file.to_owned()).or_default(),
+        };
+        *lines.entry(line).or_default() += 1;
+    }
+
+    /// Annotate sources of all the program files with the hit counts
+    pub fn report(&self, program: &Program) -> CoverageReport {
+        let mut code = BTreeMap::<&Path, Vec<u64>>::new();
+        let mut programs = Vec::new();
+        for (id, file, lines) in program.code_lines() {
+            let hits = self.hits.get(file);
+            let hit = |l: &u64| hits.and_then(|h| h.get(l)).is_some();
+            programs.push(ProgramCoverage {
+                file: file.to_owned(),
+                hit_lines: lines.iter().filter(|l| hit(l)).count(),
+                lines: lines.len(),
+                program: id,
+            });
+            code.entry(file).or_default().extend(lines);
+        }
+
+        let mut files = Vec::new();
+        for path in program.files() {
+            let source = program.text(path).expect("Bug: program file without text");
+            let code = code.get(path.as_path());
+            let hits = self.hits.get(path);
+            let lines = source
+                .lines()
+                .zip(1..)
+                .map(|(text, no)| SourceLine {
+                    text: text.strip_suffix('\r').unwrap_or(text).to_owned(),
+                    hits: code
+                        .is_some_and(|c| c.contains(&no))
+                        .then(|| hits.and_then(|h| h.get(&no)).copied().unwrap_or(0)),
+                })
+                .collect();
+            files.push(FileCoverage {
+                path: path.clone(),
+                lines,
+            });
+        }
+
+        CoverageReport { files, programs }
+    }
+}
+
+/// Source line with its hit count
+#[derive(Debug)]
+pub struct SourceLine {
+    pub text: String,
+    /// Executions, `None` if the line is no code
+    pub hits: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct FileCoverage {
+    pub path: PathBuf,
+    pub lines: Vec<SourceLine>,
+}
+
+impl FileCoverage {
+    /// Number of code lines and executed ones
+    fn count(&self) -> (usize, usize) {
+        let code = self.lines.iter().filter_map(|l| l.hits);
+        (code.clone().count(), code.filter(|&h| h > 0).count())
+    }
+}
+
+/// Code lines of a program executed at least once
+#[derive(Debug)]
+pub struct ProgramCoverage {
+    pub program: ProgramId,
+    pub file: PathBuf,
+    pub lines: usize,
+    pub hit_lines: usize,
+}
+
+/// Coverage of all program files
+#[derive(Debug)]
+pub struct CoverageReport {
+    pub files: Vec<FileCoverage>,
+    pub programs: Vec<ProgramCoverage>,
+}
+
+fn percent(hit: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        hit as f64 * 100.0 / total as f64
+    }
+}
+
+impl CoverageReport {
+    /// Write the annotated sources as HTML page
+    pub fn write_html(&self, mut fd: impl Write) -> Result<(), Error> {
+        writeln!(fd, "<!DOCTYPE html>")?;
+        writeln!(
+            fd,
+            "<html><head><meta charset=\"utf-8\"><title>Coverage</title><style>"
+        )?;
+        writeln!(fd, "body {{ font-family: sans-serif; }}")?;
+        writeln!(fd, "table {{ border-collapse: collapse; }}")?;
+        writeln!(fd, "td {{ padding: 0 0.5em; }}")?;
+        writeln!(
+            fd,
+            ".src td {{ font-family: monospace; white-space: pre; }}"
+        )?;
+        writeln!(fd, ".hits {{ text-align: right; color: #666; }}")?;
+        writeln!(fd, ".hit {{ background: #dfd; }}")?;
+        writeln!(fd, ".missed {{ background: #fcc; }}")?;
+        writeln!(fd, "</style></head><body>")?;
+
+        writeln!(fd, "<h1>Programs</h1>")?;
+        writeln!(
+            fd,
+            "<table><tr><th>Program</th><th>File</th><th>Lines</th><th>Executed</th></tr>"
+        )?;
+        for p in &self.programs {
+            let class = if p.hit_lines > 0 { "hit" } else { "missed" };
+            writeln!(
+                fd,
+                "<tr class=\"{class}\"><td>{}</td><td>{}</td><td>{}</td><td>{:.1}%</td></tr>",
+                Html(&p.program.to_string()),
+                Html(&p.file.display().to_string()),
+                p.lines,
+                percent(p.hit_lines, p.lines)
+            )?;
+        }
+        writeln!(fd, "</table>")?;
+
+        for file in &self.files {
+            let (code, hit) = file.count();
+            writeln!(
+                fd,
+                "<h1>{}</h1><p>{hit} of {code} lines executed ({:.1}%)</p>",
+                Html(&file.path.display().to_string()),
+                percent(hit, code)
+            )?;
+            writeln!(fd, "<table class=\"src\">")?;
+            for (line, no) in file.lines.iter().zip(1..) {
+                let (class, hits) = match line.hits {
+                    Some(0) => (" class=\"missed\"", "0".into()),
+                    Some(h) => (" class=\"hit\"", h.to_string()),
+                    None => ("", String::new()),
+                };
+                writeln!(
+                    fd,
+                    "<tr{class}><td class=\"hits\">{no}</td><td class=\"hits\">{hits}</td><td>{}</td></tr>",
+                    Html(&line.text)
+                )?;
+            }
+            writeln!(fd, "</table>")?;
+        }
+
+        writeln!(fd, "</body></html>")?;
+        fd.flush()
+    }
+}
+
+/// Annotated listing, code lines which never ran are marked with `#####`
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in &self.files {
+            let (code, hit) = file.count();
+            writeln!(
+                f,
+                "'{}': {hit} of {code} lines executed ({:.1}%)",
+                file.path.display(),
+                percent(hit, code)
+            )?;
+            for line in &file.lines {
+                match line.hits {
+                    Some(0) => write!(f, "{:>9}: ", "#####")?,
+                    Some(h) => write!(f, "{h:>9}: ")?,
+                    None => write!(f, "{:>9}: ", "-")?,
+                }
+                writeln!(f, "{}", line.text)?;
+            }
+            writeln!(f)?;
+        }
+        for p in &self.programs {
+            writeln!(
+                f,
+                "{}: {} of {} lines executed ({:.1}%)",
+                p.program,
+                p.hit_lines,
+                p.lines,
+                percent(p.hit_lines, p.lines)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Coverage;
+    use millsim::{GCodeFile, Machine, Program};
+
+    const PART: &str = "\
+%MPF1
+; Comments count as executed
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D1
+IF 0
+  G0 X5
+ENDIF
+L3 P1
+M5 M9
+M2
+%SPF3
+G1 X10 Z-1 F100
+G0 Z150
+M17
+%SPF4
+G0 X1
+M17
+";
+
+    #[test]
+    fn hits() {
+        let file = GCodeFile::parse("part.mpf", PART).unwrap();
+        let program = Program::from_file(file).unwrap();
+        let mut coverage = Coverage::default();
+        Machine::default()
+            .run(program.execute(None).unwrap(), |_| (), |line, _, exec, _| {
+                coverage.record(exec.file(), line);
+                Ok(())
+            })
+            .unwrap();
+        let report = coverage.report(&program);
+
+        assert_eq!(report.files.len(), 1);
+        let hits: Vec<_> = report.files[0].lines.iter().map(|l| l.hits).collect();
+        // The false IF continues behind its ENDIF
+        #[rustfmt::skip]
+        let expected = [
+            None, Some(1), Some(1), Some(1), Some(1), Some(1), Some(1), Some(0), Some(0), Some(1),
+            Some(1), Some(1),
+            None, Some(2), Some(2), Some(2),
+            None, Some(0), Some(0),
+        ];
+        assert_eq!(hits, expected);
+        assert_eq!(report.files[0].lines[7].text, "  G0 X5");
+
+        let programs: Vec<_> = report
+            .programs
+            .iter()
+            .map(|p| (p.program.to_string(), p.lines, p.hit_lines))
+            .collect();
+        let expected = [
+            ("%MPF1".to_owned(), 11, 9),
+            ("%SPF3".to_owned(), 3, 3),
+            ("%SPF4".to_owned(), 2, 0),
+        ];
+        assert_eq!(programs, expected);
+        assert!(report.to_string().contains("    #####:   G0 X5\n"));
+    }
+}
@@ -0,0 +1,126 @@
+//! JUnit XML report of diagnostics with one test case per program file
+
+use super::Html;
+use millsim::diagnostic::{Diagnostic, Severity};
+use std::{fmt, path::PathBuf};
+
+/// Program files of one check run with their diagnostics
+pub struct JUnit<'t> {
+    files: Vec<PathBuf>,
+    diagnostics: &'t [Diagnostic],
+}
+
+impl<'t> JUnit<'t> {
+    /// Report with a test case for every file, files of the diagnostics
+    /// included
+    pub fn new(files: &[PathBuf], diagnostics: &'t [Diagnostic]) -> Self {
+        let mut files = files.to_vec();
+        for file in diagnostics.iter().filter_map(|d| d.file.as_ref()) {
+            if !files.contains(file) {
+                files.push(file.clone());
+            }
+        }
+        Self { files, diagnostics }
+    }
+
+    /// Diagnostics of a file, those without a file belong to the first one
+    fn of_file(&self, idx: usize) -> impl Iterator<Item = &'t Diagnostic> + '_ {
+        let file = &self.files[idx];
+        self.diagnostics
+            .iter()
+            .filter(move |d| d.file.as_ref().map_or(idx == 0, |f| f == file))
+    }
+}
+
+impl fmt::Display for JUnit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failed = (0..self.files.len())
+            .filter(|&i| self.of_file(i).any(|d| d.severity == Severity::Error))
+            .count();
+        writeln!(f, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            f,
+            r#"<testsuites name="{0}" tests="{1}" failures="{failed}">"#,
+            env!("CARGO_PKG_NAME"),
+            self.files.len()
+        )?;
+        writeln!(
+            f,
+            r#"  <testsuite name="check" tests="{}" failures="{failed}">"#,
+            self.files.len()
+        )?;
+        for (i, file) in self.files.iter().enumerate() {
+            let name = file.display().to_string();
+            let (errors, others) = self
+                .of_file(i)
+                .partition::<Vec<_>, _>(|d| d.severity == Severity::Error);
+            write!(
+                f,
+                r#"    <testcase name="{}" classname="check""#,
+                Html(&name)
+            )?;
+            if errors.is_empty() && others.is_empty() {
+                writeln!(f, "/>")?;
+                continue;
+            }
+            writeln!(f, ">")?;
+            if let Some(first) = errors.first() {
+                writeln!(
+                    f,
+                    r#"      <failure message="{}" type="{}">"#,
+                    Html(&first.message),
+                    first.rule.code()
+                )?;
+                for d in &errors {
+                    writeln!(f, "{}", Html(&d.to_string()))?;
+                }
+                writeln!(f, "      </failure>")?;
+            }
+            if !others.is_empty() {
+                writeln!(f, "      <system-out>")?;
+                for d in &others {
+                    writeln!(f, "{}", Html(&d.to_string()))?;
+                }
+                writeln!(f, "      </system-out>")?;
+            }
+            writeln!(f, "    </testcase>")?;
+        }
+        writeln!(f, "  </testsuite>")?;
+        writeln!(f, "</testsuites>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JUnit;
+    use millsim::diagnostic::{Diagnostic, Rule, Severity};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn failures_and_output() {
+        let main = Path::new("main.mpf");
+        let mut note = Diagnostic::new(Rule::SpindleWithoutCoolant, "Note <1>").at(main, 2);
+        note.severity = Severity::Warning;
+        let diagnostics = [
+            Diagnostic::new(Rule::SyntaxError, "Expected \"X\" & <Y>").at(main, 1),
+            note,
+            Diagnostic::new(Rule::ProgramError, "Missing M17").at(Path::new("lib.spf"), 3),
+        ];
+        let files = [PathBuf::from("main.mpf"), PathBuf::from("ok.mpf")];
+        let xml = JUnit::new(&files, &diagnostics).to_string();
+
+        assert!(xml.contains(r#"<testsuites name="millsim" tests="3" failures="2">"#));
+        assert!(xml.contains(r#"<testcase name="ok.mpf" classname="check"/>"#));
+        let main = xml.split(r#"<testcase name="main.mpf""#).nth(1).unwrap();
+        let main = main.split("</testcase>").next().unwrap();
+        let (failure, output) = main.split_once("<system-out>").unwrap();
+        assert!(failure.contains(
+            r#"<failure message="Expected &quot;X&quot; &amp; &lt;Y&gt;" type="MS0001">"#
+        ));
+        assert!(!failure.contains("Note"));
+        assert!(output.contains("Note &lt;1&gt;"));
+        assert!(!output.contains("Expected"));
+        let lib = xml.split(r#"<testcase name="lib.spf""#).nth(1).unwrap();
+        assert!(lib.contains(r#"type="MS0002""#));
+    }
+}
@@ -0,0 +1,64 @@
+//! Reports collected while running a program
+
+pub mod coverage;
+pub mod junit;
+pub mod profile;
+pub mod sarif;
+pub mod stats;
+pub mod time;
+pub mod trace;
+
+use std::fmt;
+
+/// Machining time in seconds, printed as minutes and seconds
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Duration(pub f64);
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Rounded first, 59.96 s is a full minute
+        let tenths = (self.0 * 10.0).round() as u64;
+        let (minutes, tenths) = (tenths / 600, tenths % 600);
+        let seconds = format!("{}.{}", tenths / 10, tenths % 10);
+        if minutes > 0 {
+            f.pad(&format!("{minutes}:{seconds:0>4}"))
+        } else {
+            f.pad(&format!("{seconds}s"))
+        }
+    }
+}
+
+/// Text escaped for HTML and XML
+struct Html<'t>(&'t str);
+
+impl fmt::Display for Html<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '&' => f.write_str("&amp;")?,
+                '"' => f.write_str("&quot;")?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Duration;
+
+    #[test]
+    fn duration() {
+        let text = |s| Duration(s).to_string();
+        assert_eq!(text(0.0), "0.0s");
+        assert_eq!(text(59.94), "59.9s");
+        assert_eq!(text(59.96), "1:00.0");
+        assert_eq!(text(61.26), "1:01.3");
+        assert_eq!(text(119.97), "2:00.0");
+        assert_eq!(text(3725.0), "62:05.0");
+        assert_eq!(format!("{:>8}", Duration(5.0)), "    5.0s");
+    }
+}
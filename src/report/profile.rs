@@ -0,0 +1,212 @@
+//! Machining time attributed to source lines and subprograms
+
+use super::Duration;
+use millsim::{
+    gcode::words::Words,
+    machine::{Frame, ProgramId},
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+struct LineCost {
+    /// Block at the first execution
+    raw: Words,
+    hits: u64,
+    time: f64,
+}
+
+#[derive(Debug, Default)]
+struct ProgramCost {
+    /// Time of the program's own blocks
+    own: f64,
+    /// Time with the subprograms called
+    inclusive: f64,
+    /// Entered passes, every repeat counts
+    calls: u64,
+}
+
+/// Time profile of a program run
+#[derive(Debug, Default)]
+pub struct Profile {
+    lines: BTreeMap<(PathBuf, u64), LineCost>,
+    programs: BTreeMap<ProgramId, ProgramCost>,
+    /// Call stack before the next block
+    running: Vec<Frame>,
+    total: f64,
+    /// Show only that many most expensive lines
+    top: Option<usize>,
+}
+
+impl Profile {
+    /// Profile showing only `top` most expensive lines, all by default
+    pub fn new(top: Option<usize>) -> Self {
+        Self {
+            top,
+            ..Self::default()
+        }
+    }
+
+    /// Record executed block
+    ///
+    /// `stack` is the call stack after the block and `elapsed` is the
+    /// machine time after the block was executed.
+    pub fn record(&mut self, file: &Path, line: u64, raw: &Words, stack: &[Frame], elapsed: f64) {
+        let time = elapsed - self.total;
+        self.total = elapsed;
+        // The first block always belongs to the main program
+        if self.running.is_empty() {
+            self.enter(&stack[..1]);
+        }
+
+        let cost = self
+            .lines
+            .entry((file.to_owned(), line))
+            .or_insert_with(|| LineCost {
+                raw: raw.clone(),
+                hits: 0,
+                time: 0.0,
+            });
+        cost.hits += 1;
+        cost.time += time;
+
+        let running = &self.running;
+        for (idx, frame) in running.iter().enumerate() {
+            let cost = self.programs.entry(frame.program.clone()).or_default();
+            if idx + 1 == running.len() {
+                cost.own += time;
+            }
+            // Recursive calls are counted once
+            if !running[..idx].iter().any(|f| f.program == frame.program) {
+                cost.inclusive += time;
+            }
+        }
+
+        self.enter(stack);
+    }
+
+    /// Update running programs, counting the new passes
+    fn enter(&mut self, stack: &[Frame]) {
+        let common = self
+            .running
+            .iter()
+            .zip(stack)
+            .take_while(|(a, b)| a == b)
+            .count();
+        for frame in &stack[common..] {
+            self.programs
+                .entry(frame.program.clone())
+                .or_default()
+                .calls += 1;
+        }
+        self.running = stack.to_vec();
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let share = |t: f64| {
+            if self.total > 0.0 {
+                t * 100.0 / self.total
+            } else {
+                0.0
+            }
+        };
+
+        let mut programs = self.programs.iter().collect::<Vec<_>>();
+        programs.sort_by(|a, b| b.1.inclusive.total_cmp(&a.1.inclusive));
+        writeln!(f, "Programs:")?;
+        writeln!(
+            f,
+            "{:>10} {:>6} {:>10} {:>6} {:>6}  Program",
+            "Inclusive", "", "Self", "", "Calls"
+        )?;
+        for (id, p) in programs {
+            writeln!(
+                f,
+                "{:>10} {:>5.1}% {:>10} {:>5.1}% {:>6}  {id}",
+                Duration(p.inclusive),
+                share(p.inclusive),
+                Duration(p.own),
+                share(p.own),
+                p.calls
+            )?;
+        }
+
+        let mut lines = self.lines.iter().collect::<Vec<_>>();
+        lines.sort_by(|a, b| b.1.time.total_cmp(&a.1.time));
+        writeln!(f, "Lines:")?;
+        writeln!(f, "{:>10} {:>6} {:>6}  Line", "Time", "", "Hits")?;
+        for ((file, line), c) in lines.into_iter().take(self.top.unwrap_or(usize::MAX)) {
+            writeln!(
+                f,
+                "{:>10} {:>5.1}% {:>6}  {}:{line}  {}",
+                Duration(c.time),
+                share(c.time),
+                c.hits,
+                file.display(),
+                c.raw
+            )?;
+        }
+
+        writeln!(f, "Total: {}", Duration(self.total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use millsim::{
+        gcode::words::Words,
+        machine::{Frame, ProgramId},
+    };
+    use std::path::Path;
+
+    fn frame(program: &ProgramId, call_line: Option<u64>) -> Frame {
+        Frame {
+            program: program.clone(),
+            call_line,
+            repeats_left: 0,
+            runs: 1,
+        }
+    }
+
+    #[test]
+    fn recursion() {
+        let main = ProgramId::Main("MPF1".into());
+        let sub = ProgramId::Sub("L3".into());
+        let outer = [frame(&main, None), frame(&sub, Some(2))];
+        let inner = [outer[0].clone(), outer[1].clone(), frame(&sub, Some(10))];
+
+        // Every block takes as many seconds as its number in the sequence
+        let mut profile = Profile::new(None);
+        let file = Path::new("part.mpf");
+        let raw = Words::default();
+        let blocks = [
+            (1, &outer[..1]),
+            (2, &outer[..]),
+            (10, &inner[..]),
+            (10, &outer[..]),
+            (11, &outer[..1]),
+            (3, &outer[..1]),
+        ];
+        let mut elapsed = 0.0;
+        for (n, (line, stack)) in blocks.into_iter().enumerate() {
+            elapsed += n as f64 + 1.0;
+            profile.record(file, line, &raw, stack, elapsed);
+        }
+
+        assert_eq!(profile.total, 21.0);
+        let main = &profile.programs[&main];
+        assert_eq!((main.own, main.inclusive, main.calls), (9.0, 21.0, 1));
+        // The recursive pass is not counted twice
+        let sub = &profile.programs[&sub];
+        assert_eq!((sub.own, sub.inclusive, sub.calls), (12.0, 12.0, 2));
+
+        let line = &profile.lines[&(file.to_owned(), 10)];
+        assert_eq!((line.hits, line.time), (2, 7.0));
+    }
+}
@@ -0,0 +1,172 @@
+//! SARIF 2.1 log of diagnostics for code hosting and CI annotations
+
+use millsim::diagnostic::{Diagnostic, Rule, Severity};
+use serde_json::{json, Value};
+use std::{fmt, path::Path};
+
+/// Diagnostics of one check run as a SARIF log
+pub struct Sarif<'t> {
+    diagnostics: &'t [Diagnostic],
+}
+
+impl<'t> Sarif<'t> {
+    pub fn new(diagnostics: &'t [Diagnostic]) -> Self {
+        Self { diagnostics }
+    }
+
+    /// The log as JSON value
+    pub fn to_json(&self) -> Value {
+        let rules = Rule::all()
+            .map(|r| {
+                json!({
+                    "id": r.code(),
+                    "name": r.name(),
+                    "defaultConfiguration": { "level": level(r.default_severity()) },
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = self.diagnostics.iter().map(result).collect::<Vec<_>>();
+        json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules,
+                    }
+                },
+                "columnKind": "unicodeCodePoints",
+                "results": results,
+            }],
+        })
+    }
+}
+
+impl fmt::Display for Sarif<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = serde_json::to_string_pretty(&self.to_json()).map_err(|_| fmt::Error)?;
+        writeln!(f, "{text}")
+    }
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Note => "note",
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    }
+}
+
+/// Artifact URI of a path, relative paths stay relative to the checkout
+fn uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut encoded = String::new();
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    if path.starts_with('/') {
+        format!("file://{encoded}")
+    } else {
+        encoded
+    }
+}
+
+fn result(d: &Diagnostic) -> Value {
+    let mut result = json!({
+        "ruleId": d.rule.code(),
+        "ruleIndex": Rule::all().position(|r| r == d.rule),
+        "level": level(d.severity),
+        "message": { "text": d.message },
+    });
+    let Some(file) = &d.file else {
+        return result;
+    };
+    let artifact = json!({ "uri": uri(file) });
+    let mut location = json!({ "artifactLocation": artifact });
+    if let Some(line) = d.line {
+        let mut region = json!({ "startLine": line });
+        if let Some(column) = d.column {
+            region["startColumn"] = column.into();
+        }
+        location["region"] = region;
+
+        // Fixed blocks go before the failing one
+        if let Some(fix) = &d.fix {
+            result["fixes"] = json!([{
+                "description": { "text": fix.to_string() },
+                "artifactChanges": [{
+                    "artifactLocation": artifact,
+                    "replacements": [{
+                        "deletedRegion": { "startLine": line, "startColumn": 1, "endColumn": 1 },
+                        "insertedContent": { "text": format!("{}\n", fix.insert) },
+                    }],
+                }],
+            }]);
+        }
+    }
+    result["locations"] = json!([{ "physicalLocation": location }]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sarif;
+    use millsim::{
+        diagnostic::{Diagnostic, Rule},
+        errors::Fix,
+    };
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn results() {
+        let mut located = Diagnostic::new(Rule::SpindleWithoutCoolant, "No coolant")
+            .with_fix(Fix::insert("M8"))
+            .at(Path::new("parts/my part.mpf"), 4);
+        located.column = Some(3);
+        let diagnostics = [located, Diagnostic::new(Rule::SyntaxError, "Bad")];
+        let log = Sarif::new(&diagnostics).to_json();
+        let run = &log["runs"][0];
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+
+        let result = &results[0];
+        assert_eq!(result["ruleId"], "MS0012");
+        let index = result["ruleIndex"].as_u64().unwrap() as usize;
+        assert_eq!(run["tool"]["driver"]["rules"][index]["id"], "MS0012");
+        assert_eq!(result["level"], "error");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "parts/my%20part.mpf");
+        assert_eq!(
+            location["region"],
+            json!({ "startLine": 4, "startColumn": 3 })
+        );
+        let change = &result["fixes"][0]["artifactChanges"][0];
+        assert_eq!(change["artifactLocation"]["uri"], "parts/my%20part.mpf");
+        let replacement = &change["replacements"][0];
+        assert_eq!(
+            replacement["deletedRegion"],
+            json!({ "startLine": 4, "startColumn": 1, "endColumn": 1 })
+        );
+        assert_eq!(replacement["insertedContent"]["text"], "M8\n");
+
+        // Without a file there is no location
+        assert_eq!(results[1]["ruleId"], "MS0001");
+        assert!(results[1].get("locations").is_none());
+    }
+
+    #[test]
+    fn uri() {
+        assert_eq!(
+            super::uri(Path::new("/tmp/a b#1.mpf")),
+            "file:///tmp/a%20b%231.mpf"
+        );
+        assert_eq!(super::uri(Path::new("Maß.mpf")), "Ma%C3%9F.mpf");
+    }
+}
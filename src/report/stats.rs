@@ -0,0 +1,254 @@
+//! Program statistics
+
+use super::Duration;
+use millsim::{
+    render::{Circle, Line, Render},
+    types::Micrometer,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, f64::consts::TAU, fmt, io::Error};
+
+/// Axis aligned box in millimeters
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Bounds {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+impl Bounds {
+    fn add(bounds: &mut Option<Bounds>, p: [f64; 3]) {
+        let b = bounds.get_or_insert(Bounds { min: p, max: p });
+        for ((min, max), p) in b.min.iter_mut().zip(&mut b.max).zip(p) {
+            *min = min.min(p);
+            *max = max.max(p);
+        }
+    }
+}
+
+impl fmt::Display for Bounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [x0, y0, z0] = self.min;
+        let [x1, y1, z1] = self.max;
+        write!(f, "X{x0:.3}..{x1:.3} Y{y0:.3}..{y1:.3} Z{z0:.3}..{z1:.3}")
+    }
+}
+
+/// Summary of a program run
+#[derive(Debug, Default, Serialize)]
+pub struct Statistics {
+    /// Length of cutting moves in mm
+    pub cut_length: f64,
+    /// Length of rapid moves in mm
+    pub rapid_length: f64,
+    pub cut_bounds: Option<Bounds>,
+    pub rapid_bounds: Option<Bounds>,
+    pub min_z: Option<f64>,
+    /// Cutting length in mm for every tool used
+    pub tools: BTreeMap<u8, f64>,
+    pub tool_changes: usize,
+    /// Spindle running time in seconds
+    pub spindle_time: f64,
+    /// Coolant flowing time in seconds
+    pub coolant_time: f64,
+    pub executed_blocks: usize,
+    pub source_blocks: usize,
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn bounds(b: &Option<Bounds>) -> String {
+            b.map_or_else(|| "none".into(), |b| b.to_string())
+        }
+
+        writeln!(f, "Cut path length: {:.3} mm", self.cut_length)?;
+        writeln!(f, "Rapid path length: {:.3} mm", self.rapid_length)?;
+        writeln!(f, "Cut bounds: {}", bounds(&self.cut_bounds))?;
+        writeln!(f, "Rapid bounds: {}", bounds(&self.rapid_bounds))?;
+        if let Some(z) = self.min_z {
+            writeln!(f, "Minimal Z: {z:.3}")?;
+        }
+        for (tool, length) in &self.tools {
+            writeln!(f, "Tool D{tool}: {length:.3} mm cut")?;
+        }
+        writeln!(f, "Tool changes: {}", self.tool_changes)?;
+        writeln!(f, "Spindle on: {}", Duration(self.spindle_time))?;
+        writeln!(f, "Coolant on: {}", Duration(self.coolant_time))?;
+        writeln!(
+            f,
+            "Blocks executed: {} of {} in source",
+            self.executed_blocks, self.source_blocks
+        )
+    }
+}
+
+/// Render collecting statistics from machine events
+#[derive(Debug, Default)]
+pub struct Collector {
+    stats: Statistics,
+    position: Option<[f64; 3]>,
+    tool: Option<u8>,
+    spindle: bool,
+    coolant: bool,
+}
+
+impl Collector {
+    /// Finish collecting, the block counts are taken from the executor
+    pub fn into_statistics(self, executed_blocks: usize, source_blocks: usize) -> Statistics {
+        Statistics {
+            executed_blocks,
+            source_blocks,
+            ..self.stats
+        }
+    }
+
+    fn visit(&mut self, ty: Line, p: [f64; 3]) {
+        let bounds = match ty {
+            Line::Fast => &mut self.stats.rapid_bounds,
+            Line::Cut => &mut self.stats.cut_bounds,
+        };
+        Bounds::add(bounds, p);
+        let z = self.stats.min_z.get_or_insert(p[2]);
+        *z = z.min(p[2]);
+    }
+
+    fn travel(&mut self, ty: Line, length: f64) {
+        match ty {
+            Line::Fast => self.stats.rapid_length += length,
+            Line::Cut => {
+                self.stats.cut_length += length;
+                if let Some(tool) = self.tool {
+                    *self.stats.tools.entry(tool).or_default() += length;
+                }
+            }
+        }
+    }
+}
+
+impl Render for Collector {
+    fn line_to(
+        &mut self,
+        _tool: Micrometer,
+        ty: Line,
+        point: (Micrometer, Micrometer),
+        height: Micrometer,
+    ) {
+        let end = [point.0.to_mm(), point.1.to_mm(), height.to_mm()];
+        if let Some(start) = self.position.replace(end) {
+            let [dx, dy, dz] = [end[0] - start[0], end[1] - start[1], end[2] - start[2]];
+            self.visit(ty, start);
+            self.travel(ty, dx.hypot(dy).hypot(dz));
+        }
+        self.visit(ty, end);
+    }
+
+    fn arc_to(
+        &mut self,
+        _tool: Micrometer,
+        ty: Circle,
+        center: (Micrometer, Micrometer),
+        end: (Micrometer, Micrometer),
+    ) {
+        let [sx, sy, z] = self.position.expect("Bug: circle with no start");
+        let (cx, cy) = (center.0.to_mm(), center.1.to_mm());
+        let (ex, ey) = (end.0.to_mm(), end.1.to_mm());
+        let r = (sx - cx).hypot(sy - cy);
+
+        let (from, sweep) = ty.sweep((sx, sy), (cx, cy), (ex, ey));
+
+        self.visit(Line::Cut, [sx, sy, z]);
+        for quadrant in 0..4 {
+            let a = quadrant as f64 * TAU / 4.0;
+            if (a - from).rem_euclid(TAU) <= sweep {
+                self.visit(Line::Cut, [cx + r * a.cos(), cy + r * a.sin(), z]);
+            }
+        }
+        self.visit(Line::Cut, [ex, ey, z]);
+        self.travel(Line::Cut, r * sweep);
+        self.position = Some([ex, ey, z]);
+    }
+
+    fn tool_change(&mut self, tool: u8) {
+        if self.tool.is_some_and(|t| t != tool) {
+            self.stats.tool_changes += 1;
+        }
+        self.tool = Some(tool);
+        self.stats.tools.entry(tool).or_default();
+    }
+
+    fn spindle(&mut self, on: bool) {
+        self.spindle = on;
+    }
+
+    fn coolant(&mut self, on: bool) {
+        self.coolant = on;
+    }
+
+    fn elapse(&mut self, seconds: f64) {
+        if self.spindle {
+            self.stats.spindle_time += seconds;
+        }
+        if self.coolant {
+            self.stats.coolant_time += seconds;
+        }
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        // Statistics are taken with `into_statistics()`
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Collector;
+    use millsim::{render, GCodeFile, Machine, Program};
+    use std::f64::consts::PI;
+
+    const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D1
+G0 Z2
+G1 Z-1 F100
+G1 X10
+G2 X20 Y0 I5 J0
+G0 Z150
+M5 M9
+M2
+";
+
+    #[test]
+    fn lengths_and_bounds() {
+        let file = GCodeFile::parse("part.mpf", PART).unwrap();
+        let program = Program::from_file(file).unwrap();
+        let mut machine = Machine::with_renders(vec![Box::<Collector>::default()]);
+        machine
+            .run(
+                program.execute(None).unwrap(),
+                |d| panic!("unexpected diagnostic {d}"),
+                |_, _, _, _| Ok(()),
+            )
+            .unwrap();
+        let mut renders = machine.finalize();
+        let collector = render::take::<Collector>(&mut renders).unwrap();
+        let stats = collector.into_statistics(0, 0);
+
+        // Plunge, line and half circle
+        assert!((stats.cut_length - (3.0 + 10.0 + 5.0 * PI)).abs() < 1e-9);
+        assert!((stats.rapid_length - (148.0 + 151.0)).abs() < 1e-9);
+        let cut = stats.cut_bounds.unwrap();
+        assert_eq!(cut.min, [0.0, 0.0, -1.0]);
+        // The arc passes its top quadrant point
+        assert_eq!(cut.max[0], 20.0);
+        assert!((cut.max[1] - 5.0).abs() < 1e-9);
+        assert_eq!(cut.max[2], 2.0);
+        let rapid = stats.rapid_bounds.unwrap();
+        assert_eq!(rapid.min, [0.0, 0.0, -1.0]);
+        assert_eq!(rapid.max, [20.0, 0.0, 150.0]);
+        assert_eq!(stats.min_z, Some(-1.0));
+        assert_eq!(stats.tools.len(), 1);
+        assert!((stats.tools[&1] - stats.cut_length).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,85 @@
+//! Cycle time report
+
+use super::Duration;
+use millsim::gcode::words::{Word, Words};
+use std::fmt;
+
+#[derive(Debug)]
+struct BlockTime {
+    line: u64,
+    raw: Words,
+    time: f64,
+}
+
+#[derive(Debug)]
+struct CallTime {
+    line: u64,
+    call: String,
+    depth: usize,
+    time: f64,
+}
+
+/// Machining time of every executed block and subprogram call
+#[derive(Debug, Default)]
+pub struct CycleTime {
+    blocks: Vec<BlockTime>,
+    calls: Vec<CallTime>,
+    /// Running calls as index in `calls` and start time
+    open: Vec<(usize, f64)>,
+    total: f64,
+}
+
+impl CycleTime {
+    /// Record executed block
+    ///
+    /// `depth` is the subprogram nesting depth and `elapsed` is the machine
+    /// time after the block was executed.
+    pub fn record(&mut self, line: u64, raw: &Words, depth: usize, elapsed: f64) {
+        self.blocks.push(BlockTime {
+            line,
+            raw: raw.clone(),
+            time: elapsed - self.total,
+        });
+        self.total = elapsed;
+
+        while depth > self.open.len() + 1 {
+            let call = raw
+                .0
+                .iter()
+                .filter(|w| matches!(w, Word::L(_) | Word::P(_) | Word::Call(..)))
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.open.push((self.calls.len(), elapsed));
+            self.calls.push(CallTime {
+                line,
+                call,
+                depth: self.open.len(),
+                time: 0.0,
+            });
+        }
+        while depth < self.open.len() + 1 {
+            let (idx, start) = self.open.pop().expect("Bug: no open call");
+            self.calls[idx].time = elapsed - start;
+        }
+    }
+}
+
+impl fmt::Display for CycleTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Blocks:")?;
+        for b in &self.blocks {
+            writeln!(f, "{:>6} {:>10}  {}", b.line, Duration(b.time), b.raw)?;
+        }
+
+        if !self.calls.is_empty() {
+            writeln!(f, "Subprogram calls:")?;
+            for c in &self.calls {
+                let indent = "  ".repeat(c.depth);
+                writeln!(f, "{indent}{} at line {}: {}", c.call, c.line, Duration(c.time))?;
+            }
+        }
+
+        writeln!(f, "Total: {}", Duration(self.total))
+    }
+}
@@ -0,0 +1,195 @@
+//! Machine state trace in JSON Lines format
+
+use millsim::{
+    gcode::words::Words,
+    machine::{Frame, MachineState},
+    render::{Circle, Line, Render},
+    types::Micrometer,
+};
+use serde::Serialize;
+use std::io::{Error, Write};
+
+/// Motion segment produced by a block
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Segment {
+    Line {
+        #[serde(rename = "type")]
+        ty: Line,
+        to: [Micrometer; 3],
+    },
+    Arc {
+        direction: Circle,
+        center: [Micrometer; 2],
+        to: [Micrometer; 2],
+    },
+}
+
+/// Render recording motion segments until they are taken
+#[derive(Debug, Default)]
+pub struct Recorder {
+    segments: Vec<Segment>,
+}
+
+impl Recorder {
+    /// Take segments recorded since the last call
+    pub fn take(&mut self) -> Vec<Segment> {
+        std::mem::take(&mut self.segments)
+    }
+}
+
+impl Render for Recorder {
+    fn line_to(
+        &mut self,
+        _tool: Micrometer,
+        ty: Line,
+        point: (Micrometer, Micrometer),
+        height: Micrometer,
+    ) {
+        self.segments.push(Segment::Line {
+            ty,
+            to: [point.0, point.1, height],
+        });
+    }
+
+    fn arc_to(
+        &mut self,
+        _tool: Micrometer,
+        ty: Circle,
+        center: (Micrometer, Micrometer),
+        end: (Micrometer, Micrometer),
+    ) {
+        self.segments.push(Segment::Arc {
+            direction: ty,
+            center: [center.0, center.1],
+            to: [end.0, end.1],
+        });
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// One executed block
+#[derive(Debug, Serialize)]
+pub struct TraceRecord {
+    /// File line of the block
+    pub line: u64,
+    /// Call stack the block ran in, main program first
+    pub stack: Vec<Frame>,
+    /// Raw block text
+    pub block: String,
+    /// Modal state after the block
+    pub state: MachineState,
+    pub segments: Vec<Segment>,
+}
+
+impl TraceRecord {
+    pub fn new(
+        line: u64,
+        stack: Vec<Frame>,
+        raw: &Words,
+        state: MachineState,
+        segments: Vec<Segment>,
+    ) -> Self {
+        Self {
+            line,
+            stack,
+            block: raw.to_string(),
+            state,
+            segments,
+        }
+    }
+
+    /// Write as a single JSON line
+    pub fn write(&self, mut fd: impl Write) -> Result<(), Error> {
+        serde_json::to_writer(&mut fd, self)?;
+        writeln!(fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, TraceRecord};
+    use millsim::{GCodeFile, Machine, Program};
+    use serde_json::{json, Value};
+
+    const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D1
+L3 P0
+G0 Z150
+M5 M9
+M2
+%SPF3
+G1 X10 Z-1 F100
+G2 X20 Y0 I5 J0
+M17
+";
+
+    #[test]
+    fn json_lines() {
+        let file = GCodeFile::parse("part.mpf", PART).unwrap();
+        let program = Program::from_file(file).unwrap();
+        let mut out = Vec::new();
+        let mut machine = Machine::with_renders(vec![Box::<Recorder>::default()]);
+        machine
+            .run(
+                program.execute(None).unwrap(),
+                |_| (),
+                |line, raw, exec, machine| {
+                    let segments = machine.render_mut::<Recorder>().unwrap().take();
+                    let record =
+                        TraceRecord::new(line, exec.block_stack(), raw, machine.state(), segments);
+                    record.write(&mut out).unwrap();
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let records: Vec<Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let lines: Vec<_> = records
+            .iter()
+            .map(|r| r["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(lines, [2, 3, 4, 5, 6, 11, 12, 13, 7, 8, 9]);
+
+        // The call block runs in the main program, M17 in the subprogram
+        let depth = |i: usize| records[i]["stack"].as_array().unwrap().len();
+        assert_eq!((depth(4), depth(5), depth(7), depth(8)), (1, 2, 2, 1));
+        assert_eq!(records[7]["stack"][1]["program"], "%SPF3");
+        assert_eq!(records[7]["stack"][1]["call_line"], 6);
+
+        assert_eq!(records[0]["segments"], json!([]));
+        assert_eq!(
+            records[5]["segments"],
+            json!([{ "kind": "line", "type": "cut", "to": [10.0, 0.0, -1.0] }])
+        );
+        assert_eq!(
+            records[6]["segments"],
+            json!([{ "kind": "arc", "direction": "cw", "center": [15.0, 0.0], "to": [20.0, 0.0] }])
+        );
+        assert_eq!(records[6]["block"], "G2 X20.000 Y0.000 I5.000 J0.000");
+
+        let state = &records[6]["state"];
+        assert_eq!(
+            (&state["x"], &state["z"], &state["feed"]),
+            (&json!(20.0), &json!(-1.0), &json!(100))
+        );
+        assert_eq!(records[9]["state"]["spindle_on"], false);
+        let elapsed: Vec<_> = records
+            .iter()
+            .map(|r| r["state"]["elapsed"].as_f64().unwrap())
+            .collect();
+        assert!(elapsed.windows(2).all(|w| w[0] <= w[1]));
+        assert!(elapsed[6] > elapsed[5]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Block search: machine state for restarting in the middle of a program
+
+use crate::debug::{Breakpoint, Debugger};
+use millsim::{
+    errors::{LineError, SimpleError},
+    gcode::words::Words,
+    machine::{Frame, MachineState},
+};
+use std::{collections::BTreeMap, fmt};
+
+/// Action needed before the program can be restarted at the block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    /// Tool must be loaded
+    ToolChange(u8),
+    /// Coolant must be turned on
+    Coolant,
+    /// Spindle must be started with the speed
+    Spindle(Option<u16>),
+    /// Position below safe Z must be approached from above
+    Approach,
+    /// Program continues in relative coordinates
+    Relative,
+    /// Restart inside a subprogram call
+    Subprogram,
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Requirement::*;
+        match self {
+            ToolChange(t) => write!(f, "tool change to D{t} (M6)"),
+            Coolant => write!(f, "coolant start (M8)"),
+            Spindle(Some(s)) => write!(f, "spindle start (S{s} M3)"),
+            Spindle(None) => write!(f, "spindle start (M3)"),
+            Approach => write!(f, "approach from safe Z, the position is inside the part"),
+            Relative => write!(f, "relative coordinates (G91) depend on the previous position"),
+            Subprogram => write!(f, "subprogram call stack must be rebuilt"),
+        }
+    }
+}
+
+/// Machine state found by block search
+#[derive(Debug)]
+pub struct BlockSearch {
+    /// File line of the target block
+    pub line: u64,
+    pub block: Words,
+    pub stack: Vec<Frame>,
+    /// Modal state before the target block
+    pub state: MachineState,
+    pub params: BTreeMap<u8, f64>,
+    pub requirements: Vec<Requirement>,
+}
+
+impl BlockSearch {
+    /// Run the program until `count`-th occurrence of `target`
+    pub fn run(mut dbg: Debugger, target: Breakpoint, count: usize) -> Result<Self, LineError> {
+        if !dbg.run_to(target.clone(), count)? {
+            return Err(SimpleError(format!("Program ends before reaching {target}")).no_line());
+        }
+
+        let exec = dbg.executor();
+        let (line, block) = exec.peek().expect("Bug: block search stopped at the end");
+        let state = dbg.machine().state();
+        let safe_z = dbg.machine().config().safe_z();
+        let stack = exec.call_stack();
+
+        let mut requirements = Vec::new();
+        if let Some(tool) = state.tool {
+            requirements.push(Requirement::ToolChange(tool));
+        }
+        if state.coolant_on {
+            requirements.push(Requirement::Coolant);
+        }
+        if state.spindle_on {
+            requirements.push(Requirement::Spindle(state.speed));
+        }
+        if state.z.is_some_and(|z| z < safe_z) {
+            requirements.push(Requirement::Approach);
+        }
+        if state.relative {
+            requirements.push(Requirement::Relative);
+        }
+        if stack.len() > 1 {
+            requirements.push(Requirement::Subprogram);
+        }
+
+        Ok(Self {
+            line,
+            block: block.clone(),
+            stack,
+            state,
+            params: exec.parameters().clone(),
+            requirements,
+        })
+    }
+
+    /// Check if the program can be started at the block without preparation
+    pub fn safe(&self) -> bool {
+        self.requirements.is_empty()
+    }
+}
+
+impl fmt::Display for BlockSearch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Block at line {}: {}", self.line, self.block)?;
+        writeln!(f, "Call stack:")?;
+        for frame in self.stack.iter().rev() {
+            writeln!(f, "  {frame}")?;
+        }
+        self.state.fmt(f)?;
+        for (n, v) in &self.params {
+            writeln!(f, "R{n}={v}")?;
+        }
+        if self.safe() {
+            writeln!(f, "Safe to restart")
+        } else {
+            writeln!(f, "Unsafe to restart, requires:")?;
+            for r in &self.requirements {
+                writeln!(f, "  {r}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockSearch, Requirement};
+    use crate::debug::{Breakpoint, Debugger};
+    use millsim::{types::Micrometer, GCodeFile, Machine, Program};
+
+    const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D1
+R1=2
+L3 P1
+G0 Z150
+M5 M9
+M2
+%SPF3
+G1 X10 Z-1 F100
+G0 Z150
+M17
+";
+
+    fn search(target: Breakpoint, count: usize) -> Result<BlockSearch, String> {
+        let file = GCodeFile::parse("part.mpf", PART).unwrap();
+        let program = Program::from_file(file).unwrap();
+        let dbg = Debugger::new(program.execute(None).unwrap(), Machine::default());
+        BlockSearch::run(dbg, target, count).map_err(|e| e.message().to_owned())
+    }
+
+    #[test]
+    fn safe_at_start() {
+        let found = search(Breakpoint::Line(3), 1).unwrap();
+        assert_eq!(found.line, 3);
+        assert_eq!(found.state.z, Some(Micrometer::from_mm(150.0)));
+        assert_eq!(found.state.x, None);
+        assert!(found.safe(), "{found}");
+    }
+
+    #[test]
+    fn inside_subprogram() {
+        // Second run of the subprogram, the tool is back at safe Z
+        let found = search(Breakpoint::Line(12), 2).unwrap();
+        assert_eq!(found.line, 12);
+        assert_eq!(found.block.to_string(), "G1 X10.000 Z-1.000 F100");
+        assert_eq!(found.stack.len(), 2);
+        assert_eq!(found.stack[1].repeats_left, 0);
+        assert_eq!(found.state.x, Some(Micrometer::from_mm(10.0)));
+        assert_eq!(found.state.feed, Some(100));
+        assert_eq!(found.params[&1], 2.0);
+        let expected = [
+            Requirement::ToolChange(1),
+            Requirement::Coolant,
+            Requirement::Spindle(Some(1000)),
+            Requirement::Subprogram,
+        ];
+        assert_eq!(found.requirements, expected);
+        assert!(!found.safe());
+
+        let found = search(Breakpoint::Sub("L3".into()), 1).unwrap();
+        assert_eq!(found.line, 12);
+        let found = search(Breakpoint::Line(13), 1).unwrap();
+        assert!(found.requirements.contains(&Requirement::Approach));
+    }
+
+    #[test]
+    fn not_reached() {
+        let error = search(Breakpoint::Line(9), 2).unwrap_err();
+        assert_eq!(error, "Program ends before reaching line 9");
+    }
+}
@@ -1,6 +1,7 @@
 //! Types for G-Code interpreter
 
 use derive_more::{Add, AddAssign, Neg, Sub, SubAssign};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 
 use nom::{
@@ -11,6 +12,7 @@ use nom::{
     IResult,
 };
 
+/// Length in micrometers, the resolution of the control
 #[derive(
     Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Add, AddAssign, Sub, SubAssign, Neg,
 )]
@@ -21,7 +23,6 @@ impl Micrometer {
     ///
     /// # Panics
     /// Panics if `mm` does not fit into `f64`, is `Inf` or `NaN`
-    #[allow(dead_code)]
     pub fn from_mm(mm: f64) -> Self {
         let f = (mm * 1_000.0).round();
         let i = f as i64;
@@ -31,6 +32,16 @@ impl Micrometer {
         Self(i)
     }
 
+    /// Largest length in millimeters converted without loss
+    pub const MAX_MM: f64 = 1e12;
+
+    /// Convert millimeter float to micrometers unless it is out of range
+    ///
+    /// Returns `None` for `Inf`, `NaN` and lengths above `MAX_MM`.
+    pub fn try_from_mm(mm: f64) -> Option<Self> {
+        (mm.is_finite() && mm.abs() <= Self::MAX_MM).then(|| Self::from_mm(mm))
+    }
+
     /// Parse from `nom`
     pub fn parse(input: &str) -> IResult<&str, Micrometer> {
         fn decimal(input: &str) -> IResult<&str, u32> {
@@ -58,7 +69,6 @@ impl Micrometer {
     }
 
     /// Convert micrometers to millimeter float
-    #[allow(dead_code)]
     pub fn to_mm(self) -> f64 {
         (self.0 as f64) / 1_000.0
     }
@@ -66,9 +76,26 @@ impl Micrometer {
 
 impl fmt::Display for Micrometer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let a = self.0 / 1000;
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let a = (self.0 / 1000).abs();
         let b = (self.0 % 1000).abs();
-        write!(f, "{a}.{b:03}")
+        write!(f, "{sign}{a}.{b:03}")
+    }
+}
+
+impl Serialize for Micrometer {
+    /// Serialize as millimeters
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.to_mm())
+    }
+}
+
+impl<'de> Deserialize<'de> for Micrometer {
+    /// Deserialize from millimeters
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mm = f64::deserialize(deserializer)?;
+        Self::try_from_mm(mm)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid length {mm} mm")))
     }
 }
 
@@ -108,6 +135,9 @@ mod tests {
         let um = Micrometer(-7042);
         let s = format!("{um}");
         assert_eq!(s.as_str(), "-7.042");
+        let um = Micrometer(-42);
+        let s = format!("{um}");
+        assert_eq!(s.as_str(), "-0.042");
     }
 
     #[test]
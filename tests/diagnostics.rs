@@ -0,0 +1,122 @@
+//! Diagnostics and their configuration through the public API
+
+use millsim::{
+    machine::backtrace, Analysis, Diagnostic, GCodeFile, Level, Machine, MachineConfig, Program,
+    Rule, Severity,
+};
+use std::path::Path;
+
+fn program(text: &str) -> Program {
+    let file = GCodeFile::parse(Path::new("part.mpf"), text).unwrap();
+    Program::from_file(file).unwrap()
+}
+
+/// Run the program, collecting diagnostics which don't stop it
+fn simulate(program: &Program, config: MachineConfig) -> (Vec<Diagnostic>, Option<Diagnostic>) {
+    let mut reported = Vec::new();
+    let mut machine = Machine::with_config(config);
+    let result = machine.run(
+        program.execute(None).unwrap(),
+        |d| reported.push(d),
+        |_, _, _, _| Ok(()),
+    );
+    let error = result
+        .err()
+        .map(|e| Diagnostic::from_error(&e, Rule::RuntimeError));
+    (reported, error)
+}
+
+const NO_COOLANT: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M3 S1000 D1
+G1 X10 F100
+M5
+M2
+";
+
+#[test]
+fn syntax_error() {
+    let error = GCodeFile::parse(Path::new("part.mpf"), "%MPF1\nG0 X1 Q?\nM2\n").unwrap_err();
+    assert_eq!(error.rule(), Some(Rule::SyntaxError));
+    assert_eq!(error.line(), Some(2));
+    assert_eq!(error.column(), Some(7));
+    assert_eq!(error.file(), Some(Path::new("part.mpf")));
+}
+
+#[test]
+fn rule_error_with_fix() {
+    let (reported, error) = simulate(&program(NO_COOLANT), MachineConfig::default());
+    assert!(reported.is_empty());
+    let error = error.unwrap();
+    assert_eq!(error.rule, Rule::SpindleWithoutCoolant);
+    assert_eq!(error.rule.code(), "MS0012");
+    assert_eq!(error.severity, Severity::Error);
+    assert_eq!(error.line, Some(4));
+    assert_eq!(error.fix.unwrap().insert, "M8");
+}
+
+#[test]
+fn configured_rules() {
+    let config: MachineConfig = serde_json::from_str(
+        r#"{"rules": {"spindle-without-coolant": "warning", "MS0025": "off"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.rules().level(Rule::SpindleWithoutCoolant),
+        Some(Level::Warning)
+    );
+    let (reported, error) = simulate(&program(NO_COOLANT), config.clone());
+    assert!(error.is_none());
+    let rules: Vec<_> = reported.iter().map(|d| (d.rule, d.severity)).collect();
+    assert_eq!(rules, [(Rule::SpindleWithoutCoolant, Severity::Warning)]);
+
+    // Pragmas in the file override the configuration
+    let text = NO_COOLANT.replace(
+        "%MPF1\n",
+        "%MPF1\n; millsim: MS0012=off, cut-without-coolant=note\n",
+    );
+    let (reported, error) = simulate(&program(&text), config);
+    assert!(error.is_none());
+    let rules: Vec<_> = reported.iter().map(|d| (d.rule, d.severity)).collect();
+    assert_eq!(rules, [(Rule::CutWithoutCoolant, Severity::Note)]);
+
+    assert!(
+        serde_json::from_str::<MachineConfig>(r#"{"rules": {"runtime-error": "off"}}"#).is_err()
+    );
+}
+
+#[test]
+fn runtime_error_backtrace() {
+    let repeated = program("%MPF1\nG0 Z150\nL3 P2\nM2\n%SPF3\nR1=R1+1\nR2=1/(R1-2)\nM17\n");
+    let (_, error) = simulate(&repeated, MachineConfig::default());
+    let error = error.unwrap();
+    assert_eq!(error.rule, Rule::RuntimeError);
+    assert_eq!(error.line, Some(7));
+    assert_eq!(error.stack.len(), 2);
+    let calls: Vec<_> = backtrace(&error.stack).collect();
+    assert_eq!(calls, ["in L3 (repeat 2 of 3) called from line 3 of %MPF1"]);
+
+    // A failing calling block runs in the caller, not in the subprogram
+    let calling = program(
+        "%MPF1\nG0 Z150\nG0 X0 Y0\nM8\nM3 S1000 D1\nG1 Z-1 F1000 L10 P0\nM5 M9\nM2\n%SPF10\nG0 Z150\nM17\n",
+    );
+    let (_, error) = simulate(&calling, MachineConfig::default());
+    let error = error.unwrap();
+    assert_eq!(error.rule, Rule::FeedOutOfRange);
+    assert_eq!(error.line, Some(6));
+    assert_eq!(error.stack.len(), 1);
+    assert_eq!(backtrace(&error.stack).count(), 0);
+}
+
+#[test]
+fn analysis_findings() {
+    let program = program("%MPF1\nL1 P0\nM2\n%SPF1\nM17\n%SPF2\nM17\n");
+    let analysis = Analysis::new(&program, Analysis::DEFAULT_DEPTH_LIMIT, &Default::default());
+    let diagnostics: Vec<_> = analysis.findings.iter().map(|f| f.diagnostic()).collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].rule, Rule::UnusedSubprogram);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+    assert_eq!(diagnostics[0].line, Some(6));
+}
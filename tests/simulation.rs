@@ -0,0 +1,178 @@
+//! Loading, running and rendering programs through the public API
+
+use millsim::{
+    render::{self, heightmap::Stock, svg::Svg, Circle, Line},
+    types::Micrometer,
+    GCodeFile, Machine, Program, Render,
+};
+use std::{io, path::Path};
+
+const PART: &str = "\
+%MPF1
+G0 Z150
+G0 X0 Y0
+M8
+M3 S1000 D1
+L3 P1
+G0 Z150
+M5 M9
+M2
+%SPF3
+G1 X10 Z-1 F100
+G2 X20 Y0 I5 J0
+G0 Z150
+G0 X0 Y0
+M17
+";
+
+fn program(text: &str) -> Program {
+    let file = GCodeFile::parse(Path::new("part.mpf"), text).unwrap();
+    Program::from_file(file).unwrap()
+}
+
+/// Movements the machine passed to its render
+#[derive(Debug, Default)]
+struct Moves {
+    lines: Vec<(Line, (Micrometer, Micrometer), Micrometer)>,
+    arcs: Vec<(Circle, (Micrometer, Micrometer))>,
+}
+
+impl Render for Moves {
+    fn line_to(
+        &mut self,
+        _tool: Micrometer,
+        ty: Line,
+        point: (Micrometer, Micrometer),
+        height: Micrometer,
+    ) {
+        self.lines.push((ty, point, height));
+    }
+
+    fn arc_to(
+        &mut self,
+        _tool: Micrometer,
+        ty: Circle,
+        _center: (Micrometer, Micrometer),
+        end: (Micrometer, Micrometer),
+    ) {
+        self.arcs.push((ty, end));
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn run_program() {
+    let program = program(PART);
+    let exec = program.execute(None).unwrap();
+    let mut machine = Machine::default();
+    let mut lines = Vec::new();
+    machine
+        .run(
+            exec,
+            |d| panic!("unexpected diagnostic {d}"),
+            |line, _, exec, _| {
+                lines.push((line, exec.depth()));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+    let state = machine.state();
+    assert_eq!(
+        (state.x, state.y, state.z),
+        (
+            Some(Micrometer(0)),
+            Some(Micrometer(0)),
+            Some(Micrometer(150_000))
+        )
+    );
+    assert!(!state.spindle_on && !state.coolant_on);
+    assert!(state.elapsed > 0.0);
+    // The subprogram runs twice with P1
+    assert_eq!(lines.iter().filter(|(l, _)| *l == 11).count(), 2);
+    assert!(lines
+        .iter()
+        .filter(|(l, _)| *l == 11)
+        .all(|(_, depth)| *depth == 2));
+    assert_eq!(lines.last(), Some(&(9, 1)));
+}
+
+#[test]
+fn render_movements() {
+    let program = program(PART);
+    let mut machine = Machine::with_renders(vec![Box::<Moves>::default()]);
+    machine
+        .run(program.execute(None).unwrap(), |_| (), |_, _, _, _| Ok(()))
+        .unwrap();
+
+    let mut renders = machine.finalize();
+    let moves = render::take::<Moves>(&mut renders).unwrap();
+    let cut = (
+        Line::Cut,
+        (Micrometer(10_000), Micrometer(0)),
+        Micrometer(-1_000),
+    );
+    assert_eq!(moves.lines.iter().filter(|m| **m == cut).count(), 2);
+    assert_eq!(
+        moves.arcs,
+        [(Circle::Cw, (Micrometer(20_000), Micrometer(0))); 2]
+    );
+}
+
+#[test]
+fn dwell() {
+    let elapsed = |block: &str| {
+        let text = format!("%MPF1\nG0 Z150\nM8\nM3 S600 D1\n{block}\nM5 M9\nM2\n");
+        let program = program(&text);
+        let mut machine = Machine::default();
+        machine
+            .run(program.execute(None).unwrap(), |_| (), |_, _, _, _| Ok(()))
+            .unwrap();
+        machine.state().elapsed
+    };
+    let base = elapsed("");
+    assert!((elapsed("G4 F0.5") - base - 0.5).abs() < 1e-9);
+    assert!((elapsed("G4 F2") - base - 2.0).abs() < 1e-9);
+    // 3 revolutions at 600 rpm
+    assert!((elapsed("G4 S3") - base - 0.3).abs() < 1e-9);
+}
+
+#[test]
+fn render_from_reader_to_writer() {
+    let file = GCodeFile::from_reader("<stdin>", PART.as_bytes()).unwrap();
+    assert_eq!(file.text(), PART);
+    let program = Program::from_file(file).unwrap();
+    let svg = Svg::new(Vec::new(), Stock::default());
+    let mut machine = Machine::with_renders(vec![Box::new(svg)]);
+    machine
+        .run(program.execute(None).unwrap(), |_| (), |_, _, _, _| Ok(()))
+        .unwrap();
+
+    let mut renders = machine.finalize();
+    let svg = render::take::<Svg<Vec<u8>>>(&mut renders).unwrap();
+    let out = String::from_utf8(svg.finish().unwrap()).unwrap();
+    assert!(out.starts_with("<svg "));
+    assert!(out.trim_end().ends_with("</svg>"));
+}
+
+#[test]
+fn step_executor() {
+    let program = program(PART);
+    let mut exec = program.execute(Some("MPF1")).unwrap().with_budget(6);
+    assert_eq!(exec.peek().map(|(line, _)| line), Some(2));
+    let blocks: Vec<_> = exec.by_ref().take(5).map(|b| b.unwrap().0).collect();
+    assert_eq!(blocks, [2, 3, 4, 5, 6]);
+
+    // Entered the subprogram with one repeat left
+    let stack = exec.call_stack();
+    assert_eq!(stack.len(), 2);
+    assert_eq!(stack[1].call_line, Some(6));
+    assert_eq!((stack[1].run(), stack[1].runs), (1, 2));
+
+    // The budget stops the program
+    assert!(exec.next().unwrap().is_ok());
+    assert!(exec.next().unwrap().is_err());
+}